@@ -2,6 +2,7 @@ mod update;
 mod render;
 
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use log::{debug, trace, warn};
 use macroquad::camera::Camera3D;
 use macroquad::input::{is_key_pressed, is_key_released, KeyCode};
@@ -10,21 +11,41 @@ use mp_game_test_common::events_client::ClientEvent;
 use mp_game_test_common::game::{Action, CommonGameInstance, PlayerData};
 use mp_game_test_common::events_server::ServerEvent;
 use mp_game_test_common::{PacketSerialize, PACKET_PROTOCOL_VERSION};
-use mp_game_test_common::def::Vector3;
+use mp_game_test_common::def::{Vector3, MAX_PLAYERS};
+use mp_game_test_common::network::{Cookie, NetConfig, Network};
 use crate::{ActionResult, FpsCounter};
 use crate::network::NetClient;
 
+/// How long to wait for the server to ack our Disconnect before closing anyway
+const DISCONNECT_LINGER: Duration = Duration::from_millis(500);
+/// How long to wait for the server to answer our Login before sending it again
+const LOGIN_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+
 pub struct GameInstance {
     pub game: CommonGameInstance,
     pub net: Option<NetClient>,
+    net_config: NetConfig,
     pub cam: GameCamera,
     pub local_player: LocalPlayer,
     client_id: Option<u32>,
     auth_id: Option<u32>,
+    /// Name we are logging in as, kept to log in again when challenged
+    login_name: Option<String>,
+    /// Set until the server accepts or rejects our Login, which is resent until it does
+    login_attempt: Option<LoginAttempt>,
+    /// Why the server refused our login, for the main menu to show
+    login_error: Option<String>,
     actions: Action,
 
     pub fps_calc: FpsCounter,
 }
+/// The last Login we sent, Login isn't reliable as we have no auth id to get it acked with
+struct LoginAttempt {
+    cookie: Option<Cookie>,
+    sent_at: Instant,
+    /// Times it was sent with this cookie
+    attempts: u8,
+}
 #[derive(Default)]
 pub struct GameCamera {
     pub camera: Camera3D,
@@ -40,14 +61,18 @@ impl GameCamera {
     }
 }
 impl GameInstance {
-    pub fn new() -> Self {
+    pub fn new(net_config: NetConfig) -> Self {
         Self {
             game: CommonGameInstance::new(),
             cam: GameCamera::default(),
             local_player: LocalPlayer::default(),
             net: None,
+            net_config,
             client_id: None,
             auth_id: None,
+            login_name: None,
+            login_attempt: None,
+            login_error: None,
             actions: Action::empty(),
 
             fps_calc: FpsCounter::new()
@@ -57,14 +82,68 @@ impl GameInstance {
         if self.net.is_some() {
             return Err("Already connected".to_string());
         }
-        self.net = Some(NetClient::new(addr));
+        let net = NetClient::new(addr, self.net_config.clone());
+        self.start_login(net, name)
+    }
+
+    fn start_login(&mut self, net: NetClient, name: String) -> Result<(), String> {
+        self.net = Some(net);
+        self.login_name = Some(name);
+        self.send_login(None)
+    }
+
+    /// Sends a Login, echoing the server's cookie if we have been challenged
+    fn send_login(&mut self, cookie: Option<Cookie>) -> Result<(), String> {
+        let name = self.login_name.clone().ok_or("not connecting")?;
         let event = ClientEvent::Login {
             version: PACKET_PROTOCOL_VERSION,
-            name: name
+            name,
+            public_key: Some(self.net().public_key()),
+            cookie
+        };
+        debug!("sending event {:?}", event);
+        let attempts = match &self.login_attempt {
+            Some(attempt) if attempt.cookie == cookie => attempt.attempts + 1,
+            _ => 1,
         };
+        self.login_attempt = Some(LoginAttempt { cookie, sent_at: Instant::now(), attempts });
         // This should never really fail - is just a channel to another thread
-        self.send(&event).map(|_| ())
+        self.net().send_login(event.to_packet())
+    }
+
+    /// Sends our Login again if the server hasn't answered it in a while, as either may have been lost.
+    /// Gives up after [NetConfig::max_send_attempts], setting the login error
+    pub fn resend_login(&mut self) -> Result<(), String> {
+        let Some(attempt) = &self.login_attempt else {
+            return Ok(());
+        };
+        if attempt.sent_at.elapsed() < LOGIN_RESEND_INTERVAL {
+            return Ok(());
+        }
+        if attempt.attempts >= self.net_config.max_send_attempts {
+            warn!("no answer to login after {} attempts", attempt.attempts);
+            self.login_attempt = None;
+            self.login_error = Some("Server did not respond".to_string());
+            return Ok(());
+        }
+        let cookie = attempt.cookie;
+        trace!("no answer to login yet, sending again");
+        self.send_login(cookie)
+    }
+    /// Takes the reason the server gave for refusing our login, if it did
+    pub fn take_login_error(&mut self) -> Option<String> {
+        self.login_error.take()
     }
+
+    /// Stops networking without telling the server, for when we never logged in
+    pub fn close(&mut self) {
+        if let Some(net) = self.net.take() {
+            net.end();
+        }
+        self.login_name = None;
+        self.login_attempt = None;
+    }
+
     pub fn is_connected(&self) -> bool {
         self.net.is_some()
     }
@@ -104,6 +183,10 @@ impl GameInstance {
             reason: reason.into()
         };
         self.send(&event).ok();
+        self.net().flush().ok();
+        if !self.net().wait_for_acks(DISCONNECT_LINGER) {
+            warn!("server did not ack our disconnect");
+        }
         let net = self.net.take().unwrap();
         trace!("ending net threads");
         net.end();
@@ -126,16 +209,23 @@ impl GameInstance {
     }
 
     pub fn send(&self, event: &ClientEvent) -> Result<(), String> {
-        let mut pk = event.to_packet_builder()
-            .with_auth_id(self.auth_id.unwrap_or(0));
         assert!(event.get_packet_type() == 0x1 || self.auth_id.is_some(), "non-login event {:?} but no auth id {:?}", event, self.auth_id);
         debug!("sending event {:?}", event);
-        self.net().send(pk.finalize())
+        self.net().send(event)
     }
 
     pub fn process_event(&mut self, event: ServerEvent) {
         match event {
-            ServerEvent::Login { client_index: client_id, auth_id } => {
+            // Decoded fine, but would index past our players
+            ServerEvent::Login { client_index, .. }
+            | ServerEvent::PlayerSpawn { client_index, .. }
+            | ServerEvent::Move { client_index, .. }
+            | ServerEvent::Disconnect { client_index, .. } if client_index as usize >= MAX_PLAYERS => {
+                warn!("dropping event for client index {}, max is {}", client_index, MAX_PLAYERS - 1);
+            }
+            ServerEvent::Login { client_index: client_id, auth_id, min_version, max_version, .. } => {
+                debug!("server supports protocol {}..={}, we are on {}", min_version, max_version, PACKET_PROTOCOL_VERSION);
+                self.login_attempt = None;
                 // Check if we are already logged in
                 if let Some(current_auth_id) = self.auth_id {
                     // If it's the same thing - disregard
@@ -162,6 +252,161 @@ impl GameInstance {
             ServerEvent::Disconnect { client_index, reason } => {
                 self.game.set_player(client_index, None);
             }
+            ServerEvent::CommandResult { id, result } => {
+                debug!("command #{} result: {}", id, result);
+            }
+            ServerEvent::LoginRejected { reason } => {
+                warn!("login rejected: {}", reason);
+                self.login_attempt = None;
+                self.login_error = Some(reason.to_string());
+            }
+            // Taken by the network thread
+            ServerEvent::Ack { .. } | ServerEvent::Ping { .. } => {}
+            ServerEvent::Challenge { cookie } => {
+                if self.auth_id.is_some() {
+                    return;
+                }
+                trace!("challenged by server, logging in again");
+                if let Err(e) = self.send_login(Some(cookie)) {
+                    warn!("could not answer challenge: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use mp_game_test_common::datagram::{DatagramDecoder, DatagramEncoder, FRAGMENT_TIMEOUT, MAX_DATAGRAM_SIZE};
+    use mp_game_test_common::network::{MemoryTransport, Transport, COOKIE_LEN};
+    use mp_game_test_common::session::Sessions;
+    use mp_game_test_common::MIN_PROTOCOL_VERSION;
+    use super::*;
+
+    const COOKIE: Cookie = [7; COOKIE_LEN];
+
+    /// The server's end of the login, played by hand so its replies can be dropped
+    struct FakeServer {
+        transport: MemoryTransport,
+        client_addr: SocketAddr,
+        encoder: DatagramEncoder,
+        decoder: DatagramDecoder,
+    }
+
+    impl FakeServer {
+        /// The cookie of the next Login from the client, None if nothing arrives
+        fn recv_login(&mut self) -> Option<Option<Cookie>> {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let (n, addr) = self.transport.recv_from(&mut buf).ok()?;
+            let packets = self.decoder.accept(addr, &buf[..n]).unwrap();
+            match ClientEvent::from_packet(&packets[0]) {
+                Ok(ClientEvent::Login { cookie, .. }) => Some(cookie),
+                other => panic!("expected login, got {:?}", other),
+            }
+        }
+
+        fn send(&self, event: ServerEvent) {
+            for datagram in self.encoder.encode(self.client_addr, &[event.to_packet()]).unwrap() {
+                self.transport.send_to(&datagram, self.client_addr).unwrap();
+            }
+        }
+    }
+
+    fn connect(config: NetConfig) -> (GameInstance, FakeServer) {
+        let (client, server) = MemoryTransport::pair();
+        server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let (client_addr, server_addr) = (client.local_addr().unwrap(), server.local_addr().unwrap());
+        let mut game = GameInstance::new(config.clone());
+        game.start_login(NetClient::with_transport(client, server_addr, config.clone()), "test".to_string()).unwrap();
+        let server = FakeServer {
+            transport: server,
+            client_addr,
+            encoder: DatagramEncoder::new(&config, Sessions::new()),
+            decoder: DatagramDecoder::new(&config, FRAGMENT_TIMEOUT, Sessions::new()),
+        };
+        (game, server)
+    }
+
+    /// Waits a bit for the recv thread to queue an event, then processes it like the main menu does
+    fn process_next_event(game: &mut GameInstance) {
+        for _ in 0..20 {
+            if let Some((_, event, _)) = game.net_mut().next_event() {
+                game.process_event(event);
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
         }
+        panic!("no event received");
+    }
+
+    #[test]
+    fn login_is_resent_until_answered() {
+        let (mut game, mut server) = connect(NetConfig::default());
+        assert_eq!(server.recv_login(), Some(None));
+        // The Challenge in reply is lost, and nothing is resent before the interval
+        game.resend_login().unwrap();
+        assert_eq!(server.recv_login(), None);
+
+        thread::sleep(LOGIN_RESEND_INTERVAL);
+        game.resend_login().unwrap();
+        assert_eq!(server.recv_login(), Some(None));
+        server.send(ServerEvent::Challenge { cookie: COOKIE });
+        process_next_event(&mut game);
+        assert_eq!(server.recv_login(), Some(Some(COOKIE)));
+
+        // The answer to the challenge is resent with its cookie
+        thread::sleep(LOGIN_RESEND_INTERVAL);
+        game.resend_login().unwrap();
+        assert_eq!(server.recv_login(), Some(Some(COOKIE)));
+
+        server.send(ServerEvent::Login {
+            client_index: 0,
+            auth_id: 1,
+            public_key: None,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PACKET_PROTOCOL_VERSION,
+        });
+        process_next_event(&mut game);
+        assert!(game.is_authenticated());
+        thread::sleep(LOGIN_RESEND_INTERVAL);
+        game.resend_login().unwrap();
+        assert_eq!(server.recv_login(), None);
+    }
+
+    #[test]
+    fn out_of_range_client_indexes_are_dropped() {
+        let mut game = GameInstance::new(NetConfig::default());
+        let client_index = MAX_PLAYERS as u32;
+        game.process_event(ServerEvent::PlayerSpawn { client_index, name: "test".to_string(), position: Vector3::zero(), angles: Vector3::zero() });
+        game.process_event(ServerEvent::Move { client_index: u32::MAX, position: Vector3::zero(), angles: Vector3::zero(), velocity: Vector3::zero() });
+        game.process_event(ServerEvent::Disconnect { client_index, reason: "test".to_string() });
+        game.process_event(ServerEvent::Login {
+            client_index,
+            auth_id: 1,
+            public_key: None,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PACKET_PROTOCOL_VERSION,
+        });
+        assert!(game.game.players.iter().all(Option::is_none));
+        assert_eq!(game.client_id(), None);
+
+        game.process_event(ServerEvent::PlayerSpawn { client_index: client_index - 1, name: "test".to_string(), position: Vector3::zero(), angles: Vector3::zero() });
+        assert!(game.game.get_player(client_index - 1).is_some());
+    }
+
+    #[test]
+    fn login_is_given_up_on() {
+        let (mut game, mut server) = connect(NetConfig { max_send_attempts: 2, ..NetConfig::default() });
+        assert_eq!(server.recv_login(), Some(None));
+        thread::sleep(LOGIN_RESEND_INTERVAL);
+        game.resend_login().unwrap();
+        assert_eq!(server.recv_login(), Some(None));
+        assert_eq!(game.take_login_error(), None);
+
+        thread::sleep(LOGIN_RESEND_INTERVAL);
+        game.resend_login().unwrap();
+        assert_eq!(server.recv_login(), None);
+        assert_eq!(game.take_login_error().as_deref(), Some("Server did not respond"));
     }
 }
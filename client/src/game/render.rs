@@ -7,6 +7,7 @@ use macroquad::models::{draw_cube, draw_grid, draw_line_3d, draw_mesh, Mesh, Ver
 use macroquad::prelude::{clear_background, draw_text, measure_text, screen_width};
 use mp_game_test_common::def::MAX_PLAYERS;
 use mp_game_test_common::game::Action;
+use mp_game_test_common::network::Network;
 use crate::game::GameInstance;
 use crate::{get_direction_vector, FpsCounter, Player};
 
@@ -88,9 +89,31 @@ impl GameInstance {
                 20.0,
                 DARKGRAY,
             );
+            draw_text(
+                &format!("{} ms", self.net().stat().avg_ping()),
+                screen_width() - dim.width - 20.0,
+                80.0,
+                20.0,
+                DARKGRAY,
+            );
+            let link = self.net().peer_stat().link;
+            draw_text(
+                &format!("loss {:.1}% in {:.1}% out", link.loss_in(), link.loss_out()),
+                screen_width() - dim.width - 20.0,
+                110.0,
+                20.0,
+                DARKGRAY,
+            );
+            draw_text(
+                &format!("jitter {} ms ooo {} dup {}", link.jitter().as_millis(), link.out_of_order(), link.duplicates()),
+                screen_width() - dim.width - 20.0,
+                140.0,
+                20.0,
+                DARKGRAY,
+            );
         }
         draw_text(
-            &self.net().process_queue_len().to_string(),
+            &self.net().event_queue_len().to_string(),
             20.0,
             20.0,
             20.0,
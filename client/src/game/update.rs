@@ -2,6 +2,7 @@ use macroquad::input::{is_key_pressed, is_key_released, is_mouse_button_down, se
 use macroquad::logging::debug;
 use macroquad::math::{vec3, Vec3};
 use mp_game_test_common::game::{Action, PlayerData};
+use mp_game_test_common::network::Network;
 use crate::game::GameInstance;
 use crate::{get_direction_vector, ActionResult};
 
@@ -14,7 +15,7 @@ impl GameInstance {
         // }
 
         // Processes all pending incoming net data
-        while let Some(event) = self.net_mut().next_event() {
+        while let Some((_, event, _)) = self.net_mut().next_event() {
             macroquad::logging::debug!("[main->loop] got event, processing: {:?}", event);
             self.process_event(event);
         }
@@ -11,7 +11,11 @@ use mp_game_test_common::def::{Vector3, MAX_PLAYERS};
 use mp_game_test_common::events_client::ClientEvent;
 use mp_game_test_common::game::Action;
 use mp_game_test_common::setup_logger;
+use mp_game_test_common::datagram::{DEFAULT_COMPRESS_THRESHOLD, DEFAULT_MTU, MIN_MTU};
+use mp_game_test_common::network::{NetConfig, Network};
+use mp_game_test_common::netsim::{parse_percent, LinkConditions};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::ops::Sub;
 use std::sync::mpsc::channel;
 use std::thread;
@@ -33,7 +37,39 @@ struct Args {
     connect_to: Option<String>,
 
     #[arg(long)]
-    name: Option<String>
+    name: Option<String>,
+
+    /// Largest datagram to send, bigger packets are split into fragments
+    #[arg(long, default_value_t = DEFAULT_MTU, value_parser = clap::value_parser!(u16).range(MIN_MTU as i64..))]
+    mtu: u16,
+
+    /// Batches smaller than this many bytes are sent uncompressed
+    #[arg(long, default_value_t = DEFAULT_COMPRESS_THRESHOLD)]
+    compress_threshold: u16,
+
+    /// Also write every sent batch to this dir, as samples for training the compression dictionary
+    #[arg(long, value_name = "DIR")]
+    capture_packets: Option<PathBuf>,
+
+    /// Simulated latency added to everything sent, in ms
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    sim_latency: u64,
+
+    /// Simulated latency varies by up to this much either way, in ms
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    sim_jitter: u64,
+
+    /// Chance a sent datagram is dropped, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    sim_loss: f32,
+
+    /// Chance a sent datagram is sent twice, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    sim_duplicate: f32,
+
+    /// Chance a sent datagram is held back so later ones overtake it, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    sim_reorder: f32,
 }
 
 struct Player {
@@ -76,7 +112,20 @@ async fn main() {
     }
 
     let mut main_menu = MainMenu::new(args.name, server_ip);
-    let mut game = GameInstance::new();
+    let net_config = NetConfig {
+        mtu: args.mtu,
+        compress_threshold: args.compress_threshold,
+        capture_dir: args.capture_packets,
+        link: LinkConditions {
+            latency: Duration::from_millis(args.sim_latency),
+            jitter: Duration::from_millis(args.sim_jitter),
+            loss: args.sim_loss,
+            duplicate: args.sim_duplicate,
+            reorder: args.sim_reorder,
+        },
+        ..Default::default()
+    };
+    let mut game = GameInstance::new(net_config);
 
     while !is_quit_requested() {
         main_menu.draw().await;
@@ -95,12 +144,22 @@ async fn main() {
                 error!("login error: {}", err);
                 main_menu.set_err_msg(Some(err));
                 main_menu.set_status_msg(None);
-                game.net_mut().clear_last_err();
+                main_menu.clear_connect_info();
+                game.close();
             }
-            if let Some(event) = game.net_mut().next_event() {
+            if let Some((_, event, _)) = game.net_mut().next_event() {
                 debug!("[main->main_menu] got event, processing: {:?}", event);
                 game.process_event(event);
             }
+            if let Err(e) = game.resend_login() {
+                warn!("could not resend login: {}", e);
+            }
+            if let Some(err) = game.take_login_error() {
+                main_menu.set_err_msg(Some(err));
+                main_menu.set_status_msg(None);
+                main_menu.clear_connect_info();
+                game.close();
+            }
         } else {
             debug!("authenticated. ready to go.");
             // Authenticated - we are ready
@@ -113,7 +172,7 @@ async fn main() {
     }
 
     while !game.is_authenticated() {
-        if let Some(event) = game.net_mut().next_event() {
+        if let Some((_, event, _)) = game.net_mut().next_event() {
             debug!("[main->login] got event, processing: {:?}", event);
             game.process_event(event);
         }
@@ -134,6 +193,8 @@ async fn main() {
         if let Some(frame_delta) = prev_frame_time {
             game.update();
         }
+        // Send everything from this frame as one batch
+        game.net().flush().ok();
         game.render();
         if frame % 10 == 0 {
             if let Some(prev_frame_time) = prev_frame_time {
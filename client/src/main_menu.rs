@@ -69,6 +69,11 @@ impl MainMenu {
         None
     }
 
+    /// Stops connecting, until Connect is pressed again
+    pub fn clear_connect_info(&mut self) {
+        self.ip_addr = None;
+    }
+
     pub async fn draw(&mut self) {
         clear_background(WHITE);
         widgets::Window::new(hash!(), vec2(screen_width() / 2.0 - WINDOW_SIZE.x / 2.0,
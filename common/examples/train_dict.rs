@@ -0,0 +1,158 @@
+//! Trains the zstd dictionary used to compress packets (common/dict/packets.dict).
+//!
+//! Usage: `cargo run -p mp-game-test-common --example train_dict -- [CAPTURE_DIR...]`
+//!
+//! Every file in the given dirs is used as a sample. Capture some by running the server and clients with
+//! `--capture-packets <DIR>`. Without any dirs, samples are generated from typical ticks of random events instead.
+//! Retrain whenever the packet format changes, so the dictionary keeps matching real traffic.
+//! Both sides must be rebuilt with the new dictionary, so bump PACKET_PROTOCOL_VERSION when replacing it.
+//! Peers on older versions can't read batches compressed with the new one: first move the current
+//! dict/packets.dict to dict/packets-v<old version>.dict and point PREVIOUS_PACKET_DICTIONARY at it.
+use std::fs;
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use mp_game_test_common::def::Vector3;
+use mp_game_test_common::events_client::ClientEvent;
+use mp_game_test_common::events_server::ServerEvent;
+use mp_game_test_common::game::Action;
+use mp_game_test_common::packet::Packet;
+use mp_game_test_common::{PacketSerialize, MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION};
+
+const DICTIONARY_SIZE: usize = 8 * 1024;
+const GENERATED_SAMPLES: usize = 10_000;
+
+/// xorshift32, good enough for sample data
+struct Rng(u32);
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, max: u32) -> u32 {
+        self.next() % max
+    }
+
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (self.next() as f32 / u32::MAX as f32) * (max - min)
+    }
+
+    fn vector3(&mut self, min: f32, max: f32) -> Vector3 {
+        Vector3::new(self.range(min, max), self.range(min, max), self.range(min, max))
+    }
+
+    fn name(&mut self) -> String {
+        const NAMES: [&str; 6] = ["Player", "jackz", "guest", "xX_sniper_Xx", "bob", "Unnamed"];
+        format!("{}{}", NAMES[self.below(NAMES.len() as u32) as usize], self.below(100))
+    }
+}
+
+/// A tick worth of events from the server to one client
+fn server_batch(rng: &mut Rng) -> Vec<Packet> {
+    let players = 1 + rng.below(32);
+    let mut packets = Vec::new();
+    for client_index in 0..players {
+        if rng.below(4) == 0 {
+            continue; // didn't move
+        }
+        packets.push(ServerEvent::Move {
+            client_index,
+            position: rng.vector3(-200.0, 200.0),
+            angles: rng.vector3(-PI, PI),
+            velocity: rng.vector3(-10.0, 10.0),
+        }.to_packet());
+    }
+    match rng.below(20) {
+        0 => packets.push(ServerEvent::PlayerSpawn {
+            client_index: rng.below(32),
+            name: rng.name(),
+            position: rng.vector3(-200.0, 200.0),
+            angles: rng.vector3(-PI, PI),
+        }.to_packet()),
+        1 => packets.push(ServerEvent::Disconnect {
+            client_index: rng.below(32),
+            reason: ["Disconnect", "Timed out", "Server is closing"][rng.below(3) as usize].to_string(),
+        }.to_packet()),
+        2 => packets.push(ServerEvent::Login {
+            client_index: rng.below(32),
+            auth_id: rng.next(),
+            public_key: None,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PACKET_PROTOCOL_VERSION,
+        }.to_packet()),
+        3 => packets.push(ServerEvent::CommandResult {
+            id: rng.below(1000),
+            result: rng.below(2) == 0,
+        }.to_packet()),
+        _ => {}
+    }
+    packets
+}
+
+/// A frame worth of events from a client
+fn client_batch(rng: &mut Rng) -> Vec<Packet> {
+    let auth_id = rng.next();
+    let mut packets = vec![
+        ClientEvent::PerformAction {
+            actions: Action::from_bits_retain(rng.below(Action::all().bits() + 1)),
+            angles: rng.vector3(-PI, PI),
+        }.to_packet_builder().with_auth_id(auth_id).finalize()
+    ];
+    match rng.below(10) {
+        0 => packets.push(ClientEvent::Ack { seq_number: rng.below(u16::MAX as u32) as u16 }
+            .to_packet_builder().with_auth_id(auth_id).finalize()),
+        1 => packets.push(ClientEvent::Command { command: "status".to_string(), id: rng.below(1000) }
+            .to_packet_builder().with_auth_id(auth_id).finalize()),
+        _ => {}
+    }
+    packets
+}
+
+fn generate_samples() -> Vec<Vec<u8>> {
+    let mut rng = Rng(0x2545F491);
+    (0..GENERATED_SAMPLES).map(|i| {
+        let packets = if i % 2 == 0 { server_batch(&mut rng) } else { client_batch(&mut rng) };
+        Packet::concat_batch(&packets)
+    }).collect()
+}
+
+fn read_samples(dirs: &[String]) -> Vec<Vec<u8>> {
+    let mut samples = Vec::new();
+    for dir in dirs {
+        for entry in fs::read_dir(dir).expect("could not read capture dir") {
+            let path = entry.expect("could not read capture dir").path();
+            if path.is_file() {
+                samples.push(fs::read(&path).expect("could not read sample"));
+            }
+        }
+    }
+    samples
+}
+
+fn main() {
+    let dirs: Vec<String> = std::env::args().skip(1).collect();
+    let samples = if dirs.is_empty() {
+        println!("no capture dirs given, generating {} samples", GENERATED_SAMPLES);
+        generate_samples()
+    } else {
+        read_samples(&dirs)
+    };
+    let total: usize = samples.iter().map(|s| s.len()).sum();
+    println!("training on {} samples ({} bytes)", samples.len(), total);
+
+    let dictionary = zstd::dict::from_samples(&samples, DICTIONARY_SIZE).expect("training failed");
+
+    let (mut plain, mut with_dict) = (0, 0);
+    let mut compressor = zstd::bulk::Compressor::with_dictionary(zstd::DEFAULT_COMPRESSION_LEVEL, &dictionary).unwrap();
+    for sample in &samples {
+        plain += zstd::bulk::compress(sample, zstd::DEFAULT_COMPRESSION_LEVEL).unwrap().len();
+        with_dict += compressor.compress(sample).unwrap().len();
+    }
+    println!("compressed: {} bytes without dictionary, {} bytes with", plain, with_dict);
+
+    let out = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("dict/packets.dict");
+    fs::write(&out, &dictionary).expect("could not write dictionary");
+    println!("wrote {} byte dictionary to {:?}", dictionary.len(), out);
+}
@@ -1,18 +1,101 @@
 use std::fmt::Write;
-use std::ffi::{CStr, FromBytesUntilNulError};
-use std::fmt::{Debug, Formatter};
+use std::f32::consts::{PI, TAU};
+use std::ffi::CStr;
+use std::fmt::{Debug, Display, Formatter};
 use std::io;
-use std::io::{Cursor, Read, Write as OtherWrite};
+use std::io::{Read, Write as OtherWrite};
 use std::ops::Range;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use log::trace;
 use zstd::DEFAULT_COMPRESSION_LEVEL;
 use zstd::zstd_safe::CParameter::CompressionLevel;
+use crate::def::Vector3;
 use crate::packet::Packet;
 
+/// Error returned when reading from a [BitBuffer] fails, usually from a truncated or malformed packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Tried to read `width` bytes at `offset`, but buffer is only `len` bytes long
+    OutOfBounds { offset: usize, width: usize, len: usize },
+    /// String starting at `offset` has no null terminator
+    UnterminatedString { offset: usize },
+    /// Variable length integer at `offset` is longer than its type allows
+    VarIntOverflow { offset: usize },
+    /// Packet type does not belong to any known event
+    UnknownPacketType { packet_type: u8, payload_len: u16 },
+    /// Enum field has a value that matches none of its variants
+    UnknownVariant { value: u8 },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::OutOfBounds { offset, width, len } =>
+                write!(f, "read of {} bytes at offset {} exceeds buffer length {}", width, offset, len),
+            DecodeError::UnterminatedString { offset } =>
+                write!(f, "string at offset {} is missing null terminator", offset),
+            DecodeError::VarIntOverflow { offset } =>
+                write!(f, "varint at offset {} overflows", offset),
+            DecodeError::UnknownPacketType { packet_type, payload_len } =>
+                write!(f, "invalid packet type ({}). packet len={}", packet_type, payload_len),
+            DecodeError::UnknownVariant { value } =>
+                write!(f, "unknown enum value ({})", value),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// How byte sized values (u8, u32, strings, ...) are handled when the cursor is in the middle of a byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitMode {
+    /// Skip to the next byte boundary first, leaving the rest of the partial byte unused
+    #[default]
+    Aligned,
+    /// Pack directly after the previous bits
+    Unaligned,
+}
+
+/// Fixed point encoding for floats inside a known range, using `bits` bits (max 32)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedRange {
+    pub min: f32,
+    pub max: f32,
+    pub bits: u8,
+}
+
+impl FixedRange {
+    pub const fn new(min: f32, max: f32, bits: u8) -> Self {
+        assert!(bits > 0 && bits <= 32, "bits must be between 1 and 32");
+        Self { min, max, bits }
+    }
+
+    fn max_int(&self) -> u32 {
+        u32::MAX >> (32 - self.bits)
+    }
+
+    /// Maximum error of a round trip for values inside the range
+    pub fn precision(&self) -> f32 {
+        (self.max - self.min) / self.max_int() as f32 / 2.0
+    }
+
+    /// Converts value into its integer step. Values outside the range are clamped.
+    pub fn quantize(&self, value: f32) -> u32 {
+        let norm = ((value as f64 - self.min as f64) / (self.max as f64 - self.min as f64)).clamp(0.0, 1.0);
+        (norm * self.max_int() as f64).round() as u32
+    }
+
+    pub fn dequantize(&self, value: u32) -> f32 {
+        let norm = value.min(self.max_int()) as f64 / self.max_int() as f64;
+        (self.min as f64 + norm * (self.max - self.min) as f64) as f32
+    }
+}
+
 #[derive(Clone)]
 pub struct BitBuffer {
     current_offset: usize,
+    /// Number of bits already used in the byte at current_offset (0-7)
+    bit_pos: u8,
+    mode: BitMode,
     // offset: usize, // prob can just use vec.length but for now it works
     vec: Vec<u8>
 }
@@ -22,18 +105,16 @@ impl BitBuffer {
         let mut vec = Vec::with_capacity(capacity);
         Self {
             current_offset: 0,
+            bit_pos: 0,
+            mode: BitMode::default(),
             vec
         }
     }
 
-    fn _buf_cursor(&self, offset: usize) -> Cursor<&Vec<u8>> {
-        let mut c = Cursor::new(&self.vec);
-        c.set_position(offset as u64);
-        c
-    }
-
+    /// Returns all written bytes, including a partially written last byte
     pub fn as_bytes(&self) -> &[u8] {
-        &self.vec[0..self.current_offset]
+        let end = self.current_offset + (self.bit_pos > 0) as usize;
+        &self.vec[0..end]
     }
 
     pub fn set_offset_pos(&mut self, offset: usize) -> Result<(), String>{
@@ -41,9 +122,72 @@ impl BitBuffer {
             return Err(format!("offset {}, is greater than capacity {}", offset, self.vec.capacity()).to_string())
         }
         self.current_offset = offset;
+        self.bit_pos = 0;
         Ok(())
     }
 
+    pub fn bit_mode(&self) -> BitMode {
+        self.mode
+    }
+
+    /// Sets how byte sized values are written/read when not on a byte boundary.
+    /// Reader and writer must use the same mode.
+    pub fn set_bit_mode(&mut self, mode: BitMode) {
+        self.mode = mode;
+    }
+
+    /// Moves the cursor to the start of the next byte, if in the middle of one
+    pub fn align(&mut self) {
+        if self.bit_pos > 0 {
+            self.current_offset += 1;
+            self.bit_pos = 0;
+        }
+    }
+
+    /// Writes bytes at the cursor, respecting the bit mode if not on a byte boundary
+    fn _write_bytes(&mut self, bytes: &[u8]) {
+        if self.bit_pos > 0 {
+            if self.mode == BitMode::Unaligned {
+                for b in bytes {
+                    self.write_bits(*b as u32, 8);
+                }
+                return;
+            }
+            self.align();
+        }
+        let offset = self.current_offset;
+        self.try_expand(offset, bytes.len());
+        self.vec[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.current_offset += bytes.len();
+    }
+
+    /// Writes the lowest `bits` bits of value (max 32), least significant bit first
+    pub fn write_bits(&mut self, mut value: u32, mut bits: u8) {
+        assert!(bits <= 32, "cannot write more than 32 bits at once");
+        while bits > 0 {
+            if self.bit_pos == 0 {
+                // Starting a fresh byte
+                self.try_expand(self.current_offset, 1);
+                self.vec[self.current_offset] = 0;
+            }
+            let take = bits.min(8 - self.bit_pos);
+            let chunk = (value & ((1u32 << take) - 1)) as u8;
+            self.vec[self.current_offset] |= chunk << self.bit_pos;
+            value >>= take;
+            bits -= take;
+            self.bit_pos += take;
+            if self.bit_pos == 8 {
+                self.current_offset += 1;
+                self.bit_pos = 0;
+            }
+        }
+    }
+
+    /// Writes a single bit
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
     /// Reserves more space if given offset and length exceeds vec
     fn try_expand(&mut self, offset: usize, len: usize)  {
         if offset + len > self.vec.len() {
@@ -52,8 +196,7 @@ impl BitBuffer {
     }
 
     pub fn write_i8(&mut self, value: i8) {
-        self.write_i8_at(self.current_offset, value);
-        self.current_offset += 1;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     pub fn write_i8_at(&mut self, offset: usize, value: i8) {
@@ -62,8 +205,7 @@ impl BitBuffer {
     }
 
     pub fn write_u8(&mut self, value: u8) {
-        self.write_u8_at(self.current_offset, value);
-        self.current_offset += 1;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     /// Does not call try_expand
@@ -77,8 +219,7 @@ impl BitBuffer {
     }
 
     pub fn write_i16(&mut self, value: i16) {
-        self.write_i16_at(self.current_offset, value);
-        self.current_offset += 2;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     pub fn write_i16_at(&mut self, mut offset: usize, value: i16) {
@@ -90,8 +231,7 @@ impl BitBuffer {
     }
 
     pub fn write_u16(&mut self, value: u16) {
-        self.write_u16_at(self.current_offset, value);
-        self.current_offset += 2;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     pub fn write_u16_at(&mut self, mut offset: usize, value: u16) {
@@ -103,8 +243,7 @@ impl BitBuffer {
     }
 
     pub fn write_i32(&mut self, value: i32) {
-        self.write_i32_at(self.current_offset, value);
-        self.current_offset += 4;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     pub fn write_i32_at(&mut self, mut offset: usize, value: i32) {
@@ -116,8 +255,7 @@ impl BitBuffer {
     }
 
     pub fn write_u32(&mut self, value: u32) {
-        self.write_u32_at(self.current_offset, value);
-        self.current_offset += 4;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     pub fn write_u32_at(&mut self, mut offset: usize, value: u32) {
@@ -129,8 +267,7 @@ impl BitBuffer {
     }
 
     pub fn write_f32(&mut self, value: f32) {
-        self.write_f32_at(self.current_offset, value);
-        self.current_offset += 4;
+        self._write_bytes(&value.to_le_bytes());
     }
 
     pub fn write_f32_at(&mut self, mut offset: usize, value: f32) {
@@ -147,9 +284,78 @@ impl BitBuffer {
         }
     }
 
+    /// Writes an unsigned LEB128 varint, 7 bits per byte. Takes 1 byte for values < 128, up to 5 bytes.
+    pub fn write_varint_u32(&mut self, value: u32) {
+        self.write_varint_u64(value as u64);
+    }
+
+    /// Writes an unsigned LEB128 varint, 7 bits per byte. Takes up to 10 bytes.
+    pub fn write_varint_u64(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.write_u8(byte);
+                break;
+            }
+            self.write_u8(byte | 0x80);
+        }
+    }
+
+    /// Writes a signed varint using zig-zag encoding, so small negative numbers stay small
+    pub fn write_varint_i32(&mut self, value: i32) {
+        self.write_varint_u32(zigzag_encode(value));
+    }
+
+    pub fn write_vector3(&mut self, vec: &Vector3) {
+        self.write_f32(vec.x);
+        self.write_f32(vec.y);
+        self.write_f32(vec.z);
+    }
+
+    /// Writes value in range.bits bits. Values outside the range are clamped.
+    pub fn write_quantized_f32(&mut self, value: f32, range: &FixedRange) {
+        self.write_bits(range.quantize(value), range.bits);
+    }
+
+    /// Writes each axis with [BitBuffer::write_quantized_f32]
+    pub fn write_quantized_vector3(&mut self, vec: &Vector3, range: &FixedRange) {
+        self.write_quantized_f32(vec.x, range);
+        self.write_quantized_f32(vec.y, range);
+        self.write_quantized_f32(vec.z, range);
+    }
+
+    /// Writes an angle (radians) in `bits` bits. Angle is wrapped, so precision is 2π / 2^bits
+    pub fn write_angle(&mut self, radians: f32, bits: u8) {
+        let turn = (radians / TAU).rem_euclid(1.0);
+        let steps = 1u64 << bits;
+        let value = (turn as f64 * steps as f64).round() as u64 % steps;
+        self.write_bits(value as u32, bits);
+    }
+
+    /// Writes each axis with [BitBuffer::write_angle]
+    pub fn write_angles(&mut self, angles: &Vector3, bits: u8) {
+        self.write_angle(angles.x, bits);
+        self.write_angle(angles.y, bits);
+        self.write_angle(angles.z, bits);
+    }
+
+    /// Writes a normalized direction vector using octahedral encoding, with `bits` bits for each of the 2 components
+    pub fn write_unit_vector(&mut self, vec: &Vector3, bits: u8) {
+        let range = FixedRange::new(-1.0, 1.0, bits);
+        let l1 = vec.x.abs() + vec.y.abs() + vec.z.abs();
+        let (mut u, mut v) = if l1 > 0.0 { (vec.x / l1, vec.y / l1) } else { (0.0, 0.0) };
+        if vec.z < 0.0 {
+            // Fold the lower hemisphere over the diagonals
+            (u, v) = ((1.0 - v.abs()) * sign_not_zero(u), (1.0 - u.abs()) * sign_not_zero(v));
+        }
+        self.write_quantized_f32(u, &range);
+        self.write_quantized_f32(v, &range);
+    }
+
     pub fn write_string(&mut self, str: &str) {
-        self.write_string_at(self.current_offset, str);
-        self.current_offset += str.len() + 1;
+        self._write_bytes(str.as_bytes());
+        self._write_bytes(&[0x0]);
     }
 
     pub fn write_string_at(&mut self, mut offset: usize, str: &str) {
@@ -169,6 +375,11 @@ impl BitBuffer {
         self.current_offset
     }
 
+    /// The cursor position in bits
+    pub fn bit_offset_pos(&self) -> usize {
+        self.current_offset * 8 + self.bit_pos as usize
+    }
+
     pub fn max_size(&self) -> usize {
         self.vec.capacity()
     }
@@ -177,91 +388,230 @@ impl BitBuffer {
         self.current_offset < self.vec.len()
     }
 
-    pub fn read_i8(&mut self) -> i8 {
-        let v = self.peek_i8_at(self.current_offset);
-        self.current_offset += 1;
-        v
+    /// Returns the N bytes at offset, or an error if the buffer is too short
+    fn _peek_bytes<const N: usize>(&self, offset: usize) -> Result<[u8; N], DecodeError> {
+        offset.checked_add(N)
+            .and_then(|end| self.vec.get(offset..end))
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(DecodeError::OutOfBounds { offset, width: N, len: self.vec.len() })
+    }
+
+    /// Reads N bytes at the cursor, respecting the bit mode if not on a byte boundary
+    fn _read_bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        if self.bit_pos > 0 {
+            if self.mode == BitMode::Unaligned {
+                let mut bytes = [0u8; N];
+                for b in bytes.iter_mut() {
+                    *b = self.read_bits(8)? as u8;
+                }
+                return Ok(bytes);
+            }
+            self.align();
+        }
+        let bytes = self._peek_bytes::<N>(self.current_offset)?;
+        self.current_offset += N;
+        Ok(bytes)
+    }
+
+    /// Reads `bits` bits (max 32) written by [BitBuffer::write_bits]
+    pub fn read_bits(&mut self, mut bits: u8) -> Result<u32, DecodeError> {
+        assert!(bits <= 32, "cannot read more than 32 bits at once");
+        let width = (self.bit_pos as usize + bits as usize).div_ceil(8);
+        if self.current_offset + width > self.vec.len() {
+            return Err(DecodeError::OutOfBounds { offset: self.current_offset, width, len: self.vec.len() });
+        }
+        let mut value = 0u32;
+        let mut shift = 0;
+        while bits > 0 {
+            let take = bits.min(8 - self.bit_pos);
+            let chunk = (self.vec[self.current_offset] >> self.bit_pos) as u32 & ((1u32 << take) - 1);
+            value |= chunk << shift;
+            shift += take;
+            bits -= take;
+            self.bit_pos += take;
+            if self.bit_pos == 8 {
+                self.current_offset += 1;
+                self.bit_pos = 0;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Reads a single bit
+    pub fn read_bool(&mut self) -> Result<bool, DecodeError> {
+        self.read_bits(1).map(|bit| bit != 0)
     }
 
-    pub fn peek_i8_at(&self, offset: usize) -> i8 {
-        self._buf_cursor(offset).read_i8().unwrap()
+    pub fn read_i8(&mut self) -> Result<i8, DecodeError> {
+        self._read_bytes::<1>().map(i8::from_le_bytes)
     }
 
-    pub fn read_u8(&mut self) -> u8 {
-        let v = self.peek_u8_at(self.current_offset);
-        self.current_offset += 1;
-        v
+    pub fn peek_i8_at(&self, offset: usize) -> Result<i8, DecodeError> {
+        self._peek_bytes::<1>(offset).map(i8::from_le_bytes)
     }
 
-    pub fn peek_u8_at(&self, offset: usize) -> u8 {
-        self._buf_cursor(offset).read_u8().unwrap()
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        self._read_bytes::<1>().map(u8::from_le_bytes)
     }
 
-    pub fn read_i16(&mut self) -> i16 {
-        let val = self.peek_i16_at(self.current_offset);
-        self.current_offset += 2;
-        val
+    pub fn peek_u8_at(&self, offset: usize) -> Result<u8, DecodeError> {
+        self._peek_bytes::<1>(offset).map(u8::from_le_bytes)
     }
 
-    pub fn peek_i16_at(&self, offset: usize) -> i16 {
-        self._buf_cursor(offset).read_i16::<LittleEndian>().unwrap()
+    pub fn read_i16(&mut self) -> Result<i16, DecodeError> {
+        self._read_bytes::<2>().map(i16::from_le_bytes)
     }
 
-    pub fn peek_u16_at(&self, offset: usize) -> u16 {
-        self._buf_cursor(offset).read_u16::<LittleEndian>().unwrap()
+    pub fn peek_i16_at(&self, offset: usize) -> Result<i16, DecodeError> {
+        self._peek_bytes::<2>(offset).map(i16::from_le_bytes)
     }
 
-    pub fn read_u16(&mut self) -> u16 {
-        let val = self.peek_u16_at(self.current_offset);
-        self.current_offset += 2;
-        val
+    pub fn peek_u16_at(&self, offset: usize) -> Result<u16, DecodeError> {
+        self._peek_bytes::<2>(offset).map(u16::from_le_bytes)
     }
 
-    pub fn read_i32(&mut self) -> i32 {
-        let val = self.peek_i32_at(self.current_offset);
-        self.current_offset += 4;
-        val
+    pub fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        self._read_bytes::<2>().map(u16::from_le_bytes)
     }
 
-    pub fn peek_i32_at(&self, offset: usize) -> i32 {
-        self._buf_cursor(offset).read_i32::<LittleEndian>().unwrap()
+    pub fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        self._read_bytes::<4>().map(i32::from_le_bytes)
     }
 
-    pub fn read_u32(&mut self) -> u32 {
-        let val = self.peek_u32_at(self.current_offset);
-        self.current_offset += 4;
-        val
+    pub fn peek_i32_at(&self, offset: usize) -> Result<i32, DecodeError> {
+        self._peek_bytes::<4>(offset).map(i32::from_le_bytes)
     }
 
-    pub fn peek_u32_at(&self, offset: usize) -> u32 {
-        self._buf_cursor(offset).read_u32::<LittleEndian>().unwrap()
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        self._read_bytes::<4>().map(u32::from_le_bytes)
     }
 
-    pub fn read_f32(&mut self) -> f32 {
-        let val = self.peek_f32_at(self.current_offset);
-        self.current_offset += 4;
-        val
+    pub fn peek_u32_at(&self, offset: usize) -> Result<u32, DecodeError> {
+        self._peek_bytes::<4>(offset).map(u32::from_le_bytes)
     }
 
-    pub fn peek_f32_at(&self, offset: usize) -> f32 {
-        self._buf_cursor(offset).read_f32::<LittleEndian>().unwrap()
+    pub fn read_f32(&mut self) -> Result<f32, DecodeError> {
+        self._read_bytes::<4>().map(f32::from_le_bytes)
     }
 
-    pub fn read_f32_vec(&mut self, count: usize) -> Vec<f32> {
+    pub fn peek_f32_at(&self, offset: usize) -> Result<f32, DecodeError> {
+        self._peek_bytes::<4>(offset).map(f32::from_le_bytes)
+    }
+
+    pub fn read_f32_vec(&mut self, count: usize) -> Result<Vec<f32>, DecodeError> {
         let mut vec = Vec::with_capacity(count);
         for _ in 0..count {
-            vec.push(self.read_f32());
+            vec.push(self.read_f32()?);
+        }
+        Ok(vec)
+    }
+
+    pub fn read_varint_u32(&mut self) -> Result<u32, DecodeError> {
+        let offset = self.current_offset;
+        let value = self.read_varint_u64()?;
+        u32::try_from(value).map_err(|_| DecodeError::VarIntOverflow { offset })
+    }
+
+    pub fn read_varint_u64(&mut self) -> Result<u64, DecodeError> {
+        let offset = self.current_offset;
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(DecodeError::VarIntOverflow { offset });
+            }
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(DecodeError::VarIntOverflow { offset });
+            }
         }
-        vec
     }
 
-    pub fn read_string(&mut self) -> Result<String, FromBytesUntilNulError> {
-        self.peek_string_at(self.current_offset)
+    pub fn read_varint_i32(&mut self) -> Result<i32, DecodeError> {
+        self.read_varint_u32().map(zigzag_decode)
+    }
+
+    pub fn read_vector3(&mut self) -> Result<Vector3, DecodeError> {
+        Ok(Vector3::new(self.read_f32()?, self.read_f32()?, self.read_f32()?))
+    }
+
+    pub fn read_quantized_f32(&mut self, range: &FixedRange) -> Result<f32, DecodeError> {
+        self.read_bits(range.bits).map(|value| range.dequantize(value))
     }
 
-    pub fn peek_string_at(&mut self, offset: usize) -> Result<String, FromBytesUntilNulError> {
-        let cstr = CStr::from_bytes_until_nul(&self.vec[offset..self.len()])?;
-        Ok(String::from_utf8_lossy(cstr.to_bytes()).to_string())
+    pub fn read_quantized_vector3(&mut self, range: &FixedRange) -> Result<Vector3, DecodeError> {
+        Ok(Vector3::new(
+            self.read_quantized_f32(range)?,
+            self.read_quantized_f32(range)?,
+            self.read_quantized_f32(range)?
+        ))
+    }
+
+    /// Reads an angle written by [BitBuffer::write_angle], in range [-π, π)
+    pub fn read_angle(&mut self, bits: u8) -> Result<f32, DecodeError> {
+        let value = self.read_bits(bits)?;
+        let turn = value as f64 / (1u64 << bits) as f64;
+        let radians = (turn * TAU as f64) as f32;
+        Ok(if radians >= PI { radians - TAU } else { radians })
+    }
+
+    pub fn read_angles(&mut self, bits: u8) -> Result<Vector3, DecodeError> {
+        Ok(Vector3::new(self.read_angle(bits)?, self.read_angle(bits)?, self.read_angle(bits)?))
+    }
+
+    /// Reads a direction written by [BitBuffer::write_unit_vector]. Result is normalized.
+    pub fn read_unit_vector(&mut self, bits: u8) -> Result<Vector3, DecodeError> {
+        let range = FixedRange::new(-1.0, 1.0, bits);
+        let u = self.read_quantized_f32(&range)?;
+        let v = self.read_quantized_f32(&range)?;
+        let z = 1.0 - u.abs() - v.abs();
+        let (x, y) = if z < 0.0 {
+            ((1.0 - v.abs()) * sign_not_zero(u), (1.0 - u.abs()) * sign_not_zero(v))
+        } else {
+            (u, v)
+        };
+        Ok(Vector3::new(x, y, z).normalize())
+    }
+
+    /// Reads a null terminated string, moving the cursor past the terminator
+    pub fn read_string(&mut self) -> Result<String, DecodeError> {
+        if self.bit_pos > 0 {
+            if self.mode == BitMode::Unaligned {
+                let offset = self.current_offset;
+                let mut bytes = Vec::new();
+                loop {
+                    match self.read_bits(8) {
+                        Ok(0) => break,
+                        Ok(b) => bytes.push(b as u8),
+                        Err(_) => return Err(DecodeError::UnterminatedString { offset })
+                    }
+                }
+                return Ok(String::from_utf8_lossy(&bytes).to_string());
+            }
+            self.align();
+        }
+        let (str, width) = self._peek_string_at(self.current_offset)?;
+        self.current_offset += width;
+        Ok(str)
+    }
+
+    pub fn peek_string_at(&self, offset: usize) -> Result<String, DecodeError> {
+        self._peek_string_at(offset).map(|(str, _)| str)
+    }
+
+    /// Returns the string at offset and the amount of bytes it takes up (including null terminator)
+    fn _peek_string_at(&self, offset: usize) -> Result<(String, usize), DecodeError> {
+        let bytes = self.vec.get(offset..)
+            .ok_or(DecodeError::OutOfBounds { offset, width: 1, len: self.vec.len() })?;
+        let cstr = CStr::from_bytes_until_nul(bytes)
+            .map_err(|_| DecodeError::UnterminatedString { offset })?;
+        let width = cstr.to_bytes_with_nul().len();
+        Ok((String::from_utf8_lossy(cstr.to_bytes()).to_string(), width))
     }
 
     pub fn get_vec_slice(&self, offset: usize, len: usize) -> &[u8] {
@@ -292,6 +642,19 @@ impl BitBuffer {
     }
 }
 
+fn sign_not_zero(value: f32) -> f32 {
+    if value >= 0.0 { 1.0 } else { -1.0 }
+}
+
+/// Maps signed to unsigned so numbers close to zero have small encodings (0, -1, 1, -2 -> 0, 1, 2, 3)
+pub fn zigzag_encode(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+pub fn zigzag_decode(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
 impl Read for BitBuffer {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         self.vec.write(buf)
@@ -312,7 +675,282 @@ impl From<Vec<u8>> for BitBuffer {
     fn from(vec: Vec<u8>) -> Self {
         Self {
             current_offset: 0,
+            bit_pos: 0,
+            mode: BitMode::default(),
             vec
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{PI, TAU};
+    use crate::def::{Vector3, ANGLE_BITS, POSITION_RANGE, VELOCITY_RANGE};
+    use crate::codec::tests::all_events;
+    use crate::events_client::ClientEvent;
+    use crate::events_server::ServerEvent;
+    use crate::packet::{Packet, PacketBuilder};
+    use crate::PacketSerialize;
+    use super::{zigzag_decode, zigzag_encode, BitBuffer, BitMode, DecodeError, FixedRange};
+
+    /// Writes with `write`, then reads back from a fresh buffer (like a received packet) with `read`
+    fn round_trip<T>(write: impl FnOnce(&mut BitBuffer), read: impl FnOnce(&mut BitBuffer) -> T) -> T {
+        let mut buf = BitBuffer::with_capacity(64);
+        write(&mut buf);
+        let mut buf = BitBuffer::from(buf.as_bytes().to_vec());
+        read(&mut buf)
+    }
+
+    fn angle_diff(a: f32, b: f32) -> f32 {
+        let diff = (a - b).rem_euclid(TAU);
+        diff.min(TAU - diff)
+    }
+
+    #[test]
+    fn quantized_f32_within_precision() {
+        let range = FixedRange::new(-10.0, 10.0, 12);
+        for i in 0..=200 {
+            let value = -10.0 + i as f32 * 0.1;
+            let read = round_trip(|b| b.write_quantized_f32(value, &range), |b| b.read_quantized_f32(&range).unwrap());
+            assert!((read - value).abs() <= range.precision() + f32::EPSILON * 10.0, "{} -> {}", value, read);
+        }
+    }
+
+    #[test]
+    fn quantized_f32_clamps_out_of_range() {
+        let range = FixedRange::new(0.0, 1.0, 8);
+        let read = round_trip(|b| b.write_quantized_f32(5.0, &range), |b| b.read_quantized_f32(&range).unwrap());
+        assert_eq!(read, 1.0);
+        let read = round_trip(|b| b.write_quantized_f32(-5.0, &range), |b| b.read_quantized_f32(&range).unwrap());
+        assert_eq!(read, 0.0);
+    }
+
+    #[test]
+    fn varints_round_trip_in_as_few_bytes_as_needed() {
+        for (value, len) in [(0, 1), (127, 1), (128, 2), (16_383, 2), (u32::MAX as u64, 5), (u64::MAX, 10)] {
+            let mut buf = BitBuffer::with_capacity(10);
+            buf.write_varint_u64(value);
+            assert_eq!(buf.len(), len, "{} took {} bytes", value, buf.len());
+            let mut buf = BitBuffer::from(buf.as_bytes().to_vec());
+            assert_eq!(buf.read_varint_u64(), Ok(value));
+        }
+        for value in [0, 127, 128, u32::MAX] {
+            assert_eq!(round_trip(|b| b.write_varint_u32(value), |b| b.read_varint_u32()), Ok(value));
+        }
+    }
+
+    #[test]
+    fn zigzag_keeps_small_negatives_small() {
+        for (value, encoded) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (i32::MAX, u32::MAX - 1), (i32::MIN, u32::MAX)] {
+            assert_eq!(zigzag_encode(value), encoded);
+            assert_eq!(zigzag_decode(encoded), value);
+        }
+        for value in [-1, -64, -65, i32::MIN] {
+            assert_eq!(round_trip(|b| b.write_varint_i32(value), |b| b.read_varint_i32()), Ok(value));
+        }
+        let mut buf = BitBuffer::with_capacity(1);
+        buf.write_varint_i32(-64);
+        assert_eq!(buf.len(), 1);
+    }
+
+    #[test]
+    fn varint_overflow_is_rejected() {
+        let mut buf = BitBuffer::from(vec![0xFF; 11]);
+        assert_eq!(buf.read_varint_u64(), Err(DecodeError::VarIntOverflow { offset: 0 }));
+        // Fits in a u64 but not a u32
+        let mut buf = BitBuffer::with_capacity(5);
+        buf.write_varint_u64(u32::MAX as u64 + 1);
+        let mut buf = BitBuffer::from(buf.as_bytes().to_vec());
+        assert_eq!(buf.read_varint_u32(), Err(DecodeError::VarIntOverflow { offset: 0 }));
+        // Continues past the end
+        let mut buf = BitBuffer::from(vec![0x80, 0x80]);
+        assert!(matches!(buf.read_varint_u64(), Err(DecodeError::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn quantized_position_and_velocity_within_precision() {
+        let position = Vector3::new(-1500.25, 0.001, 2047.9);
+        let velocity = Vector3::new(12.5, -127.0, 0.0);
+        let (pos_read, vel_read) = round_trip(|b| {
+            b.write_quantized_vector3(&position, &POSITION_RANGE);
+            b.write_quantized_vector3(&velocity, &VELOCITY_RANGE);
+        }, |b| (
+            b.read_quantized_vector3(&POSITION_RANGE).unwrap(),
+            b.read_quantized_vector3(&VELOCITY_RANGE).unwrap()
+        ));
+        for (a, b) in position.to_vec().iter().zip(pos_read.to_vec()) {
+            assert!((a - b).abs() <= POSITION_RANGE.precision() * 1.01, "{} -> {}", a, b);
+        }
+        for (a, b) in velocity.to_vec().iter().zip(vel_read.to_vec()) {
+            assert!((a - b).abs() <= VELOCITY_RANGE.precision() * 1.01, "{} -> {}", a, b);
+        }
+    }
+
+    #[test]
+    fn angles_within_precision() {
+        let max_error = PI / (1u32 << ANGLE_BITS) as f32 + 1e-5;
+        for i in -40..=40 {
+            let angle = i as f32 * 0.2;
+            let read = round_trip(|b| b.write_angle(angle, ANGLE_BITS), |b| b.read_angle(ANGLE_BITS).unwrap());
+            assert!((-PI..PI).contains(&read), "{} out of [-π, π)", read);
+            assert!(angle_diff(angle, read) <= max_error, "{} -> {}", angle, read);
+        }
+    }
+
+    #[test]
+    fn unit_vector_within_precision() {
+        let dirs = [
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::new(0.3, -0.5, 0.8),
+            Vector3::new(-0.7, 0.1, -0.2),
+            Vector3::new(-1.0, -1.0, -1.0),
+        ];
+        for bits in [10, 16] {
+            // Each component has precision 1/2^bits, the octahedral mapping stretches that by a few times at most
+            let max_error = 8.0 / (1u32 << bits) as f32;
+            for dir in dirs {
+                let dir = dir.normalize();
+                let read = round_trip(|b| b.write_unit_vector(&dir, bits), |b| b.read_unit_vector(bits).unwrap());
+                let error = Vector3::new(dir.x - read.x, dir.y - read.y, dir.z - read.z).length();
+                assert!((read.length() - 1.0).abs() < 1e-5);
+                assert!(error <= max_error, "{:?} -> {:?} (error {}, bits {})", dir, read, error, bits);
+            }
+        }
+    }
+
+    #[test]
+    fn vector3_is_lossless() {
+        let vec = Vector3::new(1.0e-7, -3.25, 123456.78);
+        let read = round_trip(|b| b.write_vector3(&vec), |b| b.read_vector3().unwrap());
+        assert_eq!(vec.to_vec(), read.to_vec());
+    }
+
+    #[test]
+    fn reads_past_the_end_are_out_of_bounds() {
+        let mut buf = BitBuffer::from(vec![0x1, 0x2, 0x3]);
+        assert_eq!(buf.read_u8(), Ok(0x1));
+        assert_eq!(buf.read_u32(), Err(DecodeError::OutOfBounds { offset: 1, width: 4, len: 3 }));
+        assert_eq!(buf.read_f32(), Err(DecodeError::OutOfBounds { offset: 1, width: 4, len: 3 }));
+        assert_eq!(buf.read_u16(), Ok(0x0302));
+        assert_eq!(buf.read_u8(), Err(DecodeError::OutOfBounds { offset: 3, width: 1, len: 3 }));
+        assert_eq!(buf.read_u16(), Err(DecodeError::OutOfBounds { offset: 3, width: 2, len: 3 }));
+        assert_eq!(buf.read_string(), Err(DecodeError::UnterminatedString { offset: 3 }));
+        assert!(buf.peek_u32_at(usize::MAX - 1).is_err());
+    }
+
+    #[test]
+    fn string_without_terminator_is_rejected() {
+        let mut buf = BitBuffer::from(b"hi\0there".to_vec());
+        assert_eq!(buf.read_string(), Ok("hi".to_string()));
+        assert_eq!(buf.read_string(), Err(DecodeError::UnterminatedString { offset: 3 }));
+    }
+
+    /// The event's packet with only the first `len` bytes of its payload
+    fn truncated(pk: &Packet, len: usize) -> Packet {
+        let payload = pk.payload_buf();
+        let mut builder = PacketBuilder::new(pk.packet_type());
+        for b in payload.get_vec_slice(0, len) {
+            builder.buf_mut().write_u8(*b);
+        }
+        builder.finalize()
+    }
+
+    #[test]
+    fn truncated_events_fail_to_decode() {
+        let (server, client) = all_events();
+        for event in server {
+            let pk = event.to_packet();
+            for len in 0..pk.payload_len() as usize {
+                assert!(ServerEvent::from_packet(&truncated(&pk, len)).is_err(), "{:?} decoded from {} bytes", event, len);
+            }
+        }
+        for event in client {
+            let pk = event.to_packet();
+            for len in 0..pk.payload_len() as usize {
+                assert!(ClientEvent::from_packet(&truncated(&pk, len)).is_err(), "{:?} decoded from {} bytes", event, len);
+            }
+        }
+    }
+
+    #[test]
+    fn bits_round_trip_across_bytes() {
+        let values: [(u32, u8); 6] = [(1, 1), (0b101, 3), (0x1FF, 9), (0, 1), (0xDEADBEEF, 32), (u32::MAX, 32)];
+        let read = round_trip(|b| {
+            for (value, bits) in values {
+                b.write_bits(value, bits);
+            }
+        }, |b| values.map(|(_, bits)| b.read_bits(bits).unwrap()));
+        assert_eq!(read, values.map(|(value, _)| value));
+    }
+
+    #[test]
+    fn bits_are_packed_lsb_first() {
+        let mut buf = BitBuffer::with_capacity(2);
+        buf.write_bool(true);
+        buf.write_bits(0b1111_1110, 8);
+        assert_eq!(buf.as_bytes(), &[0b1111_1101, 0b1]);
+        assert_eq!(buf.bit_offset_pos(), 9);
+    }
+
+    #[test]
+    fn bytes_after_bits_start_on_the_next_byte() {
+        let (flag, value, rest) = round_trip(|b| {
+            b.write_bool(true);
+            b.write_u16(0xABCD);
+            b.write_bits(0b11, 2);
+        }, |b| (b.read_bool().unwrap(), b.read_u16().unwrap(), b.read_bits(2).unwrap()));
+        assert_eq!((flag, value, rest), (true, 0xABCD, 0b11));
+        let mut buf = BitBuffer::with_capacity(4);
+        buf.write_bool(true);
+        buf.write_u16(0xABCD);
+        assert_eq!(buf.as_bytes(), &[0x1, 0xCD, 0xAB]);
+    }
+
+    #[test]
+    fn unaligned_bytes_are_packed_after_bits() {
+        let mut buf = BitBuffer::with_capacity(4);
+        buf.set_bit_mode(BitMode::Unaligned);
+        buf.write_bool(true);
+        buf.write_u16(0xABCD);
+        assert_eq!(buf.as_bytes(), &[0x9B, 0x57, 0x1]);
+        assert_eq!(buf.bit_offset_pos(), 17);
+    }
+
+    #[test]
+    fn unaligned_values_round_trip() {
+        let read = round_trip(|b| {
+            b.set_bit_mode(BitMode::Unaligned);
+            b.write_bits(0b101, 3);
+            b.write_u32(0xDEADBEEF);
+            b.write_string("hi");
+            b.write_bool(true);
+        }, |b| {
+            b.set_bit_mode(BitMode::Unaligned);
+            (b.read_bits(3).unwrap(), b.read_u32().unwrap(), b.read_string().unwrap(), b.read_bool().unwrap())
+        });
+        assert_eq!(read, (0b101, 0xDEADBEEF, "hi".to_string(), true));
+    }
+
+    #[test]
+    fn unaligned_string_without_terminator_is_rejected() {
+        let mut buf = BitBuffer::with_capacity(4);
+        buf.set_bit_mode(BitMode::Unaligned);
+        buf.write_bool(true);
+        buf.write_bits(b'h' as u32, 8);
+        let mut buf = BitBuffer::from(buf.as_bytes().to_vec());
+        buf.set_bit_mode(BitMode::Unaligned);
+        assert!(buf.read_bool().unwrap());
+        assert_eq!(buf.read_string(), Err(DecodeError::UnterminatedString { offset: 0 }));
+    }
+
+    #[test]
+    fn bits_past_the_end_are_out_of_bounds() {
+        let mut buf = BitBuffer::from(vec![0xFF]);
+        assert_eq!(buf.read_bits(7), Ok(0x7F));
+        assert_eq!(buf.read_bits(2), Err(DecodeError::OutOfBounds { offset: 0, width: 2, len: 1 }));
+        assert_eq!(buf.read_bool(), Ok(true));
+        assert!(buf.read_bool().is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! How event fields are written to packets. Used by `#[derive(PacketSerialize)]`.
+use crate::buffer::{BitBuffer, DecodeError};
+use crate::def::Vector3;
+use crate::events_server::{LoginRejectReason, LOGIN_REJECT_REASON_BITS};
+use crate::game::{Action, ACTION_BITS};
+
+/// A type that can be written as an event field, using its default wire format
+pub trait PacketField: Sized {
+    fn write_field(&self, buf: &mut BitBuffer);
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError>;
+}
+
+macro_rules! impl_packet_field {
+    ($($ty:ty => $write:ident, $read:ident);* $(;)?) => {
+        $(
+            impl PacketField for $ty {
+                fn write_field(&self, buf: &mut BitBuffer) {
+                    buf.$write(*self);
+                }
+
+                fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+                    buf.$read()
+                }
+            }
+        )*
+    };
+}
+
+impl_packet_field! {
+    u8 => write_u8, read_u8;
+    i8 => write_i8, read_i8;
+    u16 => write_u16, read_u16;
+    i16 => write_i16, read_i16;
+    u32 => write_u32, read_u32;
+    i32 => write_i32, read_i32;
+    f32 => write_f32, read_f32;
+    bool => write_bool, read_bool;
+}
+
+impl PacketField for String {
+    fn write_field(&self, buf: &mut BitBuffer) {
+        buf.write_string(self);
+    }
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+        buf.read_string()
+    }
+}
+
+impl PacketField for Vector3 {
+    fn write_field(&self, buf: &mut BitBuffer) {
+        buf.write_vector3(self);
+    }
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+        buf.read_vector3()
+    }
+}
+
+impl PacketField for Action {
+    fn write_field(&self, buf: &mut BitBuffer) {
+        buf.write_bits(self.bits(), ACTION_BITS);
+    }
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+        buf.read_bits(ACTION_BITS).map(Action::from_bits_retain)
+    }
+}
+
+/// On its own in LoginRejected this is still the byte a u8 would be, so older clients read it the same
+impl PacketField for LoginRejectReason {
+    fn write_field(&self, buf: &mut BitBuffer) {
+        buf.write_bits(u8::from(*self) as u32, LOGIN_REJECT_REASON_BITS);
+    }
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+        let value = buf.read_bits(LOGIN_REJECT_REASON_BITS)? as u8;
+        LoginRejectReason::try_from(value).map_err(|_| DecodeError::UnknownVariant { value })
+    }
+}
+
+impl<const N: usize> PacketField for [u8; N] {
+    fn write_field(&self, buf: &mut BitBuffer) {
+        for byte in self {
+            buf.write_u8(*byte);
+        }
+    }
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+        let mut bytes = [0; N];
+        for byte in &mut bytes {
+            *byte = buf.read_u8()?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// A presence bit, then the value if there is one
+impl<T: PacketField> PacketField for Option<T> {
+    fn write_field(&self, buf: &mut BitBuffer) {
+        buf.write_bool(self.is_some());
+        if let Some(value) = self {
+            value.write_field(buf);
+        }
+    }
+
+    fn read_field(buf: &mut BitBuffer) -> Result<Self, DecodeError> {
+        if buf.read_bool()? {
+            T::read_field(buf).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// `#[packet(varint)]` - u32 as a LEB128 varint
+pub mod varint {
+    use crate::buffer::{BitBuffer, DecodeError};
+
+    pub fn write(value: &u32, buf: &mut BitBuffer) {
+        buf.write_varint_u32(*value);
+    }
+
+    pub fn read(buf: &mut BitBuffer) -> Result<u32, DecodeError> {
+        buf.read_varint_u32()
+    }
+}
+
+/// `#[packet(with = codec::position)]` - world position quantized to [POSITION_RANGE](crate::def::POSITION_RANGE)
+pub mod position {
+    use crate::buffer::{BitBuffer, DecodeError};
+    use crate::def::{Vector3, POSITION_RANGE};
+
+    pub fn write(value: &Vector3, buf: &mut BitBuffer) {
+        buf.write_quantized_vector3(value, &POSITION_RANGE);
+    }
+
+    pub fn read(buf: &mut BitBuffer) -> Result<Vector3, DecodeError> {
+        buf.read_quantized_vector3(&POSITION_RANGE)
+    }
+}
+
+/// `#[packet(with = codec::velocity)]` - velocity quantized to [VELOCITY_RANGE](crate::def::VELOCITY_RANGE)
+pub mod velocity {
+    use crate::buffer::{BitBuffer, DecodeError};
+    use crate::def::{Vector3, VELOCITY_RANGE};
+
+    pub fn write(value: &Vector3, buf: &mut BitBuffer) {
+        buf.write_quantized_vector3(value, &VELOCITY_RANGE);
+    }
+
+    pub fn read(buf: &mut BitBuffer) -> Result<Vector3, DecodeError> {
+        buf.read_quantized_vector3(&VELOCITY_RANGE)
+    }
+}
+
+/// `#[packet(with = codec::angles)]` - angles compressed to [ANGLE_BITS](crate::def::ANGLE_BITS) per axis
+pub mod angles {
+    use crate::buffer::{BitBuffer, DecodeError};
+    use crate::def::{Vector3, ANGLE_BITS};
+
+    pub fn write(value: &Vector3, buf: &mut BitBuffer) {
+        buf.write_angles(value, ANGLE_BITS);
+    }
+
+    pub fn read(buf: &mut BitBuffer) -> Result<Vector3, DecodeError> {
+        buf.read_angles(ANGLE_BITS)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::def::Vector3;
+    use crate::events_client::ClientEvent;
+    use crate::buffer::BitBuffer;
+    use crate::events_server::{LoginRejectReason, ServerEvent, LOGIN_REJECT_REASON_BITS};
+    use crate::game::Action;
+    use crate::PacketSerialize;
+    use super::PacketField;
+
+    /// Every event, with all fields set so no prefix of its payload is a valid payload too
+    pub(crate) fn all_events() -> (Vec<ServerEvent>, Vec<ClientEvent>) {
+        let server = vec![
+            ServerEvent::Ack { seq_number: 300 },
+            ServerEvent::Login { client_index: 1, auth_id: 2, public_key: Some([0x42; 32]), min_version: 3, max_version: 4 },
+            ServerEvent::Move { client_index: 1, position: Vector3::new(1.0, 2.0, 3.0), angles: Vector3::new(0.1, 0.2, 0.3), velocity: Vector3::new(-1.0, 0.0, 1.0) },
+            ServerEvent::PlayerSpawn { client_index: 1, name: "player".to_string(), position: Vector3::new(1.0, 2.0, 3.0), angles: Vector3::new(0.1, 0.2, 0.3) },
+            ServerEvent::Disconnect { client_index: 1, reason: "bye".to_string() },
+            ServerEvent::CommandResult { id: 1, result: true },
+            ServerEvent::Challenge { cookie: [0x7; 20] },
+            ServerEvent::LoginRejected { reason: LoginRejectReason::NameTaken },
+            ServerEvent::Ping { rtt_ms: 300 },
+        ];
+        let client = vec![
+            ClientEvent::Ack { seq_number: 300 },
+            ClientEvent::Login { version: 1, name: "player".to_string(), public_key: Some([0x42; 32]), cookie: Some([0x7; 20]) },
+            ClientEvent::PerformAction { actions: Action::Forward | Action::Left, angles: Vector3::new(0.1, 0.2, 0.3) },
+            ClientEvent::Disconnect { reason: "bye".to_string() },
+            ClientEvent::Command { command: "status".to_string(), id: 1 },
+        ];
+        (server, client)
+    }
+
+    #[test]
+    fn every_event_round_trips() {
+        let (server, client) = all_events();
+        for event in server {
+            let pk = event.to_packet();
+            let read = ServerEvent::from_packet(&pk).unwrap();
+            assert_eq!(read.get_packet_type(), event.get_packet_type());
+            // Quantized fields aren't exact, but they are once quantized
+            assert_eq!(read.to_packet().payload_buf().as_hex_str(), pk.payload_buf().as_hex_str(), "{:?} read as {:?}", event, read);
+        }
+        for event in client {
+            let pk = event.to_packet();
+            let read = ClientEvent::from_packet(&pk).unwrap();
+            assert_eq!(format!("{:?}", read), format!("{:?}", event));
+        }
+    }
+
+    #[test]
+    fn fields_are_written_in_declaration_order() {
+        let position = Vector3::new(1.5, -2.0, 3.25);
+        let angles = Vector3::new(0.5, 0.0, -0.5);
+        let pk = ServerEvent::PlayerSpawn { client_index: 3, name: "abc".to_string(), position, angles }.to_packet();
+        let mut payload = pk.payload_buf();
+        assert_eq!(payload.read_varint_u32().unwrap(), 3);
+        assert_eq!(payload.read_string().unwrap(), "abc");
+        assert_eq!(payload.read_vector3().unwrap().to_vec(), position.to_vec());
+        assert_eq!(payload.read_vector3().unwrap().to_vec(), angles.to_vec());
+        assert!(!payload.can_read());
+
+        let ServerEvent::PlayerSpawn { client_index, name, position: read_position, angles: read_angles } = ServerEvent::from_packet(&pk).unwrap() else {
+            panic!("expected player spawn");
+        };
+        assert_eq!((client_index, name.as_str()), (3, "abc"));
+        assert_eq!((read_position.to_vec(), read_angles.to_vec()), (position.to_vec(), angles.to_vec()));
+    }
+
+    #[test]
+    fn login_reject_reason_fits_in_its_bits() {
+        let reasons = [LoginRejectReason::ServerFull, LoginRejectReason::BadVersion, LoginRejectReason::Banned,
+            LoginRejectReason::NameTaken, LoginRejectReason::EncryptionRequired];
+        for reason in reasons {
+            assert!(u8::from(reason) >> LOGIN_REJECT_REASON_BITS == 0, "{:?} needs more than {} bits", reason, LOGIN_REJECT_REASON_BITS);
+            let mut buf = BitBuffer::with_capacity(1);
+            reason.write_field(&mut buf);
+            buf.write_bool(true);
+            assert_eq!(buf.bit_offset_pos(), LOGIN_REJECT_REASON_BITS as usize + 1);
+            // Same byte older clients read as a u8
+            let pk = ServerEvent::LoginRejected { reason }.to_packet();
+            assert_eq!(pk.payload_buf().read_u8().unwrap(), u8::from(reason));
+        }
+    }
+
+    #[test]
+    fn unknown_packet_type_is_an_error() {
+        let pk = crate::packet::PacketBuilder::new(0xEE).finalize();
+        assert!(ServerEvent::from_packet(&pk).is_err());
+        assert!(ClientEvent::from_packet(&pk).is_err());
+    }
+}
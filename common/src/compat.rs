@@ -0,0 +1,164 @@
+//! Talking to clients on an older protocol version, back to [MIN_PROTOCOL_VERSION].
+//!
+//! The server accepts their packets as is and rewrites what it sends them into their layout, see [server_packet_for].
+//! Clients only ever speak their own version, it's up to the server to match them.
+//!
+//! Kept the same across every version, so that mismatched peers can still tell each other apart:
+//! - The first two header fields, magic then version
+//! - A client's Login is sent on its own, in a plain datagram (no compression, encryption or fragments)
+//! - The [version info](crate::datagram::version_info_datagram) datagram sent back for unsupported versions
+//!
+//! Differences from protocol 15:
+//! - Its batches are compressed with the dictionary from before the last retrain, see [crate::datagram::PREVIOUS_PACKET_DICTIONARY]
+//!
+//! Differences from protocol 14, on top of those:
+//! - Its clients send nothing reliable, so there's no [ServerEvent::Ack] to send them
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use log::warn;
+use crate::buffer::DecodeError;
+use crate::events_server::ServerEvent;
+use crate::packet::{Packet, PacketBuilder};
+use crate::{PacketSerialize, MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION};
+
+/// First version whose clients send reliable events, and so need acks
+const RELIABLE_CLIENT_VERSION: u16 = 15;
+
+/// Whether we can talk to a peer on the version
+pub fn is_supported(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PACKET_PROTOCOL_VERSION).contains(&version)
+}
+
+/// Copies everything in the header but the type and length (which are written by the builder) and the version
+fn with_header_of(builder: PacketBuilder, pk: &Packet, version: u16) -> PacketBuilder {
+    builder
+        .with_version(version)
+        .with_timestamp(pk.timestamp())
+        .with_auth_id(pk.auth_id())
+        .with_sequence_number(pk.sequence_number())
+        .with_ack(pk.ack())
+        .with_ack_bits(pk.ack_bits())
+        .with_channel_seq(pk.channel_seq())
+}
+
+/// Rewrites a packet from us into the layout of an older (supported) protocol version, keeping its header.
+/// None if the version doesn't have the event
+pub fn server_packet_for(pk: &Packet, version: u16) -> Result<Option<Packet>, DecodeError> {
+    if version == PACKET_PROTOCOL_VERSION {
+        return Ok(Some(pk.clone()));
+    }
+    debug_assert!(is_supported(version), "protocol version {} is not supported", version);
+    // Events that changed since get matched here and written in the old layout
+    let builder = match ServerEvent::from_packet(pk)? {
+        ServerEvent::Ack { .. } if version < RELIABLE_CLIENT_VERSION => return Ok(None),
+        event => event.to_packet_builder(),
+    };
+    Ok(Some(with_header_of(builder, pk, version).finalize()))
+}
+
+/// Protocol version of peers on an older one, everyone else is on ours. Only the server has any
+#[derive(Clone, Default)]
+pub struct PeerVersions {
+    versions: Arc<Mutex<HashMap<SocketAddr, u16>>>,
+}
+
+impl PeerVersions {
+    pub fn set(&self, addr: SocketAddr, version: u16) {
+        let mut lock = self.versions.lock().unwrap();
+        if version == PACKET_PROTOCOL_VERSION {
+            lock.remove(&addr);
+        } else {
+            lock.insert(addr, version);
+        }
+    }
+
+    pub fn remove(&self, addr: SocketAddr) {
+        self.versions.lock().unwrap().remove(&addr);
+    }
+
+    pub fn get(&self, addr: SocketAddr) -> u16 {
+        self.versions.lock().unwrap().get(&addr).copied().unwrap_or(PACKET_PROTOCOL_VERSION)
+    }
+
+    /// Rewrites packets going to addr into its version's layout with [server_packet_for], if it's on an older one
+    pub fn rewrite<'a>(&self, addr: SocketAddr, packets: &'a [Packet]) -> Cow<'a, [Packet]> {
+        let version = self.get(addr);
+        if version == PACKET_PROTOCOL_VERSION {
+            return Cow::Borrowed(packets);
+        }
+        Cow::Owned(packets.iter().filter_map(|pk| match server_packet_for(pk, version) {
+            Ok(pk) => pk,
+            Err(e) => {
+                warn!("dropping packet (type={}) for protocol {} peer {}: {}", pk.packet_type(), version, addr, e);
+                None
+            }
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events_server::LoginRejectReason;
+
+    #[test]
+    fn header_is_kept_for_previous_version() {
+        let pk = ServerEvent::Login {
+            client_index: 3,
+            auth_id: 0xCAFE,
+            public_key: Some([7; 32]),
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PACKET_PROTOCOL_VERSION,
+        }.to_packet_builder().with_sequence_number(12).with_ack(4).with_ack_bits(0b101).with_channel_seq(2).finalize();
+
+        let old = server_packet_for(&pk, MIN_PROTOCOL_VERSION).unwrap().unwrap();
+        let old = Packet::try_from(old.as_slice().to_vec()).unwrap();
+        assert_eq!(old.version(), MIN_PROTOCOL_VERSION);
+        assert_eq!(old.sequence_number(), 12);
+        assert_eq!(old.ack(), 4);
+        assert_eq!(old.ack_bits(), 0b101);
+        assert_eq!(old.channel_seq(), 2);
+        assert_eq!(old.timestamp(), pk.timestamp());
+        let ServerEvent::Login { client_index, auth_id, public_key, .. } = ServerEvent::from_packet(&old).unwrap() else {
+            panic!("expected login");
+        };
+        assert_eq!((client_index, auth_id, public_key), (3, 0xCAFE, Some([7; 32])));
+    }
+
+    #[test]
+    fn unchanged_events_keep_their_payload() {
+        let pk = ServerEvent::LoginRejected { reason: LoginRejectReason::NameTaken }.to_packet();
+        let old = server_packet_for(&pk, MIN_PROTOCOL_VERSION).unwrap().unwrap();
+        assert_eq!(old.version(), MIN_PROTOCOL_VERSION);
+        assert_eq!(old.payload_len(), pk.payload_len());
+        assert!(matches!(
+            ServerEvent::from_packet(&old).unwrap(),
+            ServerEvent::LoginRejected { reason: LoginRejectReason::NameTaken }
+        ));
+    }
+
+    #[test]
+    fn ack_is_not_sent_to_version_without_reliable_clients() {
+        let addr = "10.0.0.1:5000".parse().unwrap();
+        let versions = PeerVersions::default();
+        versions.set(addr, RELIABLE_CLIENT_VERSION - 1);
+        let packets = [ServerEvent::Ack { seq_number: 1 }.to_packet(), ServerEvent::CommandResult { id: 1, result: true }.to_packet()];
+        let rewritten = versions.rewrite(addr, &packets);
+        assert_eq!(rewritten.len(), 1);
+        assert_eq!(rewritten[0].packet_type(), packets[1].packet_type());
+    }
+
+    #[test]
+    fn previous_version_gets_every_packet() {
+        let addr = "10.0.0.1:5000".parse().unwrap();
+        let versions = PeerVersions::default();
+        versions.set(addr, PACKET_PROTOCOL_VERSION - 1);
+        let packets = [ServerEvent::Ack { seq_number: 1 }.to_packet(), ServerEvent::CommandResult { id: 1, result: true }.to_packet()];
+        let rewritten = versions.rewrite(addr, &packets);
+        assert_eq!(rewritten.len(), 2);
+        assert!(rewritten.iter().zip(&packets).all(|(old, pk)| old.packet_type() == pk.packet_type()));
+        assert!(rewritten.iter().all(|old| old.version() == PACKET_PROTOCOL_VERSION - 1));
+    }
+}
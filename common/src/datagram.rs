@@ -0,0 +1,725 @@
+//! What actually goes over the wire. Packets going to the same address are batched back to back,
+//! compressed with [PACKET_DICTIONARY] (or [PREVIOUS_PACKET_DICTIONARY] for peers on an older version),
+//! then split into fragments if they don't fit in one datagram.
+//!
+//! Every datagram starts with a flags byte:
+//! - [FLAG_COMPRESSED]: the batch is zstd compressed. Batches under the compression threshold, or that don't shrink, are sent as is
+//! - [FLAG_FRAGMENT]: followed by [u16 fragment id][u8 index][u8 count], then that fragment's chunk of the batch.
+//!   Otherwise the rest of the datagram is the whole batch
+//! - [FLAG_ENCRYPTED]: everything after the flags byte is sealed by the peer's [Session](crate::session::Session).
+//!   Room for the encryption overhead is always left, so turning encryption on doesn't change how packets are split
+//! - [FLAG_VERSION_INFO]: sent instead of anything else to peers on an unsupported protocol version,
+//!   see [version_info_datagram]. Never combined with the other flags
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use log::{trace, warn};
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use zstd::{zstd_safe, DEFAULT_COMPRESSION_LEVEL};
+use crate::network::NetConfig;
+use crate::packet::{Packet, PacketError, MAX_PACKET_SIZE, PACKET_MAGIC};
+use crate::session::{Sessions, SESSION_OVERHEAD};
+use crate::compat::PeerVersions;
+use crate::{MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION};
+
+/// Datagram is one fragment of a larger batch
+pub const FLAG_FRAGMENT: u8 = 0b0000_0001;
+/// Batch is compressed
+pub const FLAG_COMPRESSED: u8 = 0b0000_0010;
+/// Datagram is encrypted
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0100;
+/// Datagram is the versions we support, in reply to a peer on another version. Same in every version
+pub const FLAG_VERSION_INFO: u8 = 0b1000_0000;
+
+/// Largest datagram we send, unless configured otherwise. Stays below the IPv6 minimum MTU (1280) minus IP and UDP headers
+pub const DEFAULT_MTU: u16 = 1200;
+/// Smallest configurable MTU
+pub const MIN_MTU: u16 = 256;
+/// Batches smaller than this are not worth compressing, unless configured otherwise
+pub const DEFAULT_COMPRESS_THRESHOLD: u16 = 64;
+/// Size of the receive buffer, large enough for any UDP datagram no matter the sender's MTU
+pub const MAX_DATAGRAM_SIZE: usize = 65536;
+/// How long to wait for the rest of a fragmented packet before throwing away what we have
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most incomplete fragmented packets kept at once, fragments of new ones are rejected past this
+const MAX_PENDING_FRAGMENTS: usize = 256;
+/// Most incomplete fragmented packets kept for one address, its oldest is dropped past this
+const MAX_PENDING_FRAGMENTS_PER_PEER: usize = 4;
+/// Most bytes of fragments kept at once, new fragments are rejected past this
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+
+const FLAGS_LEN: usize = 1;
+const FRAGMENT_HEADER_LEN: usize = FLAGS_LEN + 4;
+
+const VERSION_INFO_LEN: usize = FLAGS_LEN + 6;
+
+/// Tells a peer on an unsupported protocol version which ones we support:
+/// `[FLAG_VERSION_INFO][u16 PACKET_MAGIC][u16 min version][u16 max version]`.
+/// This layout can never change, so that every version can read it
+pub fn version_info_datagram() -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(VERSION_INFO_LEN);
+    datagram.push(FLAG_VERSION_INFO);
+    datagram.extend_from_slice(&PACKET_MAGIC.to_le_bytes());
+    datagram.extend_from_slice(&MIN_PROTOCOL_VERSION.to_le_bytes());
+    datagram.extend_from_slice(&PACKET_PROTOCOL_VERSION.to_le_bytes());
+    datagram
+}
+
+fn read_version_info(datagram: &[u8]) -> Result<(u16, u16), PacketError> {
+    if datagram.len() < VERSION_INFO_LEN {
+        return Err(PacketError::TooShort { len: datagram.len(), expected: VERSION_INFO_LEN });
+    }
+    let field = |offset: usize| u16::from_le_bytes([datagram[offset], datagram[offset + 1]]);
+    let magic = field(FLAGS_LEN);
+    if magic != PACKET_MAGIC {
+        return Err(PacketError::BadMagic { magic });
+    }
+    Ok((field(FLAGS_LEN + 2), field(FLAGS_LEN + 4)))
+}
+
+/// zstd dictionary trained on typical batches, both sides must use the same one.
+/// Regenerate with `cargo run -p mp-game-test-common --example train_dict`
+pub static PACKET_DICTIONARY: &[u8] = include_bytes!("../dict/packets.dict");
+/// The dictionary older versions were built with, batches to and from peers on them are compressed with this one
+pub static PREVIOUS_PACKET_DICTIONARY: &[u8] = include_bytes!("../dict/packets-v15.dict");
+
+/// Dictionary to compress batches for a peer on the version with
+fn encoder_dictionary(version: u16) -> &'static EncoderDictionary<'static> {
+    static DICTIONARY: OnceLock<EncoderDictionary<'static>> = OnceLock::new();
+    static PREVIOUS: OnceLock<EncoderDictionary<'static>> = OnceLock::new();
+    if version < PACKET_PROTOCOL_VERSION {
+        PREVIOUS.get_or_init(|| EncoderDictionary::copy(PREVIOUS_PACKET_DICTIONARY, DEFAULT_COMPRESSION_LEVEL))
+    } else {
+        DICTIONARY.get_or_init(|| EncoderDictionary::copy(PACKET_DICTIONARY, DEFAULT_COMPRESSION_LEVEL))
+    }
+}
+
+fn decoder_dictionary() -> &'static DecoderDictionary<'static> {
+    static DICTIONARY: OnceLock<DecoderDictionary<'static>> = OnceLock::new();
+    DICTIONARY.get_or_init(|| DecoderDictionary::copy(PACKET_DICTIONARY))
+}
+
+fn previous_decoder_dictionary() -> &'static DecoderDictionary<'static> {
+    static DICTIONARY: OnceLock<DecoderDictionary<'static>> = OnceLock::new();
+    DICTIONARY.get_or_init(|| DecoderDictionary::copy(PREVIOUS_PACKET_DICTIONARY))
+}
+
+/// Whether a compressed batch was compressed with [PREVIOUS_PACKET_DICTIONARY], going by the id in its zstd frame
+fn uses_previous_dictionary(bytes: &[u8]) -> bool {
+    let previous = zstd_safe::get_dict_id_from_dict(PREVIOUS_PACKET_DICTIONARY);
+    previous.is_some() && zstd_safe::get_dict_id_from_frame(bytes) == previous
+}
+
+/// Turns packets into datagrams no bigger than the MTU. Clones share fragment ids.
+#[derive(Clone)]
+pub struct DatagramEncoder {
+    mtu: u16,
+    compress_threshold: u16,
+    next_fragment_id: Arc<AtomicU16>,
+    /// Writes every batch (before compression) here, as samples for training [PACKET_DICTIONARY]
+    capture_dir: Option<PathBuf>,
+    capture_count: Arc<AtomicU32>,
+    sessions: Sessions,
+    versions: PeerVersions,
+}
+
+impl DatagramEncoder {
+    pub fn new(config: &NetConfig, sessions: Sessions) -> Self {
+        assert!(config.mtu >= MIN_MTU, "mtu must be at least {}", MIN_MTU);
+        if let Some(dir) = &config.capture_dir {
+            fs::create_dir_all(dir).expect("could not create capture dir");
+        }
+        Self {
+            mtu: config.mtu,
+            compress_threshold: config.compress_threshold,
+            next_fragment_id: Arc::new(AtomicU16::new(0)),
+            capture_dir: config.capture_dir.clone(),
+            capture_count: Arc::new(AtomicU32::new(0)),
+            sessions,
+            versions: PeerVersions::default(),
+        }
+    }
+
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Sessions used to encrypt datagrams, by address
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// Protocol versions of peers on an older one, their packets are rewritten for it
+    pub fn versions(&self) -> &PeerVersions {
+        &self.versions
+    }
+
+    /// Space for the flags byte and batch in a datagram, after leaving room for encryption
+    fn datagram_len(&self) -> usize {
+        self.mtu as usize - SESSION_OVERHEAD
+    }
+
+    /// Largest batch (after compression) that can be sent, split into the max of 255 fragments
+    pub fn max_packet_size(&self) -> usize {
+        (self.datagram_len() - FRAGMENT_HEADER_LEN) * u8::MAX as usize
+    }
+
+    /// A lone packet in a datagram, never compressed, encrypted or fragmented, so a peer on any version can read its header.
+    /// Only used for Login, see [crate::compat]
+    pub fn encode_plain(&self, packet: &Packet) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(FLAGS_LEN + packet.as_slice().len());
+        datagram.push(0);
+        datagram.extend_from_slice(packet.as_slice());
+        datagram
+    }
+
+    /// Batches and compresses the packets going to addr, returning the datagram(s) to send in order,
+    /// encrypted if there is a session with addr. Packets are rewritten first if addr is on an older protocol version.
+    /// A batch is closed once adding the next packet would take it past the MTU (uncompressed),
+    /// so a lost datagram only loses the packets in it.
+    pub fn encode(&self, addr: SocketAddr, packets: &[Packet]) -> Result<Vec<Vec<u8>>, PacketError> {
+        // The header's length would have wrapped, the peer could never read it back
+        if let Some(pk) = packets.iter().find(|pk| pk.as_slice().len() > MAX_PACKET_SIZE) {
+            return Err(PacketError::TooLarge { len: pk.as_slice().len(), max: MAX_PACKET_SIZE });
+        }
+        let version = self.versions.get(addr);
+        let packets = self.versions.rewrite(addr, packets);
+        let packets = packets.as_ref();
+        let mtu = self.datagram_len();
+        let mut datagrams = Vec::new();
+        let mut batch_start = 0;
+        let mut batch_len = 0;
+        for (i, pk) in packets.iter().enumerate() {
+            let len = pk.as_slice().len();
+            if i > batch_start && batch_len + len > mtu {
+                self.encode_batch(&packets[batch_start..i], version, &mut datagrams)?;
+                batch_start = i;
+                batch_len = 0;
+            }
+            batch_len += len;
+        }
+        if batch_start < packets.len() {
+            self.encode_batch(&packets[batch_start..], version, &mut datagrams)?;
+        }
+        datagrams.into_iter()
+            .map(|datagram| self.sessions.seal(addr, datagram))
+            .collect()
+    }
+
+    fn encode_batch(&self, packets: &[Packet], version: u16, datagrams: &mut Vec<Vec<u8>>) -> Result<(), PacketError> {
+        let batch = Packet::concat_batch(packets);
+        self.capture(&batch);
+        let (flags, bytes) = self.compress(batch, version)?;
+        let mtu = self.datagram_len();
+        if FLAGS_LEN + bytes.len() <= mtu {
+            let mut datagram = Vec::with_capacity(FLAGS_LEN + bytes.len());
+            datagram.push(flags);
+            datagram.extend_from_slice(&bytes);
+            datagrams.push(datagram);
+            return Ok(());
+        }
+
+        if bytes.len() > self.max_packet_size() {
+            return Err(PacketError::TooLarge { len: bytes.len(), max: self.max_packet_size() });
+        }
+        let chunks = bytes.chunks(mtu - FRAGMENT_HEADER_LEN);
+        let count = chunks.len() as u8;
+        let fragment_id = self.next_fragment_id.fetch_add(1, Ordering::Relaxed);
+        trace!("splitting {}B packet into {} fragments (id={})", bytes.len(), count, fragment_id);
+        datagrams.extend(chunks.enumerate().map(|(index, chunk)| {
+            let mut datagram = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            datagram.push(flags | FLAG_FRAGMENT);
+            datagram.extend_from_slice(&fragment_id.to_le_bytes());
+            datagram.push(index as u8);
+            datagram.push(count);
+            datagram.extend_from_slice(chunk);
+            datagram
+        }));
+        Ok(())
+    }
+
+    /// Compresses the batch if it's large enough and compression makes it smaller, returning its flags
+    fn compress(&self, batch: Vec<u8>, version: u16) -> Result<(u8, Vec<u8>), PacketError> {
+        if batch.len() < self.compress_threshold as usize {
+            return Ok((0, batch));
+        }
+        let mut compressor = Compressor::with_prepared_dictionary(encoder_dictionary(version))
+            .map_err(PacketError::Compress)?;
+        let compressed = compressor.compress(&batch).map_err(PacketError::Compress)?;
+        trace!("compressed batch {}B -> {}B", batch.len(), compressed.len());
+        if compressed.len() < batch.len() {
+            Ok((FLAG_COMPRESSED, compressed))
+        } else {
+            Ok((0, batch))
+        }
+    }
+
+    fn capture(&self, batch: &[u8]) {
+        if let Some(dir) = &self.capture_dir {
+            let n = self.capture_count.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{}.bin", std::process::id(), n));
+            if let Err(e) = fs::write(&path, batch) {
+                warn!("could not write captured batch to {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+struct PendingFragments {
+    flags: u8,
+    chunks: Vec<Option<Vec<u8>>>,
+    remaining: usize,
+    /// Sum of the received chunks' lengths
+    bytes: usize,
+    first_seen: Instant,
+}
+
+/// Decrypts datagrams and collects fragments until the whole batch has been received, then decompresses it
+pub struct DatagramDecoder {
+    pending: HashMap<(SocketAddr, u16), PendingFragments>,
+    /// Sum of every pending packet's bytes, see [MAX_PENDING_BYTES]
+    pending_bytes: usize,
+    /// Longest chunk a fragment can carry, anything longer wasn't split by a peer on our MTU
+    max_chunk_len: usize,
+    timeout: Duration,
+    decompressor: Decompressor<'static>,
+    /// For batches from peers on an older version
+    previous_decompressor: Decompressor<'static>,
+    sessions: Sessions,
+}
+
+impl DatagramDecoder {
+    pub fn new(config: &NetConfig, timeout: Duration, sessions: Sessions) -> Self {
+        assert!(config.mtu >= MIN_MTU, "mtu must be at least {}", MIN_MTU);
+        Self {
+            pending: HashMap::new(),
+            pending_bytes: 0,
+            max_chunk_len: config.mtu as usize - FRAGMENT_HEADER_LEN,
+            timeout,
+            sessions,
+            decompressor: Decompressor::with_prepared_dictionary(decoder_dictionary())
+                .expect("could not create decompressor"),
+            previous_decompressor: Decompressor::with_prepared_dictionary(previous_decoder_dictionary())
+                .expect("could not create decompressor"),
+        }
+    }
+
+    /// Number of packets still waiting on fragments
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Throws away incomplete packets older than the timeout, returning how many were dropped
+    pub fn expire(&mut self) -> usize {
+        let before = self.pending.len();
+        let timeout = self.timeout;
+        let pending_bytes = &mut self.pending_bytes;
+        self.pending.retain(|_, pending| {
+            let keep = pending.first_seen.elapsed() < timeout;
+            if !keep {
+                *pending_bytes -= pending.bytes;
+            }
+            keep
+        });
+        before - self.pending.len()
+    }
+
+    fn remove_pending(&mut self, key: (SocketAddr, u16)) -> Option<PendingFragments> {
+        let pending = self.pending.remove(&key)?;
+        self.pending_bytes -= pending.bytes;
+        Some(pending)
+    }
+
+    /// Makes room for a new incomplete packet from addr, by dropping its oldest if it has too many.
+    /// Other addresses' packets are never dropped for it, it is rejected instead if there are too many in total
+    fn make_room(&mut self, addr: SocketAddr) -> Result<(), PacketError> {
+        let from_addr = self.pending.iter().filter(|((from, _), _)| *from == addr);
+        if from_addr.clone().count() >= MAX_PENDING_FRAGMENTS_PER_PEER {
+            let oldest = from_addr.min_by_key(|(_, pending)| pending.first_seen).map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.remove_pending(oldest);
+            }
+        }
+        if self.pending.len() >= MAX_PENDING_FRAGMENTS {
+            return Err(PacketError::TooManyFragments);
+        }
+        Ok(())
+    }
+
+    /// Accepts a received datagram, returning its batch of packets if it was complete or was the last missing fragment.
+    /// Returns an empty list while waiting on more fragments.
+    /// Returns [PacketError::VersionRejected] if the peer doesn't support our protocol version
+    pub fn accept(&mut self, addr: SocketAddr, datagram: &[u8]) -> Result<Vec<Packet>, PacketError> {
+        if datagram.first().is_some_and(|flags| flags & FLAG_VERSION_INFO != 0) {
+            // Can't be authenticated, and a peer we have a session with already agreed on a version
+            if self.sessions.contains(addr) {
+                return Err(PacketError::Unencrypted);
+            }
+            let (min, max) = read_version_info(datagram)?;
+            return Err(PacketError::VersionRejected { min, max });
+        }
+        let decrypted = self.sessions.open(addr, datagram)?;
+        let datagram = decrypted.as_deref().unwrap_or(datagram);
+        let Some(&flags) = datagram.first() else {
+            return Err(PacketError::TooShort { len: 0, expected: FLAGS_LEN });
+        };
+        if flags & FLAG_FRAGMENT == 0 {
+            return self.decode_batch(flags, &datagram[FLAGS_LEN..]);
+        }
+
+        if datagram.len() <= FRAGMENT_HEADER_LEN {
+            return Err(PacketError::TooShort { len: datagram.len(), expected: FRAGMENT_HEADER_LEN + 1 });
+        }
+        let fragment_id = u16::from_le_bytes([datagram[1], datagram[2]]);
+        let (index, count) = (datagram[3], datagram[4]);
+        if index >= count {
+            return Err(PacketError::BadFragment { index, count });
+        }
+        let chunk = &datagram[FRAGMENT_HEADER_LEN..];
+        if chunk.len() > self.max_chunk_len {
+            return Err(PacketError::TooLarge { len: chunk.len(), max: self.max_chunk_len });
+        }
+
+        self.expire();
+        if !self.pending.contains_key(&(addr, fragment_id)) {
+            self.make_room(addr)?;
+        }
+        if self.pending_bytes + chunk.len() > MAX_PENDING_BYTES {
+            return Err(PacketError::TooManyFragments);
+        }
+        let pending = self.pending.entry((addr, fragment_id)).or_insert_with(|| PendingFragments {
+            flags,
+            chunks: vec![None; count as usize],
+            remaining: count as usize,
+            bytes: 0,
+            first_seen: Instant::now(),
+        });
+        if pending.chunks.len() != count as usize {
+            return Err(PacketError::BadFragment { index, count });
+        }
+        let slot = &mut pending.chunks[index as usize];
+        if slot.is_none() {
+            *slot = Some(chunk.to_vec());
+            pending.remaining -= 1;
+            pending.bytes += chunk.len();
+            self.pending_bytes += chunk.len();
+        }
+        if pending.remaining > 0 {
+            return Ok(Vec::new());
+        }
+
+        let pending = self.remove_pending((addr, fragment_id)).unwrap();
+        let bytes: Vec<u8> = pending.chunks.into_iter().flatten().flatten().collect();
+        trace!("reassembled {}B packet from {} fragments (id={})", bytes.len(), count, fragment_id);
+        self.decode_batch(pending.flags, &bytes)
+    }
+
+    fn decode_batch(&mut self, flags: u8, bytes: &[u8]) -> Result<Vec<Packet>, PacketError> {
+        if flags & FLAG_COMPRESSED == 0 {
+            return Packet::try_from_batch(bytes.to_vec());
+        }
+        let decompressor = if uses_previous_dictionary(bytes) {
+            &mut self.previous_decompressor
+        } else {
+            &mut self.decompressor
+        };
+        let batch = decompressor.decompress(bytes, MAX_PACKET_SIZE)
+            .map_err(PacketError::Decompress)?;
+        Packet::try_from_batch(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+    use crate::def::Vector3;
+    use crate::events_server::ServerEvent;
+    use crate::session::{Handshake, Role};
+    use crate::PacketSerialize;
+
+    fn addr() -> SocketAddr {
+        "10.0.0.1:5000".parse().unwrap()
+    }
+
+    /// Small MTU and no compression, so a few KB is enough to need fragments
+    fn encoder() -> DatagramEncoder {
+        let config = NetConfig { mtu: MIN_MTU, compress_threshold: u16::MAX, ..NetConfig::default() };
+        DatagramEncoder::new(&config, Sessions::new())
+    }
+
+    fn decoder() -> DatagramDecoder {
+        DatagramDecoder::new(&NetConfig::default(), FRAGMENT_TIMEOUT, Sessions::new())
+    }
+
+    /// A fragment with a chunk of len bytes, as a peer on any MTU could send it
+    fn fragment(id: u16, index: u8, count: u8, len: usize) -> Vec<u8> {
+        let mut datagram = vec![FLAG_FRAGMENT];
+        datagram.extend_from_slice(&id.to_le_bytes());
+        datagram.extend_from_slice(&[index, count]);
+        datagram.resize(FRAGMENT_HEADER_LEN + len, 0);
+        datagram
+    }
+
+    fn disconnect(reason_len: usize) -> Packet {
+        let reason = (0..reason_len).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        ServerEvent::Disconnect { client_index: 1, reason }.to_packet()
+    }
+
+    fn reason_len(packets: &[Packet]) -> usize {
+        let [pk] = packets else {
+            panic!("expected one packet, got {}", packets.len());
+        };
+        let ServerEvent::Disconnect { reason, .. } = ServerEvent::from_packet(pk).unwrap() else {
+            panic!("expected disconnect");
+        };
+        reason.len()
+    }
+
+    #[test]
+    fn small_batch_fits_in_one_datagram() {
+        let datagrams = encoder().encode(addr(), &[disconnect(10), disconnect(20)]).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0][0], 0);
+        assert_eq!(decoder().accept(addr(), &datagrams[0]).unwrap().len(), 2);
+    }
+
+    /// A tick of movement for a few players, large and repetitive enough to compress
+    fn moves() -> Vec<Packet> {
+        (0..8).map(|client_index| ServerEvent::Move {
+            client_index,
+            position: Vector3::new(client_index as f32, 10.0, -5.0),
+            angles: Vector3::new(0.0, 1.5, 0.0),
+            velocity: Vector3::new(1.0, 0.0, 0.0),
+        }.to_packet()).collect()
+    }
+
+    #[test]
+    fn large_batch_is_compressed() {
+        let encoder = DatagramEncoder::new(&NetConfig::default(), Sessions::new());
+        let packets = moves();
+        let datagrams = encoder.encode(addr(), &packets).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert!(datagrams[0][0] & FLAG_COMPRESSED != 0);
+        assert!(datagrams[0].len() < Packet::concat_batch(&packets).len());
+        let received = decoder().accept(addr(), &datagrams[0]).unwrap();
+        assert_eq!(received.len(), packets.len());
+        assert!(received.iter().zip(&packets).all(|(received, sent)| received.checksum() == sent.checksum()));
+    }
+
+    #[test]
+    fn batch_under_threshold_is_not_compressed() {
+        let encoder = DatagramEncoder::new(&NetConfig::default(), Sessions::new());
+        let packets = [ServerEvent::Ack { seq_number: 1 }.to_packet()];
+        assert!(Packet::concat_batch(&packets).len() < DEFAULT_COMPRESS_THRESHOLD as usize);
+        let datagrams = encoder.encode(addr(), &packets).unwrap();
+        assert_eq!(datagrams[0][0] & FLAG_COMPRESSED, 0);
+        assert_eq!(decoder().accept(addr(), &datagrams[0]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn previous_version_gets_previous_dictionary() {
+        let encoder = DatagramEncoder::new(&NetConfig::default(), Sessions::new());
+        encoder.versions().set(addr(), MIN_PROTOCOL_VERSION);
+        let datagrams = encoder.encode(addr(), &moves()).unwrap();
+        assert!(datagrams[0][0] & FLAG_COMPRESSED != 0);
+        assert!(uses_previous_dictionary(&datagrams[0][FLAGS_LEN..]));
+        let received = decoder().accept(addr(), &datagrams[0]).unwrap();
+        assert_eq!(received.len(), 8);
+        assert!(received.iter().all(|pk| pk.version() == MIN_PROTOCOL_VERSION));
+
+        let datagrams = encoder.encode("10.0.0.2:5000".parse().unwrap(), &moves()).unwrap();
+        assert!(!uses_previous_dictionary(&datagrams[0][FLAGS_LEN..]));
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_any_order() {
+        let datagrams = encoder().encode(addr(), &[disconnect(3000)]).unwrap();
+        assert!(datagrams.len() > 2);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= MIN_MTU as usize - SESSION_OVERHEAD));
+        assert!(datagrams.iter().all(|datagram| datagram[0] & FLAG_FRAGMENT != 0));
+
+        let mut decoder = decoder();
+        let (first, rest) = datagrams.split_first().unwrap();
+        for datagram in rest.iter().rev() {
+            assert!(decoder.accept(addr(), datagram).unwrap().is_empty());
+        }
+        assert_eq!(decoder.pending_count(), 1);
+        assert_eq!(reason_len(&decoder.accept(addr(), first).unwrap()), 3000);
+        assert_eq!(decoder.pending_count(), 0);
+    }
+
+    #[test]
+    fn duplicate_fragments_are_ignored() {
+        let datagrams = encoder().encode(addr(), &[disconnect(3000)]).unwrap();
+        let mut decoder = decoder();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest.iter().chain(rest) {
+            assert!(decoder.accept(addr(), datagram).unwrap().is_empty());
+        }
+        assert_eq!(reason_len(&decoder.accept(addr(), last).unwrap()), 3000);
+        // A late copy starts a new packet that never completes, rather than decoding it twice
+        assert!(decoder.accept(addr(), last).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fragments_from_other_peers_are_kept_apart() {
+        let datagrams = encoder().encode(addr(), &[disconnect(3000)]).unwrap();
+        let other = "10.0.0.2:5000".parse().unwrap();
+        let mut decoder = decoder();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert!(decoder.accept(addr(), datagram).unwrap().is_empty());
+        }
+        assert!(decoder.accept(other, last).unwrap().is_empty());
+        assert_eq!(decoder.pending_count(), 2);
+    }
+
+    #[test]
+    fn incomplete_packets_expire() {
+        let datagrams = encoder().encode(addr(), &[disconnect(3000)]).unwrap();
+        let mut decoder = DatagramDecoder::new(&NetConfig::default(), Duration::from_millis(50), Sessions::new());
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert!(decoder.accept(addr(), datagram).unwrap().is_empty());
+        }
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(decoder.expire(), 1);
+        // What arrives after that is the start of a new packet
+        assert!(decoder.accept(addr(), last).unwrap().is_empty());
+        assert_eq!(decoder.pending_count(), 1);
+    }
+
+    #[test]
+    fn oldest_incomplete_packet_of_a_peer_is_evicted() {
+        let encoder = encoder();
+        let packets: Vec<Vec<Vec<u8>>> = (0..=MAX_PENDING_FRAGMENTS_PER_PEER)
+            .map(|_| encoder.encode(addr(), &[disconnect(1000)]).unwrap())
+            .collect();
+        let mut decoder = decoder();
+        for datagrams in &packets {
+            assert!(decoder.accept(addr(), &datagrams[0]).unwrap().is_empty());
+        }
+        assert_eq!(decoder.pending_count(), MAX_PENDING_FRAGMENTS_PER_PEER);
+
+        let newest = packets.last().unwrap();
+        let received: Vec<Packet> = newest[1..].iter()
+            .flat_map(|datagram| decoder.accept(addr(), datagram).unwrap())
+            .collect();
+        assert_eq!(reason_len(&received), 1000);
+        // The first one was dropped to make room, its other fragments start over
+        let oldest = &packets[0];
+        for datagram in &oldest[1..] {
+            assert!(decoder.accept(addr(), datagram).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn other_peers_never_evict_incomplete_packets() {
+        let datagrams = encoder().encode(addr(), &[disconnect(1000)]).unwrap();
+        let mut decoder = decoder();
+        assert!(decoder.accept(addr(), &datagrams[0]).unwrap().is_empty());
+        let flood = |i: usize| SocketAddr::from(([10, 1, (i / 256) as u8, (i % 256) as u8], 5000));
+        for i in 1..MAX_PENDING_FRAGMENTS {
+            assert!(decoder.accept(flood(i), &fragment(0, 0, 2, 1)).unwrap().is_empty());
+        }
+        assert!(matches!(decoder.accept(flood(MAX_PENDING_FRAGMENTS), &fragment(0, 0, 2, 1)), Err(PacketError::TooManyFragments)));
+        assert_eq!(decoder.pending_count(), MAX_PENDING_FRAGMENTS);
+
+        let received: Vec<Packet> = datagrams[1..].iter()
+            .flat_map(|datagram| decoder.accept(addr(), datagram).unwrap())
+            .collect();
+        assert_eq!(reason_len(&received), 1000);
+    }
+
+    #[test]
+    fn pending_bytes_are_capped() {
+        let mut decoder = decoder();
+        let max_chunk = DEFAULT_MTU as usize - FRAGMENT_HEADER_LEN;
+        let mut peer = 0;
+        let mut accepted = 0;
+        // Every peer fills its share of packets with full chunks, none of them ever complete
+        let result = 'flood: loop {
+            peer += 1;
+            let from = SocketAddr::from(([10, 1, 0, peer], 5000));
+            for id in 0..MAX_PENDING_FRAGMENTS_PER_PEER as u16 {
+                for index in 0..u8::MAX - 1 {
+                    match decoder.accept(from, &fragment(id, index, u8::MAX, max_chunk)) {
+                        Ok(_) => accepted += max_chunk,
+                        Err(e) => break 'flood e,
+                    }
+                }
+            }
+        };
+        assert!(matches!(result, PacketError::TooManyFragments));
+        assert!(accepted <= MAX_PENDING_BYTES);
+        assert!(accepted + max_chunk > MAX_PENDING_BYTES);
+        assert_eq!(decoder.expire(), 0);
+    }
+
+    #[test]
+    fn chunks_longer_than_the_mtu_allows_are_rejected() {
+        let mut decoder = decoder();
+        let max_chunk = DEFAULT_MTU as usize - FRAGMENT_HEADER_LEN;
+        assert!(decoder.accept(addr(), &fragment(0, 0, 2, max_chunk)).unwrap().is_empty());
+        assert!(matches!(
+            decoder.accept(addr(), &fragment(1, 0, 2, max_chunk + 1)),
+            Err(PacketError::TooLarge { len, max }) if len == max_chunk + 1 && max == max_chunk
+        ));
+        assert!(matches!(
+            decoder.accept(addr(), &fragment(2, 0, 2, MAX_DATAGRAM_SIZE - FRAGMENT_HEADER_LEN)),
+            Err(PacketError::TooLarge { .. })
+        ));
+        assert_eq!(decoder.pending_count(), 1);
+    }
+
+    #[test]
+    fn malformed_fragment_headers_are_rejected() {
+        let datagrams = encoder().encode(addr(), &[disconnect(3000)]).unwrap();
+        let mut decoder = decoder();
+
+        let mut out_of_range = datagrams[0].clone();
+        out_of_range[3] = out_of_range[4];
+        assert!(matches!(decoder.accept(addr(), &out_of_range), Err(PacketError::BadFragment { .. })));
+
+        assert!(decoder.accept(addr(), &datagrams[0]).unwrap().is_empty());
+        let mut wrong_count = datagrams[1].clone();
+        wrong_count[4] += 1;
+        assert!(matches!(decoder.accept(addr(), &wrong_count), Err(PacketError::BadFragment { .. })));
+
+        let header_only = &datagrams[1][..FRAGMENT_HEADER_LEN];
+        assert!(matches!(decoder.accept(addr(), header_only), Err(PacketError::TooShort { .. })));
+        assert!(matches!(decoder.accept(addr(), &[]), Err(PacketError::TooShort { .. })));
+    }
+
+    #[test]
+    fn version_info_is_ignored_once_there_is_a_session() {
+        let mut decoder = decoder();
+        assert!(matches!(decoder.accept(addr(), &version_info_datagram()), Err(PacketError::VersionRejected { .. })));
+
+        let (client, server) = (Handshake::new(), Handshake::new());
+        decoder.sessions.insert(addr(), client.finish(Role::Client, server.public_key()));
+        // Anyone could have sent it, the server already took our Login
+        let err = decoder.accept(addr(), &version_info_datagram()).err().unwrap();
+        assert!(matches!(err, PacketError::Unencrypted), "{}", err);
+        assert!(err.is_rejected());
+    }
+
+    #[test]
+    fn oversized_packets_are_not_sent() {
+        let encoder = encoder();
+        // Would wrap the header's u16 length
+        assert!(matches!(
+            encoder.encode(addr(), &[disconnect(70_000)]),
+            Err(PacketError::TooLarge { max: MAX_PACKET_SIZE, .. })
+        ));
+        // Fits a packet, but not in 255 fragments of this MTU
+        let len = encoder.max_packet_size() + 1;
+        assert!(matches!(encoder.encode(addr(), &[disconnect(len)]), Err(PacketError::TooLarge { .. })));
+    }
+}
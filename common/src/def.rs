@@ -3,11 +3,18 @@ use std::fmt::{Debug, Formatter};
 use std::ops::{Add, AddAssign};
 use log::{debug, trace};
 use crate::{PacketSerialize};
-use crate::buffer::BitBuffer;
+use crate::buffer::{BitBuffer, FixedRange};
 use crate::packet::{Packet, PacketBuilder};
 
 pub const MAX_PLAYERS: usize = 32;
 
+/// Range positions are quantized to when sent over the network (~0.5mm precision)
+pub const POSITION_RANGE: FixedRange = FixedRange::new(-2048.0, 2048.0, 22);
+/// Range velocities are quantized to when sent over the network
+pub const VELOCITY_RANGE: FixedRange = FixedRange::new(-128.0, 128.0, 16);
+/// Bits used per axis for angles sent over the network
+pub const ANGLE_BITS: u8 = 16;
+
 #[derive( Clone, Copy)]
 pub struct Vector3 {
     pub x: f32,
@@ -33,6 +40,19 @@ impl Vector3 {
     pub fn to_vec(&self) -> Vec<f32> {
         vec![self.x, self.y, self.z]
     }
+
+    pub fn length(&self) -> f32 {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    /// Returns the vector scaled to a length of 1, or zero vector if length is 0
+    pub fn normalize(&self) -> Vector3 {
+        let len = self.length();
+        if len == 0.0 {
+            return Vector3::zero();
+        }
+        Vector3::new(self.x / len, self.y / len, self.z / len)
+    }
 }
 
 pub fn get_direction_vector(direction: &Vector3, ang: &Vector3) -> Vector3 {
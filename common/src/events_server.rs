@@ -1,146 +1,93 @@
+use std::fmt::{Display, Formatter};
 use int_enum::IntEnum;
-use log::trace;
 use crate::def::Vector3;
-use crate::packet::{Packet, PacketBuilder};
 use crate::PacketSerialize;
+use crate::network::Cookie;
+use crate::session::PublicKey;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PacketSerialize)]
 pub enum ServerEvent {
-    Login { client_index: u32, auth_id: u32 }, // 0x1
-    Move { client_index: u32, position: Vector3, angles: Vector3, velocity: Vector3 }, // 0x2
-    PlayerSpawn { client_index: u32, name: String, position: Vector3, angles: Vector3 }, //0x3,
-    Disconnect { client_index: u32, reason: String },
-    CommandResult { id: u32, result: bool }
-}
-impl ServerEvent {
-    pub fn get_packet_type(&self) -> u8 {
-        match self {
-            ServerEvent::Login { .. } => 0x1,
-            ServerEvent::Move { .. } => 0x2,
-            ServerEvent::PlayerSpawn { .. } => 0x3,
-            ServerEvent::Disconnect { .. } => 0x4,
-            ServerEvent::CommandResult { .. } => 0x5,
-        }
+    /// Only carries the header ACK for the client's reliable events
+    #[packet(id = 0x0)]
+    Ack { seq_number: u16 },
+    #[packet(id = 0x1, channel = ReliableOrdered)]
+    Login {
+        #[packet(varint)] client_index: u32,
+        auth_id: u32,
+        /// Set if the server requires an encrypted session
+        public_key: Option<PublicKey>,
+        /// Protocol versions the server accepts
+        min_version: u16,
+        max_version: u16
+    },
+    #[packet(id = 0x2, channel = UnreliableSequenced)]
+    Move {
+        #[packet(varint)] client_index: u32,
+        #[packet(with = crate::codec::position)] position: Vector3,
+        #[packet(with = crate::codec::angles)] angles: Vector3,
+        #[packet(with = crate::codec::velocity)] velocity: Vector3
+    },
+    #[packet(id = 0x3, channel = ReliableOrdered)]
+    PlayerSpawn {
+        #[packet(varint)] client_index: u32,
+        name: String,
+        position: Vector3,
+        angles: Vector3
+    },
+    #[packet(id = 0x4, channel = ReliableOrdered)]
+    Disconnect {
+        #[packet(varint)] client_index: u32,
+        reason: String
+    },
+    #[packet(id = 0x5, channel = ReliableUnordered)]
+    CommandResult {
+        #[packet(varint)] id: u32,
+        result: bool
+    },
+    /// Reply to a Login without a valid cookie, the client has to log in again with it
+    #[packet(id = 0x6)]
+    Challenge {
+        cookie: Cookie
+    },
+    /// Reply to a Login that was refused, no player was set up
+    #[packet(id = 0x7, channel = ReliableUnordered)]
+    LoginRejected {
+        reason: LoginRejectReason
+    },
+    /// Round trip time the server measured to the client, sent every second
+    #[packet(id = 0x8)]
+    Ping {
+        rtt_ms: u16
     }
 }
-impl PacketSerialize for ServerEvent {
-    // Serializing to send to client
-    fn to_packet_builder(&self) -> PacketBuilder {
-        let mut pk = PacketBuilder::new(self.get_packet_type());
-        match self {
-            ServerEvent::Login { client_index, auth_id } => {
-                let buf = pk.buf_mut();
-                buf.write_u32(*client_index);
-                buf.write_u32(*auth_id);
-            },
-            ServerEvent::Move { client_index, position, angles, velocity } => {
-                let buf = pk.buf_mut();
-                buf.write_u32(*client_index);
-                buf.write_f32(position.x);
-                buf.write_f32(position.y);
-                buf.write_f32(position.z);
-                buf.write_f32(angles.x);
-                buf.write_f32(angles.y);
-                buf.write_f32(angles.z);
-                buf.write_f32(velocity.x);
-                buf.write_f32(velocity.y);
-                buf.write_f32(velocity.z);
-            }
-            ServerEvent::PlayerSpawn { client_index, name, position, angles } => {
-                let buf = pk.buf_mut();
-                buf.write_u32(*client_index);
-                buf.write_f32(position.x);
-                buf.write_f32(position.y);
-                buf.write_f32(position.z);
-                buf.write_f32(angles.x);
-                buf.write_f32(angles.y);
-                buf.write_f32(angles.z);
-                buf.write_string(name);
-            },
-            ServerEvent::Disconnect { client_index, reason } => {
-                let buf = pk.buf_mut();
-                buf.write_u32(*client_index);
-                buf.write_string(reason)
-            },
-            ServerEvent::CommandResult { id, result } => {
-                let buf = pk.buf_mut();
-                buf.write_u32(*id);
-                buf.write_u8(result.then(|| 1).unwrap_or(0));
-            }
-        }
-        pk
-    }
 
-    // For client parsing
-    fn from_packet(mut packet: &Packet) -> Result<Self, String> {
-        let len = packet.payload_len();
-        let pk_type = packet.packet_type();
-        let mut buf = packet.payload_buf();
-        match pk_type {
-            0x1 => {
-                trace!("reading 0x1: Server Connect");
-                Ok(ServerEvent::Login {
-                    client_index: buf.read_u32(),
-                    auth_id: buf.read_u32(),
-                })
-            },
-            0x2 => {
-                trace!("reading 0x2: Server Move");
-                Ok(ServerEvent::Move {
-                    client_index: buf.read_u32(),
-                    position: Vector3::new(
-                        buf.read_f32(),
-                        buf.read_f32(),
-                        buf.read_f32()
-                    ),
-                    angles: Vector3::new(
-                        buf.read_f32(),
-                        buf.read_f32(),
-                        buf.read_f32()
-                    ),
-                    velocity: Vector3::new(
-                        buf.read_f32(),
-                        buf.read_f32(),
-                        buf.read_f32()
-                    ),
-                })
-            },
-            0x3 => {
-                trace!("reading 0x3: Server Player Spawn");
-                Ok(ServerEvent::PlayerSpawn {
-                    client_index: buf.read_u32(),
-                    position: Vector3::new(
-                        buf.read_f32(),
-                        buf.read_f32(),
-                        buf.read_f32()
-                    ),
-                    angles: Vector3::new(
-                        buf.read_f32(),
-                        buf.read_f32(),
-                        buf.read_f32()
-                    ),
-                    name: buf.read_string().unwrap(),
+/// Number of bits needed to send every [LoginRejectReason], raise along with the highest variant
+pub const LOGIN_REJECT_REASON_BITS: u8 = 3;
+
+/// Why the server refused a login
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntEnum)]
+pub enum LoginRejectReason {
+    /// No free player slots (or only reserved ones)
+    ServerFull = 0,
+    /// Client is on a protocol version the server doesn't support
+    BadVersion = 1,
+    /// Client's address is banned
+    Banned = 2,
+    /// Another player already has the name
+    NameTaken = 3,
+    /// Server requires an encrypted session, but the client didn't offer a key
+    EncryptionRequired = 4,
+}
 
-                })
-            },
-            0x4 => {
-                trace!("reading 0x4: Server Disconnect");
-                Ok(ServerEvent::Disconnect {
-                    client_index: buf.read_u32(),
-                    reason: buf.read_string().unwrap()
-                })
-            },
-            0x5 => {
-                trace!("reading 0x5: Server CommandResult");
-                Ok(ServerEvent::CommandResult {
-                    id: buf.read_u32(),
-                    result: buf.read_u8() != 0,
-                })
-            }
-            _ => {
-                // println!("{:?}", packet.buf());
-                Err(format!("Invalid packet type ({}). packet len={}", pk_type, len))
-            }
+impl Display for LoginRejectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginRejectReason::ServerFull => write!(f, "Server is full"),
+            LoginRejectReason::BadVersion => write!(f, "Server is on a different protocol version"),
+            LoginRejectReason::Banned => write!(f, "You are banned from this server"),
+            LoginRejectReason::NameTaken => write!(f, "Name is already taken"),
+            LoginRejectReason::EncryptionRequired => write!(f, "Server requires encryption"),
         }
     }
-}
\ No newline at end of file
+}
@@ -43,6 +43,9 @@ bitflags! {
     }
 }
 
+/// Number of bits needed to send all [Action] flags
+pub const ACTION_BITS: u8 = (u32::BITS - Action::all().bits().leading_zeros()) as u8;
+
 
 impl PlayerData {
     pub fn new(client_id: u32, name: String, position: Vector3, angles: Vector3) -> Self{
@@ -114,7 +117,7 @@ impl CommonGameInstance {
     }
 
     fn _check_player_id(&self, client_index: u32) {
-        assert!(client_index <= self.players.len() as u32, "client index out of bounds");
+        assert!(client_index < self.players.len() as u32, "client index out of bounds");
     }
 
     pub fn get_empty_slot(&self) -> Option<u32> {
@@ -1,27 +1,51 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use simple_moving_average::{NoSumSMA, SMA};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use crate::buffer::DecodeError;
 use crate::packet::{Packet, PacketBuilder};
+use crate::network::{Channel, LinkStats};
+
+// Lets code generated by mp-game-test-derive refer to this crate by name from inside it
+extern crate self as mp_game_test_common;
 
 pub mod def;
-mod buffer;
+pub mod buffer;
+pub mod codec;
 pub mod packet;
+pub mod datagram;
 pub mod events_client;
 pub mod events_server;
 pub mod game;
 pub mod network;
+pub mod compat;
+pub mod session;
+pub mod netsim;
 
-pub const PACKET_PROTOCOL_VERSION: u32 = 0;
-/// How long to wait until we consider packet was lost and resend?
+pub const PACKET_PROTOCOL_VERSION: u16 = 16;
+/// Oldest protocol version still accepted, packets to and from versions before ours are translated by [compat]
+pub const MIN_PROTOCOL_VERSION: u16 = PACKET_PROTOCOL_VERSION - 2;
+/// How long to wait until we consider packet was lost and resend, until a round trip has been measured.
+/// After that it adapts to the peer, see [network::RttEstimator]
 pub static ACK_TIMEOUT_REPLY: Duration = Duration::from_millis(50);
 
 #[derive(Clone)]
 pub struct NetStat {
-    packet_count: Arc<NetContainer<AtomicU16>>,
+    packet_count: Arc<NetContainer<AtomicU32>>,
+    /// Total bytes of datagrams sent and received, including rejected ones
+    byte_count: Arc<NetContainer<AtomicU64>>,
+    /// Traffic with each tracked peer, until it's forgotten
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerStat>>>,
+    /// Total number of unreliable packets not sent as they didn't fit in the peer's per-tick budget
+    over_budget_count: Arc<AtomicU32>,
+    /// Total number of received packets that were dropped for being malformed
+    dropped_count: Arc<AtomicU32>,
+    /// Total number of received datagrams rejected by integrity checks (magic, checksum)
+    rejected_count: Arc<AtomicU32>,
     activity_time: Arc<Mutex<NetContainer<Option<Instant>>>>,
     ping_time: Arc<Mutex<NoSumSMA<u16, u16, 10>>>
 }
@@ -30,6 +54,7 @@ pub enum NetDirection {
     In,
     Out
 }
+#[derive(Debug, Default, Clone, Copy)]
 pub struct NetContainer<T> {
     pub tx: T,
     pub rx: T
@@ -40,10 +65,23 @@ impl<T> NetContainer<T> {
     }
 }
 
+/// Traffic with one peer, see [NetStat::peer_stat]
+#[derive(Debug, Default, Clone)]
+pub struct PeerStat {
+    /// Bytes of datagrams sent to and received from the peer
+    pub bytes: NetContainer<u64>,
+    pub link: LinkStats,
+}
+
 impl NetStat {
     pub fn new() -> Self {
         Self {
-            packet_count: Arc::new(NetContainer::new(AtomicU16::new(0), AtomicU16::new(0))),
+            packet_count: Arc::new(NetContainer::new(AtomicU32::new(0), AtomicU32::new(0))),
+            byte_count: Arc::new(NetContainer::new(AtomicU64::new(0), AtomicU64::new(0))),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            over_budget_count: Arc::new(AtomicU32::new(0)),
+            dropped_count: Arc::new(AtomicU32::new(0)),
+            rejected_count: Arc::new(AtomicU32::new(0)),
             activity_time: Arc::new(Mutex::new(NetContainer::new(None, None))),
             ping_time: Arc::new(Mutex::new(NoSumSMA::new())),
         }
@@ -83,10 +121,10 @@ impl NetStat {
     }
     pub fn reset_pk_count(&mut self) {
         self.packet_count.tx.store(0, Ordering::Relaxed);
-        self.packet_count.tx.store(0, Ordering::Relaxed);
+        self.packet_count.rx.store(0, Ordering::Relaxed);
     }
 
-    pub fn pk_count(&self) -> NetContainer<u16> {
+    pub fn pk_count(&self) -> NetContainer<u32> {
         let (pk_in, pk_out) = (
             self.packet_count.tx.load(Ordering::Relaxed),
             self.packet_count.rx.load(Ordering::Relaxed)
@@ -105,6 +143,99 @@ impl NetStat {
         }
     }
 
+    /// Starts keeping stats for the peer, until it's forgotten. Traffic with peers that aren't tracked only counts towards the totals,
+    /// so datagrams from random (or spoofed) addresses can't grow the stats
+    pub fn track_peer(&self, addr: SocketAddr) {
+        self.peers.lock().unwrap().entry(addr).or_default();
+    }
+
+    pub fn is_tracked(&self, addr: SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(&addr)
+    }
+
+    /// Records a datagram sent to or received from the peer
+    pub fn add_bytes(&self, dir: NetDirection, addr: SocketAddr, bytes: usize) {
+        let bytes = bytes as u64;
+        let mut lock = self.peers.lock().unwrap();
+        let peer = lock.get_mut(&addr);
+        match dir {
+            NetDirection::In => {
+                self.byte_count.rx.fetch_add(bytes, Ordering::Relaxed);
+                if let Some(peer) = peer {
+                    peer.bytes.rx += bytes;
+                }
+            },
+            NetDirection::Out => {
+                self.byte_count.tx.fetch_add(bytes, Ordering::Relaxed);
+                if let Some(peer) = peer {
+                    peer.bytes.tx += bytes;
+                }
+            }
+        }
+    }
+
+    /// Records a packet from the peer on the UnreliableSequenced channel, see [LinkStats::record_sequenced]
+    pub fn record_sequenced(&self, addr: SocketAddr, channel_seq: u16) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&addr) {
+            peer.link.record_sequenced(channel_seq, Instant::now());
+        }
+    }
+
+    /// Records a reliable packet from the peer, see [LinkStats::record_reliable]
+    pub fn record_reliable(&self, addr: SocketAddr, seq: u16, new: bool) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&addr) {
+            peer.link.record_reliable(seq, new);
+        }
+    }
+
+    /// Records reliable packets sent to the peer, see [LinkStats::record_sent]
+    pub fn record_sent(&self, addr: SocketAddr, sent: usize, resent: usize) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&addr) {
+            peer.link.record_sent(sent, resent);
+        }
+    }
+
+    pub fn byte_count(&self) -> NetContainer<u64> {
+        NetContainer::new(self.byte_count.tx.load(Ordering::Relaxed), self.byte_count.rx.load(Ordering::Relaxed))
+    }
+
+    /// Traffic with the peer so far, all zero if it isn't tracked
+    pub fn peer_stat(&self, addr: SocketAddr) -> PeerStat {
+        self.peers.lock().unwrap().get(&addr).cloned().unwrap_or_default()
+    }
+
+    /// Stops tracking the peer, once it's gone
+    pub fn forget_peer(&self, addr: SocketAddr) {
+        self.peers.lock().unwrap().remove(&addr);
+    }
+
+    /// Records unreliable packets that were dropped instead of going over a peer's per-tick budget
+    pub fn add_over_budget(&self, count: usize) {
+        self.over_budget_count.fetch_add(count as u32, Ordering::Relaxed);
+    }
+
+    pub fn over_budget_count(&self) -> u32 {
+        self.over_budget_count.load(Ordering::Relaxed)
+    }
+
+    /// Records a received packet that was dropped because it could not be decoded
+    pub fn inc_dropped(&mut self) {
+        self.dropped_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_count(&self) -> u32 {
+        self.dropped_count.load(Ordering::Relaxed)
+    }
+
+    /// Records a received datagram that failed integrity checks (not our protocol or corrupted)
+    pub fn inc_rejected(&mut self) {
+        self.rejected_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected_count(&self) -> u32 {
+        self.rejected_count.load(Ordering::Relaxed)
+    }
+
     pub fn add_ping(&mut self, ping: u16) {
         let mut lock = self.ping_time.lock().unwrap();
         lock.add_sample(ping);
@@ -116,6 +247,14 @@ impl NetStat {
     }
 }
 
+pub use mp_game_test_derive::PacketSerialize;
+
+/// Used by code generated by `#[derive(PacketSerialize)]`, so crates deriving it don't need these dependencies themselves
+#[doc(hidden)]
+pub mod __private {
+    pub use log;
+}
+
 pub trait PacketSerialize {
     fn to_packet(&self) -> Packet {
         self.to_packet_builder().finalize()
@@ -123,7 +262,10 @@ pub trait PacketSerialize {
 
     fn to_packet_builder(&self) -> PacketBuilder;
 
-    fn from_packet(bytes: &Packet) -> Result<Self, String> where Self: Sized;
+    /// How the event is delivered
+    fn channel(&self) -> Channel;
+
+    fn from_packet(bytes: &Packet) -> Result<Self, DecodeError> where Self: Sized;
 }
 
 // pub enum ClientId {
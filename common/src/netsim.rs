@@ -0,0 +1,292 @@
+//! Simulated network conditions, for testing on localhost where nothing is ever lost.
+//!
+//! [SimSocket] wraps the [Transport] of the server or client and applies its [LinkConditions] to every datagram sent
+//! through it: some are dropped or sent twice, the rest are held back for the latency (give or take the jitter)
+//! by a delivery thread. Only what we send is affected, so set it on both ends to degrade both directions.
+//! The conditions are all off by default, datagrams then go straight to the socket.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, trace};
+use rand::random;
+use crate::network::Transport;
+
+/// Extra delay of a reordered datagram, so the ones sent after it get there first
+pub const REORDER_DELAY: Duration = Duration::from_millis(30);
+
+/// Conditions of the simulated link. Chances are in percent
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LinkConditions {
+    /// Added to every datagram
+    pub latency: Duration,
+    /// The latency varies by up to this much either way
+    pub jitter: Duration,
+    /// Chance a datagram is dropped
+    pub loss: f32,
+    /// Chance a datagram is sent twice
+    pub duplicate: f32,
+    /// Chance a datagram is held back an extra [REORDER_DELAY]
+    pub reorder: f32,
+}
+
+impl LinkConditions {
+    /// Whether datagrams go out untouched
+    pub fn is_off(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = self.jitter.mul_f32(random::<f32>() * 2.0);
+        let mut delay = (self.latency + jitter).saturating_sub(self.jitter);
+        if chance(self.reorder) {
+            delay += REORDER_DELAY;
+        }
+        delay
+    }
+}
+
+impl Display for LinkConditions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_off() {
+            return write!(f, "off");
+        }
+        write!(f, "latency {} ms ±{} ms, loss {}%, duplicate {}%, reorder {}%",
+            self.latency.as_millis(), self.jitter.as_millis(), self.loss, self.duplicate, self.reorder)
+    }
+}
+
+fn chance(percent: f32) -> bool {
+    percent > 0.0 && random::<f32>() * 100.0 < percent
+}
+
+/// Parses a chance in percent, for the command line and server commands
+pub fn parse_percent(s: &str) -> Result<f32, String> {
+    let percent: f32 = s.trim_end_matches('%').parse().map_err(|_| format!("\"{}\" is not a number", s))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("{} is not between 0 and 100", percent));
+    }
+    Ok(percent)
+}
+
+/// A datagram waiting for its simulated delay, ordered by when it's due and then by when it was sent
+type Delayed = Reverse<(Instant, u64, Vec<u8>, SocketAddr)>;
+
+/// A [Transport] that sends through the simulated link, see the [module docs](self).
+/// Clones share the socket and conditions, one goes to every network thread
+#[derive(Clone)]
+pub struct SimSocket {
+    transport: Arc<dyn Transport>,
+    conditions: Arc<Mutex<LinkConditions>>,
+    delayed: Sender<(Instant, Vec<u8>, SocketAddr)>,
+}
+
+impl SimSocket {
+    /// Wraps the transport, starting a delivery thread for delayed datagrams that ends with the last clone
+    pub fn new(transport: impl Transport + 'static, conditions: LinkConditions) -> Self {
+        if !conditions.is_off() {
+            debug!("[netsim] simulating {}", conditions);
+        }
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let (tx, rx) = channel();
+        {
+            let transport = transport.clone();
+            thread::spawn(move || delivery_thread(transport, rx));
+        }
+        Self { transport, conditions: Arc::new(Mutex::new(conditions)), delayed: tx }
+    }
+
+    pub fn conditions(&self) -> LinkConditions {
+        self.conditions.lock().unwrap().clone()
+    }
+
+    /// Changes the conditions for everything sent from now on
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        debug!("[netsim] simulating {}", conditions);
+        *self.conditions.lock().unwrap() = conditions;
+    }
+
+}
+
+impl Transport for SimSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let conditions = self.conditions();
+        if conditions.is_off() {
+            return self.transport.send_to(buf, addr);
+        }
+        if chance(conditions.loss) {
+            trace!("[netsim] dropping {} bytes", buf.len());
+            return Ok(buf.len());
+        }
+        let copies = if chance(conditions.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let delay = conditions.delay();
+            if delay.is_zero() {
+                self.transport.send_to(buf, addr)?;
+            } else if self.delayed.send((Instant::now() + delay, buf.to_vec(), addr)).is_err() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "netsim delivery thread has exited"));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.transport.recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
+
+fn delivery_thread(transport: Arc<dyn Transport>, rx: Receiver<(Instant, Vec<u8>, SocketAddr)>) {
+    let mut queue: BinaryHeap<Delayed> = BinaryHeap::new();
+    let mut sent_order = 0u64;
+    loop {
+        let next = match queue.peek() {
+            Some(Reverse((due, ..))) => rx.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok((due, buf, addr)) => {
+                sent_order += 1;
+                queue.push(Reverse((due, sent_order, buf, addr)));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let now = Instant::now();
+        while queue.peek().is_some_and(|Reverse((due, ..))| *due <= now) {
+            let Reverse((_, _, buf, addr)) = queue.pop().unwrap();
+            transport.send_to(&buf, addr).ok();
+        }
+    }
+    trace!("[netsim] delivery thread: EXITED");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events_server::ServerEvent;
+    use crate::network::{Endpoint, MemoryNetwork, MemoryTransport, NetConfig, Network};
+    use crate::network::endpoint::tests::Peer;
+
+    /// A simulated link and the endpoint at the other end of it
+    struct Link {
+        socket: SimSocket,
+        receiver: MemoryTransport,
+    }
+
+    impl Link {
+        fn new(conditions: LinkConditions) -> Self {
+            let (socket, receiver) = MemoryTransport::pair();
+            receiver.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            Self { socket: SimSocket::new(socket, conditions), receiver }
+        }
+
+        fn send(&self, buf: &[u8]) {
+            self.socket.send_to(buf, self.receiver.local_addr().unwrap()).unwrap();
+        }
+
+        fn recv_all(&self) -> Vec<u8> {
+            let mut received = Vec::new();
+            let mut buf = [0; 16];
+            while let Ok((n, _)) = self.receiver.recv_from(&mut buf) {
+                received.extend_from_slice(&buf[..n]);
+            }
+            received
+        }
+    }
+
+    #[test]
+    fn off_sends_right_away() {
+        let link = Link::new(LinkConditions::default());
+        link.send(&[1]);
+        link.send(&[2]);
+        assert_eq!(link.recv_all(), vec![1, 2]);
+    }
+
+    #[test]
+    fn latency_holds_datagrams_back() {
+        let link = Link::new(LinkConditions { latency: Duration::from_millis(50), ..Default::default() });
+        let start = Instant::now();
+        link.send(&[1]);
+        assert_eq!(link.receiver.recv_from(&mut [0; 16]).unwrap().0, 1);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn loss_and_duplication() {
+        let link = Link::new(LinkConditions { loss: 100.0, ..Default::default() });
+        link.send(&[1]);
+        assert!(link.recv_all().is_empty());
+
+        link.socket.set_conditions(LinkConditions { duplicate: 100.0, latency: Duration::from_millis(1), ..Default::default() });
+        link.send(&[2]);
+        assert_eq!(link.recv_all(), vec![2, 2]);
+    }
+
+    #[test]
+    fn reordered_datagrams_arrive_late() {
+        let link = Link::new(LinkConditions { reorder: 100.0, ..Default::default() });
+        link.send(&[1]);
+        link.socket.set_conditions(LinkConditions::default());
+        link.send(&[2]);
+        assert_eq!(link.recv_all(), vec![2, 1]);
+    }
+
+    #[test]
+    fn reliable_ordered_events_arrive_once_and_in_order() {
+        const EVENTS: u32 = 200;
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            loss: 10.0,
+            duplicate: 5.0,
+            reorder: 10.0,
+        };
+        // Both directions are bad, so acks get lost too
+        let config = NetConfig { link: conditions, max_send_attempts: 20, ..NetConfig::default() };
+        let network = MemoryNetwork::new();
+        let a = Endpoint::new(network.bind("10.0.0.1:1".parse().unwrap()).unwrap(), &config, Peer);
+        let mut b = Endpoint::new(network.bind("10.0.0.2:1".parse().unwrap()).unwrap(), &config, Peer);
+        a.track_peer(b.local_addr());
+        b.track_peer(a.local_addr());
+        for client_index in 0..EVENTS {
+            a.send_to(&ServerEvent::Disconnect { client_index, reason: String::new() }, b.local_addr()).unwrap();
+            // A few per tick, so they are spread over many datagrams
+            if client_index % 5 == 4 {
+                a.flush().unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert!(a.wait_for_acks(b.local_addr(), Duration::from_secs(10)), "not everything was acked");
+        assert!(a.take_unresponsive().is_empty());
+        assert!(a.resent_count(b.local_addr()) > 0, "nothing was lost, the link wasn't exercised");
+
+        let mut received = Vec::new();
+        while let Some((_, event, _)) = b.next_event() {
+            let ServerEvent::Disconnect { client_index, .. } = event else {
+                panic!("unexpected {:?}", event);
+            };
+            received.push(client_index);
+        }
+        assert_eq!(received, (0..EVENTS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn percent_is_checked() {
+        assert_eq!(parse_percent("2.5%"), Ok(2.5));
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("lots").is_err());
+    }
+}
@@ -0,0 +1,738 @@
+//! The networking shared by the server and client, see [Endpoint].
+//!
+//! An endpoint runs three threads over its [Transport]: one receiving (decoding, acking and putting events back in order),
+//! one sending (batching everything queued in a tick) and one resending reliable packets that weren't acked in time.
+//! The server and client only differ in their [EndpointEvents]: which events go each way,
+//! and what happens to the few that are handled before the game sees them.
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, error, trace, warn};
+use crate::{NetContainer, NetDirection, NetStat, PacketSerialize};
+use crate::datagram::{DatagramDecoder, DatagramEncoder, FRAGMENT_TIMEOUT, MAX_DATAGRAM_SIZE};
+use crate::netsim::{LinkConditions, SimSocket};
+use crate::packet::{Packet, PacketError};
+use crate::session::Sessions;
+use super::{AckWindow, Channel, ChannelReceiver, NetConfig, ReceivedSequences, ReliableQueue, RttEstimator, Transport, RETRANSMIT_INTERVAL};
+
+/// How often the receive thread drops the ordering and ack state of peers that were forgotten
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The side-specific part of an [Endpoint]. Lives on the receive thread
+pub trait EndpointEvents: Send + 'static {
+    /// Events we send
+    type Out: PacketSerialize + Debug;
+    /// Events the other side sends us
+    type In: PacketSerialize + Send + 'static;
+
+    /// The event sent just to carry an ACK in its header
+    fn ack(seq_number: u16) -> Self::Out;
+
+    /// Whether datagrams from the address are taken at all
+    fn accepts(&self, _addr: SocketAddr) -> bool {
+        true
+    }
+
+    /// Whether the packet is really from the peer at the address, checked before its acks or sequence number are used.
+    /// By default, peers given an auth id with [Network::set_peer_auth_id] have to send it. Packets that fail are dropped
+    fn authenticates(&self, link: &Link, addr: SocketAddr, pk: &Packet, _event: &Self::In) -> bool {
+        link.peer_auth_id(addr).is_none_or(|auth_id| auth_id == pk.auth_id())
+    }
+
+    /// Sees every event received, before it's put back in order for the game
+    fn received(&mut self, link: &mut Link, addr: SocketAddr, pk: &Packet, event: Self::In) -> Received<Self::In>;
+
+    /// Sees every datagram that couldn't be read, once it's been counted
+    fn rejected(&mut self, _link: &mut Link, _addr: SocketAddr, _err: &PacketError) {}
+}
+
+/// What to do with a received event, see [EndpointEvents::received]
+pub enum Received<EV> {
+    /// Queued for the game once it's in order
+    Queue(EV),
+    /// The first event of a new connection from the address, ordering and acking start over from it
+    Restart(EV),
+    /// Not for the game, nothing more to do
+    Handled,
+}
+
+/// What every thread of an endpoint shares: the socket, how to encode for it and what to count
+#[derive(Clone)]
+pub struct Link {
+    socket: SimSocket,
+    encoder: DatagramEncoder,
+    net_stat: NetStat,
+    /// Put on everything we send. Only clients have one, given by the server in its Login
+    auth_id: Arc<AtomicU32>,
+    /// Auth ids peers have to put on what they send us. Only the server has any, one per logged in client
+    peer_auth_ids: Arc<Mutex<HashMap<SocketAddr, u32>>>,
+    last_error: Arc<Mutex<Option<String>>>,
+}
+
+impl Link {
+    pub fn encoder(&self) -> &DatagramEncoder {
+        &self.encoder
+    }
+
+    pub fn stat(&self) -> &NetStat {
+        &self.net_stat
+    }
+
+    pub fn stat_mut(&mut self) -> &mut NetStat {
+        &mut self.net_stat
+    }
+
+    pub fn auth_id(&self) -> u32 {
+        self.auth_id.load(Ordering::Relaxed)
+    }
+
+    pub fn set_auth_id(&self, auth_id: u32) {
+        self.auth_id.store(auth_id, Ordering::Relaxed);
+    }
+
+    /// Auth id the peer has to send, see [Network::set_peer_auth_id]
+    pub fn peer_auth_id(&self, addr: SocketAddr) -> Option<u32> {
+        self.peer_auth_ids.lock().unwrap().get(&addr).copied()
+    }
+
+    /// Sets the error shown by [Network::last_error]
+    pub fn set_error(&self, error: impl Into<String>) {
+        *self.last_error.lock().unwrap() = Some(error.into());
+    }
+
+    /// Sends a datagram as is
+    pub fn send_datagram(&self, addr: SocketAddr, datagram: &[u8]) {
+        match self.socket.send_to(datagram, addr) {
+            Ok(_) => self.net_stat.add_bytes(NetDirection::Out, addr, datagram.len()),
+            Err(e) => warn!("[net] could not send {}B to {}: {}", datagram.len(), addr, e)
+        }
+    }
+
+    /// Encodes the packets as one batch for the address and sends it right away
+    pub fn send_now(&self, addr: SocketAddr, packets: &[Packet]) -> Result<(), PacketError> {
+        for datagram in self.encoder.encode(addr, packets)? {
+            self.send_datagram(addr, &datagram);
+        }
+        Ok(())
+    }
+}
+
+enum OutPacket {
+    Single(Packet, SocketAddr),
+    /// Sent right away in its own plain datagram, so any version can read it. Only for Login, see [crate::compat]
+    Plain(Packet, SocketAddr),
+    /// Sends everything queued so far, one batch per address. Sent at the end of every tick
+    Flush,
+    /// Drops the encrypted session, protocol version, auth id and stats of the address, once what's queued for it has been flushed
+    Forget(SocketAddr),
+}
+
+type EventQueue<EV> = Arc<Mutex<VecDeque<(Packet, EV, SocketAddr)>>>;
+
+/// A server's or client's end of the network, sending `E::Out` events and receiving `E::In` ones.
+/// Used through [Network]. Its threads are stopped when it's dropped
+pub struct Endpoint<E: EndpointEvents> {
+    link: Link,
+    event_queue: EventQueue<E::In>,
+    reliable_queue: Arc<Mutex<ReliableQueue>>,
+    /// Peers that stopped acking reliable packets
+    unresponsive: Arc<Mutex<Vec<SocketAddr>>>,
+    transmit_out_tx: Option<Sender<OutPacket>>,
+    recv_end_signal: Option<Sender<()>>,
+    retransmit_end_signal: Option<Sender<()>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl<E: EndpointEvents> Endpoint<E> {
+    pub fn new(transport: impl Transport + 'static, config: &NetConfig, events: E) -> Self {
+        let link = Link {
+            socket: SimSocket::new(transport, config.link.clone()),
+            encoder: DatagramEncoder::new(config, Sessions::new()),
+            net_stat: NetStat::new(),
+            auth_id: Arc::new(AtomicU32::new(0)),
+            peer_auth_ids: Arc::new(Mutex::new(HashMap::new())),
+            last_error: Arc::new(Mutex::new(None)),
+        };
+        let (tx, rx) = channel::<OutPacket>();
+        let event_queue = Arc::new(Mutex::new(VecDeque::new()));
+        let reliable_queue = Arc::new(Mutex::new(ReliableQueue::new()));
+        let unresponsive = Arc::new(Mutex::new(Vec::new()));
+        let end_signal = channel::<()>();
+        let retransmit_end_signal = channel::<()>();
+
+        let send_thread = {
+            let link = link.clone();
+            let tick_budget = config.tick_budget as usize;
+            thread::spawn(move || network_send_thread(link, rx, tick_budget))
+        };
+        let recv_thread = {
+            let link = link.clone();
+            let event_queue = event_queue.clone();
+            let reliable_queue = reliable_queue.clone();
+            let decoder = DatagramDecoder::new(config, FRAGMENT_TIMEOUT, link.encoder.sessions().clone());
+            thread::spawn(move || network_recv_thread(end_signal.1, link, decoder, events, event_queue, reliable_queue))
+        };
+        let retransmit_thread = {
+            let link = link.clone();
+            let reliable_queue = reliable_queue.clone();
+            let unresponsive = unresponsive.clone();
+            let max_attempts = config.max_send_attempts;
+            thread::spawn(move || network_retransmit_thread(retransmit_end_signal.1, link, reliable_queue, max_attempts, unresponsive))
+        };
+
+        Self {
+            link,
+            event_queue,
+            reliable_queue,
+            unresponsive,
+            transmit_out_tx: Some(tx),
+            recv_end_signal: Some(end_signal.0),
+            retransmit_end_signal: Some(retransmit_end_signal.0),
+            threads: vec![send_thread, recv_thread, retransmit_thread],
+        }
+    }
+
+    pub fn link(&self) -> &Link {
+        &self.link
+    }
+
+    fn queue(&self, out: OutPacket) -> Result<(), String> {
+        let tx = self.transmit_out_tx.as_ref().ok_or("shutdown in progress".to_string())?;
+        tx.send(out).map_err(|e| e.to_string())
+    }
+}
+
+impl<E: EndpointEvents> Drop for Endpoint<E> {
+    fn drop(&mut self) {
+        // Drop senders, which the threads will then end after noticing its dropped
+        self.transmit_out_tx = None;
+        self.recv_end_signal = None;
+        self.retransmit_end_signal = None;
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("[net] a network thread panicked");
+            }
+        }
+    }
+}
+
+/// The networking of the server and client, implemented once by their [Endpoint]
+pub trait Network {
+    type Events: EndpointEvents;
+
+    fn endpoint(&self) -> &Endpoint<Self::Events>;
+
+    fn endpoint_mut(&mut self) -> &mut Endpoint<Self::Events>;
+
+    fn stat(&self) -> &NetStat {
+        &self.endpoint().link.net_stat
+    }
+
+    /// Returns the number of packets sent and received since last called.
+    /// Resets the count
+    fn pks_per_interval(&mut self) -> NetContainer<u32> {
+        let net_stat = &mut self.endpoint_mut().link.net_stat;
+        let pk_stat = net_stat.pk_count();
+        net_stat.reset_pk_count();
+        pk_stat
+    }
+
+    /// Sends an event to the address, on the event's [Channel].
+    /// Reliable events wait for room if too many are waiting on an ack, and are resent until acked, see [Network::take_unresponsive]
+    fn send_to(&self, event: &<Self::Events as EndpointEvents>::Out, addr: SocketAddr) -> Result<(), String> {
+        let endpoint = self.endpoint();
+        let auth_id = endpoint.link.auth_id();
+        let mut lock = endpoint.reliable_queue.lock().unwrap();
+        if !event.channel().is_reliable() {
+            let pk = lock.unreliable_packet_as(addr, event, auth_id);
+            debug!("EVENT[{}B] {:?} {:?}", pk.buf_len(), addr, event);
+            return endpoint.queue(OutPacket::Single(pk, addr));
+        }
+        let entry = lock.add_event_as(addr, event, auth_id);
+        if entry.sent_time.is_none() {
+            debug!("EVENT seq#{} {:?} {:?} held, reliable window is full", entry.seq_id, addr, event);
+            return Ok(());
+        }
+        debug!("EVENT[{}B] seq#{} {:?} {:?}", entry.packet.buf_len(), entry.seq_id, addr, event);
+        endpoint.link.net_stat.record_sent(addr, 1, 0);
+        endpoint.queue(OutPacket::Single(entry.packet, addr))
+    }
+
+    /// Sends the packet right away, on its own, so the peer can tell our version even if it doesn't support it.
+    /// Only for the client's Login
+    fn send_plain(&self, packet: Packet, addr: SocketAddr) -> Result<(), String> {
+        self.endpoint().queue(OutPacket::Plain(packet, addr))
+    }
+
+    /// Sends all events queued since the last flush
+    fn flush(&self) -> Result<(), String> {
+        self.endpoint().queue(OutPacket::Flush)
+    }
+
+    /// Only packets carrying the auth id are taken from the address from now on, until it's forgotten.
+    /// Anyone could send from its address, this keeps them from acking or using up its sequence numbers
+    fn set_peer_auth_id(&self, addr: SocketAddr, auth_id: u32) {
+        self.endpoint().link.peer_auth_ids.lock().unwrap().insert(addr, auth_id);
+    }
+
+    /// Keeps stats and reliable delivery for the peer, until it's forgotten. Reliable packets from peers that aren't
+    /// tracked are dropped unacked and their events are handed over as they arrive, so random addresses can't grow any per-peer state
+    fn track_peer(&self, addr: SocketAddr) {
+        self.endpoint().link.net_stat.track_peer(addr);
+    }
+
+    /// Forgets the address' encrypted session, protocol version, auth id and stats, after the events already queued for it are sent.
+    /// Reliable events it hasn't acked yet are not resent
+    fn forget_peer(&self, addr: SocketAddr) -> Result<(), String> {
+        self.endpoint().reliable_queue.lock().unwrap().delete_all(addr);
+        self.endpoint().queue(OutPacket::Forget(addr))
+    }
+
+    /// Takes the peers that were given up on since last called, after not acking a reliable packet
+    /// within [NetConfig::max_send_attempts]. Nothing more is resent to them
+    fn take_unresponsive(&self) -> Vec<SocketAddr> {
+        std::mem::take(&mut *self.endpoint().unresponsive.lock().unwrap())
+    }
+
+    /// Whether the peer has reliable events it hasn't acked yet, that are still being resent
+    fn has_unacked(&self, addr: SocketAddr) -> bool {
+        self.endpoint().reliable_queue.lock().unwrap().count(addr).unwrap_or(0) > 0
+    }
+
+    /// Waits until the peer acked every reliable event sent to it, or the timeout passes. Returns whether it did
+    fn wait_for_acks(&self, addr: SocketAddr, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.has_unacked(addr) {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            thread::sleep(RETRANSMIT_INTERVAL);
+        }
+        true
+    }
+
+    /// Round trip time estimate of the peer, from how long it takes to ack reliable packets
+    fn rtt(&self, addr: SocketAddr) -> Option<RttEstimator> {
+        self.endpoint().reliable_queue.lock().unwrap().rtt(addr)
+    }
+
+    /// Number of reliable packets resent to the peer after not being acked in time
+    fn resent_count(&self, addr: SocketAddr) -> u32 {
+        self.endpoint().reliable_queue.lock().unwrap().resent_count(addr)
+    }
+
+    /// Simulated conditions on everything we send
+    fn link_conditions(&self) -> LinkConditions {
+        self.endpoint().link.socket.conditions()
+    }
+
+    fn set_link_conditions(&self, conditions: LinkConditions) {
+        self.endpoint().link.socket.set_conditions(conditions);
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.endpoint().link.socket.local_addr().unwrap()
+    }
+
+    /// The last thing that went wrong on the network threads, if anything did
+    fn last_error(&self) -> Option<String> {
+        self.endpoint().link.last_error.lock().unwrap().clone()
+    }
+
+    fn clear_last_error(&self) {
+        *self.endpoint().link.last_error.lock().unwrap() = None;
+    }
+
+    fn event_queue_len(&self) -> usize {
+        self.endpoint().event_queue.lock().unwrap().len()
+    }
+
+    /// Pops the next event off, if any
+    fn next_event(&mut self) -> Option<(Packet, <Self::Events as EndpointEvents>::In, SocketAddr)> {
+        self.endpoint_mut().event_queue.lock().unwrap().pop_front()
+    }
+
+    /// Stops the network threads, after sending what's been flushed
+    fn end(self) where Self: Sized {
+        drop(self);
+    }
+}
+
+impl<E: EndpointEvents> Network for Endpoint<E> {
+    type Events = E;
+
+    fn endpoint(&self) -> &Endpoint<E> {
+        self
+    }
+
+    fn endpoint_mut(&mut self) -> &mut Endpoint<E> {
+        self
+    }
+}
+
+fn network_recv_thread<E: EndpointEvents>(
+    end_signal: Receiver<()>,
+    mut link: Link,
+    mut decoder: DatagramDecoder,
+    mut events: E,
+    event_queue: EventQueue<E::In>,
+    reliable_queue: Arc<Mutex<ReliableQueue>>,
+) {
+    let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    // Puts each peer's events back in order per channel, reset when it starts a new connection
+    let mut receivers: HashMap<SocketAddr, ChannelReceiver<(Packet, E::In)>> = HashMap::new();
+    // Reliable sequence numbers received from each peer, acked back and used to drop resent duplicates
+    let mut received = ReceivedSequences::default();
+    // We need a time out so we can check end_signal
+    // Otherwise, we cannot shut down (so then we can't stop the program), until we received some data
+    link.socket.set_read_timeout(Some(Duration::from_secs(1))).expect("set_read_timeout failed");
+    let mut last_purge = Instant::now();
+    while end_signal.try_recv() != Err(TryRecvError::Disconnected) { // Check if we are good
+        if last_purge.elapsed() >= PURGE_INTERVAL {
+            // Drop what's left of forgotten peers
+            receivers.retain(|addr, _| link.net_stat.is_tracked(*addr));
+            received.retain(|addr| link.net_stat.is_tracked(addr));
+            last_purge = Instant::now();
+        }
+        buf.resize(MAX_DATAGRAM_SIZE, 0);
+        let (n, addr) = match link.socket.recv_from(&mut buf) {
+            Ok((_, addr)) if !events.accepts(addr) => {
+                trace!("[net] ignoring datagram from {}", addr);
+                continue;
+            }
+            Ok(recv) => recv,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    error!("[net] recv error: {}", e);
+                    link.set_error(e.to_string());
+                }
+                continue;
+            }
+        };
+        buf.truncate(n);
+        link.net_stat.mark_activity(NetDirection::In);
+        link.net_stat.add_bytes(NetDirection::In, addr, n);
+        if n == 0 {
+            continue;
+        }
+        let packets = match decoder.accept(addr, buf.as_slice()) {
+            Ok(packets) => packets, // empty if waiting on more fragments
+            Err(e) => {
+                warn!("[net] dropping bad packet from {}: {}", addr, e);
+                if e.is_rejected() {
+                    link.net_stat.inc_rejected();
+                } else {
+                    link.net_stat.inc_dropped();
+                }
+                events.rejected(&mut link, addr, &e);
+                continue;
+            }
+        };
+        let mut needs_ack = false;
+        for pk in packets {
+            trace!("[net] IN n={} {}", n, pk.as_hex_str());
+            link.net_stat.inc_pk_count(NetDirection::In);
+            let event = match E::In::from_packet(&pk) {
+                Ok(event) => event,
+                Err(err) => {
+                    warn!("[net] dropping malformed packet (type={}) from {}: {}", pk.packet_type(), addr, err);
+                    link.net_stat.inc_dropped();
+                    continue;
+                }
+            };
+            if !events.authenticates(&link, addr, &pk, &event) {
+                warn!("[net] dropping packet (type={}) from {} with the wrong auth id {}", pk.packet_type(), addr, pk.auth_id());
+                link.net_stat.inc_rejected();
+                continue;
+            }
+            // Any packet can carry ACKs in its header
+            let ack = pk.ack();
+            if ack > 0 {
+                trace!("[net] got ACK {:?} bits={:032b} from {}", ack, pk.ack_bits(), addr);
+                let acked = reliable_queue.lock().unwrap().accept_acks(addr, ack, pk.ack_bits());
+                if let Some(rtt) = acked.rtt {
+                    link.net_stat.add_ping(rtt.as_millis().min(u16::MAX as u128) as u16);
+                }
+            }
+            // Duplicates are acked again, our last ack may have been lost
+            let seq_num = pk.sequence_number();
+            let tracked = link.net_stat.is_tracked(addr);
+            if seq_num > 0 {
+                if !tracked {
+                    debug!("[net] dropping reliable seq#{} from untracked {}", seq_num, addr);
+                    link.net_stat.inc_dropped();
+                    continue;
+                }
+                needs_ack = true;
+                let new = received.record(addr, seq_num);
+                link.net_stat.record_reliable(addr, seq_num, new);
+                if !new {
+                    trace!("[net] dropping duplicate seq#{} from {}", seq_num, addr);
+                    continue;
+                }
+            }
+            let event = match events.received(&mut link, addr, &pk, event) {
+                Received::Queue(event) => event,
+                Received::Restart(event) => {
+                    receivers.remove(&addr);
+                    received.remove(addr);
+                    event
+                }
+                Received::Handled => continue,
+            };
+            let (channel, channel_seq) = (event.channel(), pk.channel_seq());
+            if channel == Channel::UnreliableSequenced {
+                link.net_stat.record_sequenced(addr, channel_seq);
+            }
+            let ready = if tracked {
+                receivers.entry(addr).or_default().receive(channel, channel_seq, (pk, event))
+            } else {
+                vec![(pk, event)]
+            };
+            if !ready.is_empty() {
+                let mut lock = event_queue.lock().unwrap();
+                lock.extend(ready.into_iter().map(|(pk, event)| (pk, event, addr)));
+            }
+        }
+        // One ACK covers every reliable packet in the datagram, and the 32 before the newest
+        if let Some(window) = received.window(addr).filter(|_| needs_ack) {
+            send_ack::<E>(&link, addr, window);
+        }
+    }
+    debug!("[net] recv thread: EXITED");
+}
+
+/// Acks the peer's reliable packets right away, rather than waiting on the next tick
+fn send_ack<E: EndpointEvents>(link: &Link, addr: SocketAddr, window: &AckWindow) {
+    trace!("[net] sending ACK seq#{} bits={:032b} to {}", window.ack(), window.ack_bits(), addr);
+    let pk = E::ack(window.ack()).to_packet_builder()
+        .with_auth_id(link.auth_id())
+        .with_ack(window.ack())
+        .with_ack_bits(window.ack_bits())
+        .finalize();
+    if let Err(e) = link.send_now(addr, std::slice::from_ref(&pk)) {
+        error!("[net] could not send ACK to {}: {}", addr, e);
+    }
+}
+
+/// Resends reliable packets that weren't acked in time, to every peer, on its own timer
+fn network_retransmit_thread(
+    end_signal: Receiver<()>,
+    link: Link,
+    reliable_queue: Arc<Mutex<ReliableQueue>>,
+    max_attempts: u8,
+    unresponsive: Arc<Mutex<Vec<SocketAddr>>>,
+) {
+    while end_signal.recv_timeout(RETRANSMIT_INTERVAL) == Err(RecvTimeoutError::Timeout) {
+        let mut resends = Vec::new();
+        {
+            let mut lock = reliable_queue.lock().unwrap();
+            for addr in lock.peers() {
+                let resent = lock.resent_count(addr);
+                match lock.due(addr, max_attempts) {
+                    Ok(due) if due.is_empty() => {}
+                    Ok(due) => {
+                        // Held ones go out for the first time
+                        let resent = (lock.resent_count(addr) - resent) as usize;
+                        link.net_stat.record_sent(addr, due.len() - resent, resent);
+                        resends.push((addr, due));
+                    },
+                    Err(seq) => {
+                        warn!("[net] {} did not ack seq#{} after {} attempts, giving up", addr, seq, max_attempts);
+                        lock.delete_all(addr);
+                        unresponsive.lock().unwrap().push(addr);
+                    }
+                }
+            }
+        }
+        for (addr, due) in resends {
+            if let Err(e) = link.send_now(addr, &due) {
+                error!("[net] could not resend {} packets to {}: {}", due.len(), addr, e);
+            }
+        }
+    }
+    debug!("[net] retransmit thread: EXITED");
+}
+
+/// Keeps what fits in the tick's budget, reliable packets first as they'd only be resent.
+/// Unreliable packets are kept in order until one doesn't fit, the ones after it are dropped. Returns how many were dropped
+fn apply_tick_budget(packets: &mut Vec<Packet>, budget: usize) -> usize {
+    let mut used: usize = packets.iter()
+        .filter(|pk| pk.sequence_number() > 0)
+        .map(|pk| pk.buf_len() as usize)
+        .sum();
+    let before = packets.len();
+    let mut full = false;
+    packets.retain(|pk| {
+        if pk.sequence_number() > 0 {
+            return true;
+        }
+        full = full || used + pk.buf_len() as usize > budget;
+        if !full {
+            used += pk.buf_len() as usize;
+        }
+        !full
+    });
+    before - packets.len()
+}
+
+fn network_send_thread(mut link: Link, transmit_recv: Receiver<OutPacket>, tick_budget: usize) {
+    // Packets waiting for the end of the tick, to be sent together
+    let mut pending: HashMap<SocketAddr, Vec<Packet>> = HashMap::new();
+    // Peers to forget after the next flush
+    let mut forgotten: Vec<SocketAddr> = Vec::new();
+    // Unlike the recv thread this blocks, what's queued before shutting down (like a Disconnect) is still sent
+    while let Ok(out) = transmit_recv.recv() {
+        match out {
+            OutPacket::Single(pk, addr) => {
+                trace!("[net] OUT addr={} pk_len={} py_len={} {}", addr, pk.buf_len(), pk.payload_len(), pk.as_hex_str());
+                pending.entry(addr).or_default().push(pk);
+            }
+            OutPacket::Plain(pk, addr) => {
+                trace!("[net] OUT plain addr={} pk_len={} py_len={} {}", addr, pk.buf_len(), pk.payload_len(), pk.as_hex_str());
+                link.send_datagram(addr, &link.encoder.encode_plain(&pk));
+                link.net_stat.inc_pk_count(NetDirection::Out);
+                link.net_stat.mark_activity(NetDirection::Out);
+            }
+            OutPacket::Forget(addr) => {
+                forgotten.push(addr);
+            }
+            OutPacket::Flush => {
+                if pending.is_empty() && forgotten.is_empty() {
+                    continue;
+                }
+                link.net_stat.mark_activity(NetDirection::Out);
+                for (addr, mut packets) in pending.drain() {
+                    let over_budget = apply_tick_budget(&mut packets, tick_budget);
+                    if over_budget > 0 {
+                        debug!("[net] {} over its tick budget, dropping {} unreliable packets", addr, over_budget);
+                        link.net_stat.add_over_budget(over_budget);
+                    }
+                    if let Err(e) = link.send_now(addr, &packets) {
+                        error!("[net] could not send {} packets to {}: {}", packets.len(), addr, e);
+                        continue;
+                    }
+                    trace!("[net] OUT addr={} packets={}", addr, packets.len());
+                    for _ in 0..packets.len() {
+                        link.net_stat.inc_pk_count(NetDirection::Out);
+                    }
+                }
+                for addr in forgotten.drain(..) {
+                    trace!("[net] forgetting peer {}", addr);
+                    link.encoder.sessions().remove(addr);
+                    link.encoder.versions().remove(addr);
+                    link.peer_auth_ids.lock().unwrap().remove(&addr);
+                    link.net_stat.forget_peer(addr);
+                }
+            }
+        }
+    }
+    debug!("[net] send thread: EXITED");
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::def::Vector3;
+    use crate::events_server::ServerEvent;
+    use crate::network::MemoryNetwork;
+
+    /// Both ends send and receive server events, taking everything but acks
+    pub(crate) struct Peer;
+
+    impl EndpointEvents for Peer {
+        type Out = ServerEvent;
+        type In = ServerEvent;
+
+        fn ack(seq_number: u16) -> ServerEvent {
+            ServerEvent::Ack { seq_number }
+        }
+
+        fn received(&mut self, _link: &mut Link, _addr: SocketAddr, _pk: &Packet, event: ServerEvent) -> Received<ServerEvent> {
+            match event {
+                ServerEvent::Ack { .. } => Received::Handled,
+                _ => Received::Queue(event),
+            }
+        }
+    }
+
+    #[test]
+    fn reliable_events_are_delivered_and_acked() {
+        let network = MemoryNetwork::new();
+        let a = Endpoint::new(network.bind("10.0.0.1:1".parse().unwrap()).unwrap(), &NetConfig::default(), Peer);
+        let mut b = Endpoint::new(network.bind("10.0.0.2:1".parse().unwrap()).unwrap(), &NetConfig::default(), Peer);
+        a.track_peer(b.local_addr());
+        b.track_peer(a.local_addr());
+        a.send_to(&ServerEvent::CommandResult { id: 7, result: true }, b.local_addr()).unwrap();
+        a.flush().unwrap();
+        assert!(a.wait_for_acks(b.local_addr(), Duration::from_secs(1)), "not acked");
+        let (_, event, addr) = b.next_event().expect("nothing received");
+        assert!(matches!(event, ServerEvent::CommandResult { id: 7, .. }));
+        assert_eq!(addr, a.local_addr());
+        assert!(a.rtt(b.local_addr()).and_then(|rtt| rtt.srtt()).is_some());
+    }
+
+    #[test]
+    fn rejected_datagrams_are_counted() {
+        let network = MemoryNetwork::new();
+        let endpoint = Endpoint::new(network.bind("10.0.0.1:1".parse().unwrap()).unwrap(), &NetConfig::default(), Peer);
+        let sender = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+        let encoder = DatagramEncoder::new(&NetConfig::default(), Sessions::new());
+        let mut datagram = encoder.encode_plain(&ServerEvent::Ping { rtt_ms: 1 }.to_packet());
+        *datagram.last_mut().unwrap() ^= 0x1;
+        sender.send_to(&datagram, endpoint.local_addr()).unwrap();
+        sender.send_to(b"not a packet", endpoint.local_addr()).unwrap();
+        for _ in 0..20 {
+            if endpoint.stat().rejected_count() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(endpoint.stat().rejected_count(), 2);
+        assert_eq!(endpoint.stat().dropped_count(), 0);
+        assert_eq!(endpoint.event_queue_len(), 0);
+    }
+
+    #[test]
+    fn untracked_peers_get_no_per_peer_state() {
+        let network = MemoryNetwork::new();
+        let mut endpoint = Endpoint::new(network.bind("10.0.0.1:1".parse().unwrap()).unwrap(), &NetConfig::default(), Peer);
+        let sender = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+        let encoder = DatagramEncoder::new(&NetConfig::default(), Sessions::new());
+        let reliable = ServerEvent::CommandResult { id: 1, result: true }.to_packet_builder().with_sequence_number(1).finalize();
+        sender.send_to(&encoder.encode_plain(&reliable), endpoint.local_addr()).unwrap();
+        sender.send_to(&encoder.encode_plain(&ServerEvent::Ping { rtt_ms: 1 }.to_packet()), endpoint.local_addr()).unwrap();
+        let mut event = None;
+        for _ in 0..20 {
+            event = endpoint.next_event();
+            if event.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The unreliable event is still handed over, the reliable one is dropped without an ack
+        assert!(matches!(event, Some((_, ServerEvent::Ping { .. }, _))));
+        assert_eq!(endpoint.event_queue_len(), 0);
+        assert_eq!(endpoint.stat().dropped_count(), 1);
+        assert!(endpoint.stat().byte_count().rx > 0);
+        assert!(!endpoint.stat().is_tracked(sender.local_addr().unwrap()));
+        assert_eq!(endpoint.stat().peer_stat(sender.local_addr().unwrap()).bytes.rx, 0);
+    }
+
+    #[test]
+    fn unreliable_packets_over_budget_are_dropped() {
+        let moved = |x| ServerEvent::Move { client_index: 1, position: Vector3::new(x, 0.0, 0.0), angles: Vector3::zero(), velocity: Vector3::zero() }.to_packet();
+        let reliable = ServerEvent::CommandResult { id: 1, result: true }.to_packet_builder().with_sequence_number(1).finalize();
+        let mut packets = vec![moved(1.0), moved(2.0), reliable.clone(), moved(3.0)];
+        let budget = (reliable.buf_len() + moved(1.0).buf_len() * 2) as usize;
+        assert_eq!(apply_tick_budget(&mut packets, budget), 1);
+        let kept: Vec<_> = packets.iter().map(|pk| pk.sequence_number()).collect();
+        assert_eq!(kept, vec![0, 0, 1]);
+    }
+}
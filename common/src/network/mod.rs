@@ -0,0 +1,1092 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use log::trace;
+use crate::{PacketSerialize, ACK_TIMEOUT_REPLY};
+use crate::events_client::ClientEvent;
+use crate::events_server::ServerEvent;
+use crate::packet::{Packet, PacketBuilder};
+use std::path::PathBuf;
+use crate::datagram::{DEFAULT_COMPRESS_THRESHOLD, DEFAULT_MTU};
+use crate::netsim::LinkConditions;
+
+pub(crate) mod endpoint;
+pub use endpoint::{Endpoint, EndpointEvents, Link, Network, Received};
+
+/// Size of a login challenge [Cookie]
+pub const COOKIE_LEN: usize = 20;
+/// Issued by the server in reply to a Login, proving the client can receive at its address.
+/// Opaque to the client, which sends it back in its next Login
+pub type Cookie = [u8; COOKIE_LEN];
+
+/// Default [NetConfig::max_send_attempts]. With the timeout doubling on every resend that's about 5s before giving up
+pub const DEFAULT_MAX_SEND_ATTEMPTS: u8 = 10;
+/// How often reliable packets are checked for a resend, independent of any traffic
+pub const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(10);
+/// Default [NetConfig::tick_budget], about 120 KiB/s per client at 30 ticks
+pub const DEFAULT_TICK_BUDGET: u32 = 4096;
+
+/// Network settings, shared by the server and client
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    /// Largest datagram we send, bigger packets are split into fragments
+    pub mtu: u16,
+    /// Batches smaller than this many bytes are sent uncompressed
+    pub compress_threshold: u16,
+    /// If set, every sent batch is also written to this dir, for training the compression dictionary
+    pub capture_dir: Option<PathBuf>,
+    /// Server only - require clients to set up an encrypted session during login
+    pub secure: bool,
+    /// Reliable packets are sent at most this many times, then the peer is given up on
+    pub max_send_attempts: u8,
+    /// Most bytes sent to a peer in one tick. Unreliable packets past it are dropped, reliable ones always go
+    pub tick_budget: u32,
+    /// Simulated latency, loss and such on everything we send, off by default. See [crate::netsim]
+    pub link: LinkConditions,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            compress_threshold: DEFAULT_COMPRESS_THRESHOLD,
+            capture_dir: None,
+            secure: false,
+            max_send_attempts: DEFAULT_MAX_SEND_ATTEMPTS,
+            tick_budget: DEFAULT_TICK_BUDGET,
+            link: LinkConditions::default(),
+        }
+    }
+}
+
+/// How an event is delivered. Set per event variant with `#[packet(channel = ...)]`, see [PacketSerialize::channel].
+/// Every channel but Unreliable numbers its packets to each peer on its own, in [Packet::channel_seq]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Sent once, may be lost, duplicated or arrive out of order
+    Unreliable = 0,
+    /// Sent once, dropped if anything newer on the channel already arrived (e.g. Move)
+    UnreliableSequenced = 1,
+    /// Resent until acked, handed over as soon as it arrives
+    ReliableUnordered = 2,
+    /// Resent until acked, handed over in the order it was sent
+    ReliableOrdered = 3,
+}
+
+impl Channel {
+    const COUNT: usize = 4;
+
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Channel::ReliableUnordered | Channel::ReliableOrdered)
+    }
+}
+
+/// Last sequence number given out on each channel, to one peer
+#[derive(Debug, Default, Clone)]
+pub struct ChannelSequences {
+    seqs: [u16; Channel::COUNT],
+}
+
+impl ChannelSequences {
+    /// Starts the packet for an event, numbered in its channel if that's sequenced
+    pub fn builder_for(&mut self, event: &impl PacketSerialize) -> PacketBuilder {
+        let builder = event.to_packet_builder();
+        let channel = event.channel();
+        if channel == Channel::Unreliable {
+            return builder;
+        }
+        let seq = next_seq(self.seqs[channel as usize]);
+        self.seqs[channel as usize] = seq;
+        builder.with_channel_seq(seq)
+    }
+}
+
+/// Puts received events of a peer back in order per their [Channel]. Holds on to ordered events
+/// that arrived ahead of a missing one, and drops stale sequenced ones
+pub struct ChannelReceiver<T> {
+    /// Newest received on UnreliableSequenced, 0 if none yet
+    latest_sequenced: u16,
+    /// Next expected on ReliableOrdered
+    next_ordered: u16,
+    /// ReliableOrdered events that arrived early, by channel seq
+    held: HashMap<u16, T>,
+}
+
+impl<T> Default for ChannelReceiver<T> {
+    fn default() -> Self {
+        Self { latest_sequenced: 0, next_ordered: 1, held: HashMap::new() }
+    }
+}
+
+impl<T> ChannelReceiver<T> {
+    /// Takes a received event, returning what can be handed over now (in order).
+    /// A channel seq of 0 is from a peer without channels, handed over as is
+    pub fn receive(&mut self, channel: Channel, channel_seq: u16, event: T) -> Vec<T> {
+        if channel_seq == 0 {
+            return vec![event];
+        }
+        match channel {
+            Channel::Unreliable | Channel::ReliableUnordered => vec![event],
+            Channel::UnreliableSequenced => {
+                if self.latest_sequenced != 0 && !seq_is_newer(channel_seq, self.latest_sequenced) {
+                    trace!("dropping stale sequenced event #{} (newest #{})", channel_seq, self.latest_sequenced);
+                    return Vec::new();
+                }
+                self.latest_sequenced = channel_seq;
+                vec![event]
+            }
+            Channel::ReliableOrdered => {
+                if channel_seq != self.next_ordered {
+                    if seq_is_newer(channel_seq, self.next_ordered) {
+                        trace!("holding ordered event #{} until #{} arrives", channel_seq, self.next_ordered);
+                        self.held.entry(channel_seq).or_insert(event);
+                    }
+                    // otherwise it's a duplicate of one already handed over
+                    return Vec::new();
+                }
+                let mut ready = vec![event];
+                self.next_ordered = next_seq(self.next_ordered);
+                while let Some(event) = self.held.remove(&self.next_ordered) {
+                    ready.push(event);
+                    self.next_ordered = next_seq(self.next_ordered);
+                }
+                ready
+            }
+        }
+    }
+
+    /// Number of ordered events waiting on a missing one
+    pub fn held_count(&self) -> usize {
+        self.held.len()
+    }
+}
+
+/// How many reliable packets before [Packet::ack] an ack also covers, one bit each in [Packet::ack_bits]
+pub const ACK_BITS: u16 = 32;
+
+/// Reliable sequence numbers go from 1 to u16::MAX and then wrap back to 1, as 0 marks an unreliable packet.
+/// Returns how many sequence numbers `newer` comes after `older`
+pub fn seq_distance(newer: u16, older: u16) -> u16 {
+    debug_assert!(newer > 0 && older > 0, "0 is not a reliable sequence number");
+    let space = u16::MAX as u32;
+    ((newer as u32 + space - older as u32) % space) as u16
+}
+
+/// Whether `a` is more recent than `b`, assuming they are within half the sequence space of each other
+pub fn seq_is_newer(a: u16, b: u16) -> bool {
+    a != b && seq_distance(a, b) < u16::MAX / 2
+}
+
+fn next_seq(seq: u16) -> u16 {
+    // seq number has to be > 0 (wrap to 1 on overflow)
+    seq.checked_add(1).unwrap_or(1)
+}
+
+/// The reliable sequence numbers received from a peer, acked back with [AckWindow::ack] and [AckWindow::ack_bits]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AckWindow {
+    /// Newest sequence number received, 0 if none yet
+    latest: u16,
+    /// Bit n is set if `latest - 1 - n` has been received
+    bits: u32,
+}
+
+impl AckWindow {
+    /// Marks a sequence number as received, returning false if it already was (or is too old to tell)
+    pub fn record(&mut self, seq: u16) -> bool {
+        if seq == 0 {
+            return false;
+        }
+        if self.latest == 0 || seq_is_newer(seq, self.latest) {
+            if self.latest != 0 {
+                // The previous latest moves into the bits, along with everything before it
+                let shift = seq_distance(seq, self.latest) as u32;
+                self.bits = self.bits.checked_shl(shift).unwrap_or(0) | 1u32.checked_shl(shift - 1).unwrap_or(0);
+            }
+            self.latest = seq;
+            return true;
+        }
+        let age = seq_distance(self.latest, seq);
+        if age == 0 || age > ACK_BITS {
+            return false;
+        }
+        let bit = 1 << (age - 1);
+        if self.bits & bit != 0 {
+            return false;
+        }
+        self.bits |= bit;
+        true
+    }
+
+    pub fn ack(&self) -> u16 {
+        self.latest
+    }
+
+    pub fn ack_bits(&self) -> u32 {
+        self.bits
+    }
+}
+
+/// The [AckWindow] of every peer we receive reliable packets from. Resent packets we already have are
+/// acked again (our ack may have been lost) but not handed over twice
+#[derive(Debug, Default)]
+pub struct ReceivedSequences {
+    windows: HashMap<SocketAddr, AckWindow>,
+}
+
+impl ReceivedSequences {
+    /// Marks a reliable sequence number from the peer as received, returning false if it's a duplicate to drop
+    pub fn record(&mut self, addr: SocketAddr, seq: u16) -> bool {
+        self.windows.entry(addr).or_default().record(seq)
+    }
+
+    /// What to ack back to the peer, None if nothing reliable was received from it
+    pub fn window(&self, addr: SocketAddr) -> Option<&AckWindow> {
+        self.windows.get(&addr)
+    }
+
+    /// Starts over for the peer, when it logs in again
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.windows.remove(&addr);
+    }
+
+    /// Keeps only the peers `keep` returns true for
+    pub fn retain(&mut self, mut keep: impl FnMut(SocketAddr) -> bool) {
+        self.windows.retain(|addr, _| keep(*addr));
+    }
+}
+
+#[derive(Clone)]
+pub struct ReliableEntry {
+    pub seq_id: u16,
+    pub packet: Packet,
+    /// When last sent, None if waiting for room in the window
+    pub sent_time: Option<Instant>,
+    /// Times sent so far
+    pub attempts: u8,
+}
+
+/// Lower bound of [RttEstimator::rto], so a few fast acks don't make us resend on any hiccup
+pub const MIN_RTO: Duration = Duration::from_millis(20);
+/// Upper bound of [RttEstimator::rto], also after backing off
+pub const MAX_RTO: Duration = Duration::from_secs(1);
+
+/// Round trip time to a peer, smoothed over ack samples (Jacobson/Karels, as in RFC 6298)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RttEstimator {
+    /// Smoothed round trip time, None until the first sample
+    srtt: Option<Duration>,
+    /// How much samples vary from srtt
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// Smoothed round trip time, None if nothing was measured yet
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// How long to wait on an ack before resending, [ACK_TIMEOUT_REPLY] until a round trip was measured
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO),
+            None => ACK_TIMEOUT_REPLY,
+        }
+    }
+
+    /// The timeout of a packet sent this many times already, doubling on each resend
+    fn backoff_rto(&self, attempts: u8) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+        self.rto().saturating_mul(factor).min(MAX_RTO)
+    }
+}
+
+/// What an ack from a peer covered, see [ReliableQueue::accept_acks]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Acked {
+    /// Packets that are no longer waiting on an ack
+    pub count: usize,
+    /// Round trip of the acked packet, if it was only sent once (a resent one can't tell which send was acked)
+    pub rtt: Option<Duration>,
+}
+
+/// Gaps between sequenced packets longer than this don't count for jitter, the peer just had nothing to send
+const JITTER_MAX_GAP: Duration = Duration::from_secs(1);
+
+/// How well the link with a peer is doing: loss both ways, jitter, and packets arriving out of order or twice.
+/// Loss from the peer is taken from gaps in its UnreliableSequenced channel (never resent), loss to it from resends of reliable packets
+#[derive(Debug, Default, Clone)]
+pub struct LinkStats {
+    /// Newest received on UnreliableSequenced, 0 if none yet
+    latest_sequenced: u16,
+    /// Newest reliable sequence number received, 0 if none yet
+    latest_reliable: u16,
+    /// Sequenced packets the peer sent going by the newest one, and how many of those arrived
+    expected: u64,
+    received: u64,
+    last_arrival: Option<Instant>,
+    /// Time between the last two sequenced packets that arrived one after the other
+    last_gap: Option<Duration>,
+    jitter: Duration,
+    out_of_order: u32,
+    duplicates: u32,
+    /// Reliable packets sent to the peer, first sends and resends
+    sent: u64,
+    resent: u64,
+}
+
+impl LinkStats {
+    /// Records a packet on the UnreliableSequenced channel that arrived at `now`.
+    /// Jitter is how much the time between consecutive packets varies, smoothed like RFC 3550 does
+    pub fn record_sequenced(&mut self, channel_seq: u16, now: Instant) {
+        if channel_seq == 0 {
+            return;
+        }
+        if self.latest_sequenced != 0 && !seq_is_newer(channel_seq, self.latest_sequenced) {
+            if channel_seq == self.latest_sequenced {
+                self.duplicates += 1;
+            } else {
+                self.out_of_order += 1;
+                self.received = (self.received + 1).min(self.expected);
+            }
+            return;
+        }
+        let distance = if self.latest_sequenced == 0 { 1 } else { seq_distance(channel_seq, self.latest_sequenced) };
+        self.expected += distance as u64;
+        self.received += 1;
+        let gap = self.last_arrival.map(|last| now - last).filter(|gap| distance == 1 && *gap <= JITTER_MAX_GAP);
+        if let (Some(gap), Some(last_gap)) = (gap, self.last_gap) {
+            self.jitter = (self.jitter * 15 + gap.abs_diff(last_gap)) / 16;
+        }
+        self.last_gap = gap;
+        self.last_arrival = Some(now);
+        self.latest_sequenced = channel_seq;
+    }
+
+    /// Records a reliable packet from the peer, `new` being whether [AckWindow::record] took it
+    pub fn record_reliable(&mut self, seq: u16, new: bool) {
+        if !new {
+            self.duplicates += 1;
+            return;
+        }
+        if self.latest_reliable != 0 && !seq_is_newer(seq, self.latest_reliable) {
+            self.out_of_order += 1;
+            return;
+        }
+        self.latest_reliable = seq;
+    }
+
+    /// Records reliable packets sent to the peer, for the first time and again after not being acked
+    pub fn record_sent(&mut self, sent: usize, resent: usize) {
+        self.sent += sent as u64;
+        self.resent += resent as u64;
+    }
+
+    /// Percentage of the peer's packets that didn't arrive
+    pub fn loss_in(&self) -> f32 {
+        if self.expected == 0 {
+            return 0.0;
+        }
+        (self.expected - self.received) as f32 / self.expected as f32 * 100.0
+    }
+
+    /// Percentage of our reliable packets to the peer that went unacked and had to be resent
+    pub fn loss_out(&self) -> f32 {
+        let sends = self.sent + self.resent;
+        if sends == 0 {
+            return 0.0;
+        }
+        self.resent as f32 / sends as f32 * 100.0
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Packets that arrived after a newer one, on either channel
+    pub fn out_of_order(&self) -> u32 {
+        self.out_of_order
+    }
+
+    /// Packets that arrived again after already being received
+    pub fn duplicates(&self) -> u32 {
+        self.duplicates
+    }
+}
+
+/// Reliable packets sent to one peer, oldest first
+#[derive(Default)]
+struct PeerQueue {
+    /// Last sequence number given out, shared by the reliable channels as it's what acks refer to
+    seq_number: u16,
+    entries: VecDeque<ReliableEntry>,
+    channel_seqs: ChannelSequences,
+    rtt: RttEstimator,
+    /// Packets resent after timing out, a sign of loss
+    resent: u32,
+}
+
+impl PeerQueue {
+    /// Whether the packet can be sent without going past what an ack from the peer can cover.
+    /// Otherwise a lost older packet could be received and never acked, as it'd be behind the bits
+    fn in_window(&self, seq: u16) -> bool {
+        self.entries.front().is_none_or(|oldest| seq_distance(seq, oldest.seq_id) <= ACK_BITS)
+    }
+}
+
+/// Reliable packets waiting for an ack, per peer. Any of them can be acked and resent independently,
+/// but only [ACK_BITS] + 1 are in flight at once. Also numbers the peer's packets in their [Channel]
+pub struct ReliableQueue {
+    client_queue: HashMap<SocketAddr, PeerQueue>,
+}
+
+impl ReliableQueue {
+    pub fn new() -> Self {
+        Self { client_queue: HashMap::new() }
+    }
+
+    pub fn count(&self, addr: SocketAddr) -> Option<usize> {
+        self.client_queue.get(&addr).map(|queue| queue.entries.len())
+    }
+
+    pub fn delete_all(&mut self, addr: SocketAddr) {
+        self.client_queue.remove(&addr);
+    }
+
+    /// Queues an event for the peer, returning its entry. Only send it right away if it has a sent_time,
+    /// otherwise the window is full and it's returned by [ReliableQueue::due] once there's room
+    pub fn add_event(&mut self, addr: SocketAddr, event: &impl PacketSerialize) -> ReliableEntry {
+        self.add_event_as(addr, event, 0)
+    }
+
+    /// [ReliableQueue::add_event], for clients sending with the auth id the server gave them
+    pub fn add_event_as(&mut self, addr: SocketAddr, event: &impl PacketSerialize, auth_id: u32) -> ReliableEntry {
+        debug_assert!(event.channel().is_reliable(), "{:?} is not a reliable channel", event.channel());
+        let queue = self.client_queue.entry(addr).or_default();
+        let seq = next_seq(queue.seq_number);
+        queue.seq_number = seq;
+        let packet = queue.channel_seqs.builder_for(event)
+            .with_sequence_number(seq)
+            .with_auth_id(auth_id)
+            .finalize();
+        let sent_time = queue.in_window(seq).then(Instant::now);
+        let entry = ReliableEntry {
+            seq_id: seq,
+            packet,
+            sent_time,
+            attempts: sent_time.is_some() as u8,
+        };
+        queue.entries.push_back(entry.clone());
+        entry
+    }
+
+    /// Writes an unreliable event for the peer, numbered in its channel if that's sequenced
+    pub fn unreliable_packet(&mut self, addr: SocketAddr, event: &impl PacketSerialize) -> Packet {
+        self.unreliable_packet_as(addr, event, 0)
+    }
+
+    /// [ReliableQueue::unreliable_packet], for clients sending with the auth id the server gave them
+    pub fn unreliable_packet_as(&mut self, addr: SocketAddr, event: &impl PacketSerialize, auth_id: u32) -> Packet {
+        debug_assert!(!event.channel().is_reliable(), "{:?} is a reliable channel", event.channel());
+        self.client_queue.entry(addr).or_default().channel_seqs.builder_for(event)
+            .with_auth_id(auth_id)
+            .finalize()
+    }
+
+    /// Peers with reliable packets waiting on an ack
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.client_queue.iter()
+            .filter(|(_, queue)| !queue.entries.is_empty())
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Round trip time estimate of the peer, None if nothing was ever sent to it
+    pub fn rtt(&self, addr: SocketAddr) -> Option<RttEstimator> {
+        self.client_queue.get(&addr).map(|queue| queue.rtt)
+    }
+
+    /// Number of packets resent to the peer as they weren't acked in time, 0 if nothing was ever sent to it
+    pub fn resent_count(&self, addr: SocketAddr) -> u32 {
+        self.client_queue.get(&addr).map(|queue| queue.resent).unwrap_or(0)
+    }
+
+    /// Returns the packets to (re)send to the peer: those not acked within the peer's [RttEstimator::rto]
+    /// (doubled for every resend), and those that were waiting on the window. Marks them as sent now.
+    /// Errors with the sequence number of a packet that is due but was already sent max_attempts times
+    pub fn due(&mut self, addr: SocketAddr, max_attempts: u8) -> Result<Vec<Packet>, u16> {
+        let Some(queue) = self.client_queue.get_mut(&addr) else {
+            return Ok(Vec::new());
+        };
+        let Some(oldest) = queue.entries.front().map(|entry| entry.seq_id) else {
+            return Ok(Vec::new());
+        };
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for entry in queue.entries.iter_mut().take_while(|entry| seq_distance(entry.seq_id, oldest) <= ACK_BITS) {
+            match entry.sent_time {
+                Some(time) if now - time <= queue.rtt.backoff_rto(entry.attempts) => continue,
+                Some(_) if entry.attempts >= max_attempts => return Err(entry.seq_id),
+                Some(time) => {
+                    trace!("ACK timeout (seq#{}). resending (original pk {} ms ago)", entry.seq_id, (now - time).as_millis());
+                    queue.resent += 1;
+                },
+                None => trace!("window has room, sending seq#{}", entry.seq_id),
+            }
+            entry.sent_time = Some(now);
+            entry.attempts += 1;
+            due.push(entry.packet.clone());
+        }
+        Ok(due)
+    }
+
+    /// Drops every packet covered by an ack from the peer. The newest acked packet is measured for the peer's round trip time,
+    /// the ones in the bits were already received earlier
+    pub fn accept_acks(&mut self, addr: SocketAddr, ack: u16, ack_bits: u32) -> Acked {
+        if ack == 0 {
+            return Acked::default();
+        }
+        let Some(queue) = self.client_queue.get_mut(&addr) else {
+            return Acked::default();
+        };
+        let before = queue.entries.len();
+        let mut rtt = None;
+        queue.entries.retain(|entry| {
+            let acked = match seq_distance(ack, entry.seq_id) {
+                0 => {
+                    // Karn's algorithm: a resent packet's ack could be for any of its sends
+                    if entry.attempts == 1 {
+                        rtt = entry.sent_time.map(|time| time.elapsed());
+                    }
+                    true
+                },
+                age @ 1..=ACK_BITS => ack_bits & (1 << (age - 1)) != 0,
+                _ => false
+            };
+            !acked
+        });
+        if let Some(rtt) = rtt {
+            queue.rtt.sample(rtt);
+        }
+        let count = before - queue.entries.len();
+        if count > 0 {
+            trace!("accepting ACK {} (bits {:032b}) for {:?}, {} packets acked", ack, ack_bits, addr, count);
+        }
+        Acked { count, rtt }
+    }
+}
+
+/// Most ticks between snapshots to a peer, when it's congested
+pub const MAX_SNAPSHOT_INTERVAL: u8 = 8;
+/// Round trip time above the lowest one seen, that counts as the peer's link being queued up
+const RTT_RISE: Duration = Duration::from_millis(50);
+
+/// Picks how often a peer is sent snapshots (every n ticks). Backs off quickly when packets to it are lost
+/// or its round trip time rises over the lowest one seen, and speeds back up slowly once they don't
+#[derive(Debug, Clone)]
+pub struct SendRateControl {
+    interval: u8,
+    /// Ticks since the last snapshot
+    since_sent: u8,
+    min_rtt: Option<Duration>,
+    /// [ReliableQueue::resent_count] at the last update
+    last_resent: u32,
+    congested: bool,
+}
+
+impl Default for SendRateControl {
+    fn default() -> Self {
+        Self { interval: 1, since_sent: 0, min_rtt: None, last_resent: 0, congested: false }
+    }
+}
+
+impl SendRateControl {
+    /// Called every tick
+    pub fn tick(&mut self) {
+        self.since_sent = self.since_sent.saturating_add(1);
+    }
+
+    /// Whether it's time for the next snapshot, if so it counts as sent
+    pub fn take_due(&mut self) -> bool {
+        if self.since_sent < self.interval {
+            return false;
+        }
+        self.since_sent = 0;
+        true
+    }
+
+    /// Updates the rate with the peer's current round trip time and resend count, about once a second
+    pub fn update(&mut self, srtt: Option<Duration>, resent: u32) {
+        let lost = resent > self.last_resent;
+        self.last_resent = resent;
+        let queued = match (srtt, self.min_rtt) {
+            (Some(srtt), Some(min_rtt)) => srtt > min_rtt * 2 + RTT_RISE,
+            _ => false,
+        };
+        if let Some(srtt) = srtt {
+            self.min_rtt = Some(self.min_rtt.map_or(srtt, |min_rtt| min_rtt.min(srtt)));
+        }
+        self.congested = lost || queued;
+        if self.congested {
+            self.interval = self.interval.saturating_mul(2).min(MAX_SNAPSHOT_INTERVAL);
+        } else if self.interval > 1 {
+            self.interval -= 1;
+        }
+    }
+
+    /// Ticks between snapshots, 1 when not congested
+    pub fn interval(&self) -> u8 {
+        self.interval
+    }
+
+    /// Whether the last update found the peer losing packets or queueing them up
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+}
+
+/// Where datagrams are sent and received through, a UdpSocket or a [MemoryTransport] in tests.
+/// Shared by the network threads, so everything takes &self
+pub trait Transport: Send + Sync {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Blocks until a datagram arrives, or fails with [io::ErrorKind::WouldBlock] once the read timeout is up
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+type Mailbox = Sender<(Vec<u8>, SocketAddr)>;
+
+/// An in-process network, so a server and its clients can run in one test.
+/// Endpoints are bound to made up addresses and datagrams go over channels: nothing is lost or reordered,
+/// wrap them in a [SimSocket](crate::netsim::SimSocket) for that. Datagrams to unbound addresses are dropped, like UDP
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    mailboxes: Arc<Mutex<HashMap<SocketAddr, Mailbox>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds an endpoint at the address, port 0 picks a free one
+    pub fn bind(&self, mut addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut lock = self.mailboxes.lock().unwrap();
+        if addr.port() == 0 {
+            let port = (1..=u16::MAX).find(|&port| !lock.contains_key(&SocketAddr::new(addr.ip(), port)))
+                .ok_or(io::ErrorKind::AddrInUse)?;
+            addr.set_port(port);
+        } else if lock.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = channel();
+        lock.insert(addr, tx);
+        Ok(MemoryTransport {
+            addr,
+            network: self.clone(),
+            inbox: Mutex::new(rx),
+            read_timeout: Mutex::new(None),
+        })
+    }
+}
+
+/// An endpoint on a [MemoryNetwork], unbound when dropped
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl MemoryTransport {
+    /// Two endpoints on a network of their own, for a client and server
+    pub fn pair() -> (Self, Self) {
+        let network = MemoryNetwork::new();
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        (network.bind(localhost).unwrap(), network.bind(localhost).unwrap())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if let Some(mailbox) = self.network.mailboxes.lock().unwrap().get(&addr) {
+            mailbox.send((buf.to_vec(), self.addr)).ok();
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let inbox = self.inbox.lock().unwrap();
+        let (datagram, from) = match timeout {
+            Some(timeout) => inbox.recv_timeout(timeout).map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?,
+            // Never disconnected, we hold a sender in the network ourselves
+            None => inbox.recv().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?,
+        };
+        // Anything that doesn't fit is cut off, like UDP
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot set a 0 duration timeout"));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.mailboxes.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::def::Vector3;
+
+    fn addr() -> SocketAddr {
+        "10.0.0.1:5000".parse().unwrap()
+    }
+
+    fn event() -> ServerEvent {
+        ServerEvent::Disconnect { client_index: 1, reason: "test".to_string() }
+    }
+
+    #[test]
+    fn channels_are_numbered_separately() {
+        let mut queue = ReliableQueue::new();
+        let moved = ServerEvent::Move { client_index: 1, position: Vector3::zero(), angles: Vector3::zero(), velocity: Vector3::zero() };
+        let result = ServerEvent::CommandResult { id: 1, result: true };
+        assert_eq!(queue.unreliable_packet(addr(), &moved).channel_seq(), 1);
+        assert_eq!(queue.add_event(addr(), &event()).packet.channel_seq(), 1);
+        assert_eq!(queue.add_event(addr(), &result).packet.channel_seq(), 1);
+        let entry = queue.add_event(addr(), &event());
+        assert_eq!((entry.seq_id, entry.packet.channel_seq()), (3, 2));
+        assert_eq!(queue.unreliable_packet(addr(), &moved).channel_seq(), 2);
+        let challenge = ServerEvent::Challenge { cookie: [0; COOKIE_LEN] };
+        assert_eq!(queue.unreliable_packet(addr(), &challenge).channel_seq(), 0);
+    }
+
+    #[test]
+    fn ordered_events_wait_for_missing_ones() {
+        let mut receiver = ChannelReceiver::default();
+        assert_eq!(receiver.receive(Channel::ReliableOrdered, 2, 'b'), vec![]);
+        assert_eq!(receiver.receive(Channel::ReliableOrdered, 3, 'c'), vec![]);
+        assert_eq!(receiver.receive(Channel::ReliableUnordered, 5, 'x'), vec!['x']);
+        assert_eq!(receiver.held_count(), 2);
+        assert_eq!(receiver.receive(Channel::ReliableOrdered, 1, 'a'), vec!['a', 'b', 'c']);
+        assert_eq!(receiver.receive(Channel::ReliableOrdered, 2, 'b'), vec![], "duplicate");
+        assert_eq!(receiver.held_count(), 0);
+    }
+
+    #[test]
+    fn stale_sequenced_events_are_dropped() {
+        let mut receiver = ChannelReceiver::default();
+        assert_eq!(receiver.receive(Channel::UnreliableSequenced, 2, 2), vec![2]);
+        assert_eq!(receiver.receive(Channel::UnreliableSequenced, 1, 1), vec![]);
+        assert_eq!(receiver.receive(Channel::UnreliableSequenced, 2, 2), vec![]);
+        assert_eq!(receiver.receive(Channel::UnreliableSequenced, 5, 5), vec![5]);
+        assert_eq!(receiver.receive(Channel::UnreliableSequenced, 0, 0), vec![0], "peer without channels");
+    }
+
+    #[test]
+    fn seq_wraps_past_zero() {
+        assert_eq!(next_seq(u16::MAX), 1);
+        assert_eq!(seq_distance(1, u16::MAX), 1);
+        assert_eq!(seq_distance(3, 1), 2);
+        assert!(seq_is_newer(2, u16::MAX));
+        assert!(!seq_is_newer(u16::MAX, 2));
+    }
+
+    #[test]
+    fn window_sets_bits_for_older_packets() {
+        let mut window = AckWindow::default();
+        assert!(window.record(1));
+        assert!(window.record(3));
+        assert!(window.record(2));
+        assert_eq!((window.ack(), window.ack_bits()), (3, 0b11));
+        assert!(window.record(7));
+        assert_eq!((window.ack(), window.ack_bits()), (7, 0b111000));
+    }
+
+    #[test]
+    fn window_rejects_duplicates() {
+        let mut window = AckWindow::default();
+        assert!(window.record(5));
+        assert!(window.record(4));
+        assert!(!window.record(5));
+        assert!(!window.record(4));
+        assert!(window.record(40));
+        assert!(!window.record(5), "too old to tell");
+    }
+
+    #[test]
+    fn duplicates_are_tracked_per_peer() {
+        let other: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let mut received = ReceivedSequences::default();
+        assert!(received.record(addr(), 1));
+        assert!(received.record(other, 1));
+        assert!(!received.record(addr(), 1));
+        assert!(received.record(addr(), 2));
+        assert_eq!(received.window(addr()).map(|window| (window.ack(), window.ack_bits())), Some((2, 0b1)));
+        received.remove(addr());
+        assert!(received.record(addr(), 1), "forgotten after a new login");
+        assert_eq!(received.window(other).map(|window| window.ack()), Some(1));
+    }
+
+    #[test]
+    fn window_across_wrap() {
+        let mut window = AckWindow::default();
+        assert!(window.record(u16::MAX));
+        assert!(window.record(2));
+        assert_eq!((window.ack(), window.ack_bits()), (2, 0b10));
+    }
+
+    #[test]
+    fn acks_are_accepted_out_of_order() {
+        let mut queue = ReliableQueue::new();
+        for _ in 0..5 {
+            queue.add_event(addr(), &event());
+        }
+        // 2 and 4 arrived, 1, 3 and 5 didn't
+        assert_eq!(queue.accept_acks(addr(), 4, 0b10).count, 2);
+        assert_eq!(queue.count(addr()), Some(3));
+        // a late ack is fine too
+        assert_eq!(queue.accept_acks(addr(), 3, 0).count, 1);
+        assert_eq!(queue.accept_acks(addr(), 3, 0).count, 0);
+        assert_eq!(queue.count(addr()), Some(2));
+    }
+
+    #[test]
+    fn lost_packet_does_not_block_the_rest() {
+        let mut queue = ReliableQueue::new();
+        for _ in 0..3 {
+            queue.add_event(addr(), &event());
+        }
+        queue.accept_acks(addr(), 3, 0b1);
+        std::thread::sleep(ACK_TIMEOUT_REPLY * 2);
+        let due = queue.due(addr(), DEFAULT_MAX_SEND_ATTEMPTS).unwrap();
+        assert_eq!(due.iter().map(|pk| pk.sequence_number()).collect::<Vec<_>>(), vec![1]);
+        assert!(queue.due(addr(), DEFAULT_MAX_SEND_ATTEMPTS).unwrap().is_empty(), "resent packets get another timeout");
+    }
+
+    #[test]
+    fn peer_is_given_up_after_max_attempts() {
+        let mut queue = ReliableQueue::new();
+        queue.add_event(addr(), &event());
+        queue.add_event(addr(), &event());
+        queue.accept_acks(addr(), 1, 0);
+        std::thread::sleep(ACK_TIMEOUT_REPLY * 2);
+        assert_eq!(queue.due(addr(), 2).unwrap().len(), 1);
+        std::thread::sleep(ACK_TIMEOUT_REPLY * 2);
+        assert_eq!(queue.due(addr(), 2).err(), Some(2));
+    }
+
+    #[test]
+    fn window_holds_packets_until_acked() {
+        let mut queue = ReliableQueue::new();
+        let entries: Vec<_> = (0..ACK_BITS + 3).map(|_| queue.add_event(addr(), &event())).collect();
+        let held: Vec<_> = entries.iter().filter(|entry| entry.sent_time.is_none()).map(|entry| entry.seq_id).collect();
+        assert_eq!(held, vec![ACK_BITS + 2, ACK_BITS + 3]);
+        assert!(queue.due(addr(), DEFAULT_MAX_SEND_ATTEMPTS).unwrap().is_empty());
+        assert_eq!(queue.peers(), vec![addr()]);
+
+        queue.accept_acks(addr(), 1, 0);
+        let due: Vec<_> = queue.due(addr(), DEFAULT_MAX_SEND_ATTEMPTS).unwrap().iter().map(|pk| pk.sequence_number()).collect();
+        assert_eq!(due, vec![ACK_BITS + 2]);
+    }
+
+    #[test]
+    fn sequence_numbers_are_per_peer() {
+        let mut queue = ReliableQueue::new();
+        let other: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        assert_eq!(queue.add_event(addr(), &event()).seq_id, 1);
+        assert_eq!(queue.add_event(other, &event()).seq_id, 1);
+        assert_eq!(queue.accept_acks(other, 1, 0).count, 1);
+        assert_eq!(queue.count(addr()), Some(1));
+    }
+
+    #[test]
+    fn rtt_is_smoothed_over_samples() {
+        let ms = Duration::from_millis;
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(), ACK_TIMEOUT_REPLY);
+        rtt.sample(ms(80));
+        assert_eq!((rtt.srtt(), rtt.rttvar()), (Some(ms(80)), ms(40)));
+        assert_eq!(rtt.rto(), ms(240));
+        rtt.sample(ms(40));
+        assert_eq!((rtt.srtt(), rtt.rttvar()), (Some(ms(75)), ms(40)));
+        assert_eq!(rtt.backoff_rto(1), ms(235));
+        assert_eq!(rtt.backoff_rto(3), ms(940));
+        assert_eq!(rtt.backoff_rto(4), MAX_RTO);
+        rtt = RttEstimator::default();
+        rtt.sample(Duration::ZERO);
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+
+    #[test]
+    fn resent_packets_are_not_measured() {
+        let mut queue = ReliableQueue::new();
+        queue.add_event(addr(), &event());
+        queue.add_event(addr(), &event());
+        assert!(queue.rtt(addr()).unwrap().srtt().is_none());
+        std::thread::sleep(ACK_TIMEOUT_REPLY * 2);
+        assert_eq!(queue.due(addr(), DEFAULT_MAX_SEND_ATTEMPTS).unwrap().len(), 2);
+        assert_eq!(queue.accept_acks(addr(), 1, 0).rtt, None);
+        assert!(queue.rtt(addr()).unwrap().srtt().is_none());
+
+        queue.add_event(addr(), &event());
+        let acked = queue.accept_acks(addr(), 3, 0);
+        assert!(acked.rtt.is_some());
+        assert_eq!(queue.rtt(addr()).unwrap().srtt(), acked.rtt);
+    }
+
+    #[test]
+    fn snapshot_rate_backs_off_when_congested() {
+        let ms = Duration::from_millis;
+        let mut rate = SendRateControl::default();
+        rate.update(Some(ms(20)), 0);
+        assert_eq!(rate.interval(), 1);
+        rate.update(Some(ms(30)), 2);
+        assert!(rate.is_congested());
+        assert_eq!(rate.interval(), 2);
+        rate.update(Some(ms(200)), 2);
+        assert_eq!(rate.interval(), 4, "round trip rose");
+        rate.update(Some(ms(25)), 2);
+        assert!(!rate.is_congested());
+        assert_eq!(rate.interval(), 3);
+        for _ in 0..5 {
+            rate.update(Some(ms(300)), 2);
+        }
+        assert_eq!(rate.interval(), MAX_SNAPSHOT_INTERVAL);
+    }
+
+    #[test]
+    fn snapshots_are_due_every_interval() {
+        let mut rate = SendRateControl::default();
+        rate.update(None, 1);
+        assert_eq!(rate.interval(), 2);
+        let due: Vec<_> = (0..6).map(|_| {
+            rate.tick();
+            rate.take_due()
+        }).collect();
+        assert_eq!(due, vec![false, true, false, true, false, true]);
+    }
+
+    #[test]
+    fn gaps_in_sequenced_packets_count_as_loss() {
+        let now = Instant::now();
+        let mut link = LinkStats::default();
+        for seq in [1, 2, 5] {
+            link.record_sequenced(seq, now);
+        }
+        assert_eq!(link.loss_in(), 40.0);
+        link.record_sequenced(4, now);
+        link.record_sequenced(5, now);
+        assert_eq!(link.loss_in(), 20.0);
+        assert_eq!((link.out_of_order(), link.duplicates()), (1, 1));
+    }
+
+    #[test]
+    fn jitter_follows_uneven_arrivals() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut link = LinkStats::default();
+        for (seq, at) in [(1, 0), (2, 30), (3, 60)] {
+            link.record_sequenced(seq, start + ms(at));
+        }
+        assert_eq!(link.jitter(), Duration::ZERO);
+        link.record_sequenced(4, start + ms(106));
+        assert_eq!(link.jitter(), ms(1));
+        // A gap in the sequence isn't one interval
+        link.record_sequenced(9, start + ms(500));
+        link.record_sequenced(10, start + ms(530));
+        assert_eq!(link.jitter(), ms(1));
+    }
+
+    #[test]
+    fn reliable_resends_count_as_loss_out() {
+        let mut link = LinkStats::default();
+        link.record_sent(3, 1);
+        assert_eq!(link.loss_out(), 25.0);
+        link.record_reliable(2, true);
+        link.record_reliable(1, true);
+        link.record_reliable(2, false);
+        assert_eq!((link.out_of_order(), link.duplicates()), (1, 1));
+    }
+
+    #[test]
+    fn memory_endpoints_exchange_datagrams() {
+        let (a, b) = MemoryTransport::pair();
+        let b_addr = b.local_addr().unwrap();
+        a.send_to(&[1, 2, 3], b_addr).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(b.recv_from(&mut buf).unwrap(), (3, a.local_addr().unwrap()));
+        assert_eq!(buf[..3], [1, 2, 3]);
+        // Gone once dropped, so sends to it go nowhere
+        drop(b);
+        a.send_to(&[1], b_addr).unwrap();
+    }
+
+    #[test]
+    fn memory_recv_times_out() {
+        let network = MemoryNetwork::new();
+        let transport = network.bind(addr()).unwrap();
+        assert_eq!(network.bind(addr()).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+        transport.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let err = transport.recv_from(&mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -1,13 +1,89 @@
 use log::{debug, trace};
 use crate::buffer::BitBuffer;
-use std::fmt::Write;
+use std::fmt::{Display, Formatter, Write};
 use std::io;
 use std::time::{SystemTime, UNIX_EPOCH};
-use anyhow::{anyhow, format_err};
 use int_enum::IntEnum;
-use zstd::DEFAULT_COMPRESSION_LEVEL;
-use zstd::stream::copy_encode;
-use crate::unix_timestamp;
+use crate::{unix_timestamp, MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION};
+
+/// Header fields are always present - Packet is only constructed from a builder or by try_from
+const HEADER_VALIDATED: &str = "packet header was not validated";
+
+/// Largest possible packet, a full header plus the largest payload the length field allows
+pub const MAX_PACKET_SIZE: usize = PacketHeaderOffset::Payload as usize + u16::MAX as usize;
+
+/// First bytes of every packet, to tell our packets apart from stray datagrams
+pub const PACKET_MAGIC: u16 = u16::from_le_bytes(*b"MP");
+
+/// Why a received datagram could not be turned into a [Packet], or a packet could not be sent
+#[derive(Debug)]
+pub enum PacketError {
+    /// Packet could not be compressed
+    Compress(io::Error),
+    /// Compressed packet is larger than can be split into fragments
+    TooLarge { len: usize, max: usize },
+    /// Datagram is not valid zstd data
+    Decompress(io::Error),
+    /// Fragment index is outside its fragment count, or count differs from the other fragments
+    BadFragment { index: u8, count: u8 },
+    /// Too many incomplete fragmented packets are waiting on the rest of their fragments, this one was dropped
+    TooManyFragments,
+    /// Buffer is too short to contain a header, or the payload its header claims
+    TooShort { len: usize, expected: usize },
+    /// Payload length in header is 0
+    EmptyPayload,
+    /// Packet does not start with [PACKET_MAGIC]
+    BadMagic { magic: u16 },
+    /// Sender is on a protocol version outside [MIN_PROTOCOL_VERSION]..=[PACKET_PROTOCOL_VERSION]
+    BadVersion { version: u16 },
+    /// Checksum in header does not match the packet contents
+    BadChecksum { expected: u32, actual: u32 },
+    /// Datagram could not be encrypted
+    Encrypt,
+    /// Encrypted datagram failed authentication, it was tampered with or sealed with another key
+    Decrypt,
+    /// Encrypted datagram was already received, or is too old to tell
+    Replayed { counter: u64 },
+    /// Encrypted datagram from a peer we have no session with
+    NoSession,
+    /// Plaintext datagram from a peer that has an encrypted session
+    Unencrypted,
+    /// Peer doesn't support our protocol version, only the ones in min..=max
+    VersionRejected { min: u16, max: u16 },
+}
+
+impl PacketError {
+    /// True if the datagram failed integrity checks (foreign, corrupted or replayed), rather than being a malformed packet of ours
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, PacketError::Decompress(_) | PacketError::BadMagic { .. } | PacketError::BadVersion { .. } | PacketError::BadChecksum { .. }
+            | PacketError::Decrypt | PacketError::Replayed { .. } | PacketError::NoSession | PacketError::Unencrypted)
+    }
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketError::Compress(e) => write!(f, "compress failed: {}", e),
+            PacketError::TooLarge { len, max } => write!(f, "packet len ({}) exceeds max fragmented size ({})", len, max),
+            PacketError::Decompress(e) => write!(f, "decompress failed: {}", e),
+            PacketError::BadFragment { index, count } => write!(f, "bad fragment {} of {}", index, count),
+            PacketError::TooManyFragments => write!(f, "too many incomplete fragmented packets"),
+            PacketError::TooShort { len, expected } => write!(f, "packet len ({}) is smaller than expected ({})", len, expected),
+            PacketError::EmptyPayload => write!(f, "payload length is invalid (0)"),
+            PacketError::BadMagic { magic } => write!(f, "bad magic 0x{:04X}", magic),
+            PacketError::BadVersion { version } => write!(f, "protocol version {}, expected {}..={}", version, MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION),
+            PacketError::BadChecksum { expected, actual } => write!(f, "bad checksum 0x{:08X}, expected 0x{:08X}", actual, expected),
+            PacketError::Encrypt => write!(f, "encrypt failed"),
+            PacketError::Decrypt => write!(f, "decrypt failed"),
+            PacketError::Replayed { counter } => write!(f, "replayed datagram (counter {})", counter),
+            PacketError::NoSession => write!(f, "encrypted datagram without a session"),
+            PacketError::Unencrypted => write!(f, "unencrypted datagram on an encrypted session"),
+            PacketError::VersionRejected { min, max } => write!(f, "peer only supports protocol versions {}..={}", min, max),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
 
 pub struct PacketBuilder {
     buf: BitBuffer,
@@ -16,13 +92,29 @@ pub struct PacketBuilder {
 #[repr(usize)]
 #[derive(IntEnum)]
 enum PacketHeaderOffset {
-    Length = 0x0,           // u16
-    PacketType = 0x2,       // u8
-    Timestamp = 0x3,        // u32
-    AuthId = 0x7,           // u32
-    SeqNum = 0xB,           // u16
+    Magic = 0x0,            // u16
+    Version = 0x2,          // u16
+    Checksum = 0x4,         // u32
+    Length = 0x8,           // u16
+    PacketType = 0xA,       // u8
+    Timestamp = 0xB,        // u32
+    AuthId = 0xF,           // u32 - client -> server only, 0 before login
+    SeqNum = 0x13,          // u16 - 0 if not reliable
+    Ack = 0x15,             // u16 - latest reliable seq received from the other side, 0 if none
+    AckBits = 0x17,         // u32 - bit n set if seq ack-1-n was also received
+    ChannelSeq = 0x1B,      // u16 - order in the packet's channel, 0 if unreliable
 
-    Payload = 0xD
+    Payload = 0x1D
+}
+
+/// CRC32 of the packet with the checksum field zeroed, seeded with the packet's protocol version
+fn packet_checksum(bytes: &[u8], version: u16) -> u32 {
+    let checksum_start: usize = PacketHeaderOffset::Checksum.into();
+    let mut hasher = crc32fast::Hasher::new_with_initial(version as u32);
+    hasher.update(&bytes[..checksum_start]);
+    hasher.update(&[0; 4]);
+    hasher.update(&bytes[checksum_start + 4..]);
+    hasher.finalize()
 }
 
 impl PacketBuilder {
@@ -31,11 +123,16 @@ impl PacketBuilder {
         let mut builder = Self {
             buf
         }
+            .with_magic()
+            .with_version(PACKET_PROTOCOL_VERSION)
             .with_length(u16::MAX)
             .with_type(packet_type)
             .with_timestamp(unix_timestamp())
             .with_auth_id(0) // unused on server side
-            .with_sequence_number(0); // only set for reliable
+            .with_sequence_number(0) // only set for reliable
+            .with_ack(0)
+            .with_ack_bits(0)
+            .with_channel_seq(0);
         // Set cursor to end of header - prevent payload overwriting
         builder.buf.set_offset_pos(PacketHeaderOffset::Payload.into()).unwrap();
         builder
@@ -46,25 +143,53 @@ impl PacketBuilder {
         self
     }
 
+    fn with_magic(mut self) -> Self {
+        self.buf.write_u16_at(PacketHeaderOffset::Magic.into(), PACKET_MAGIC);
+        self
+    }
+
+    /// Sets the protocol version (defaults to [PACKET_PROTOCOL_VERSION]), for peers on an older one.
+    /// The payload has to be written in that version's layout, see [crate::compat]
+    pub fn with_version(mut self, version: u16) -> Self {
+        self.buf.write_u16_at(PacketHeaderOffset::Version.into(), version);
+        self
+    }
+
     fn with_length(mut self, len: u16) -> Self {
         self.buf.write_u16_at(PacketHeaderOffset::Length.into(), len);
         self
     }
 
-    // Sets the auth id (defaults to 0)
-    // Note: This field conflicts with sequence_number, auth id is only for outgoing client packets
+    /// Sets the auth id (defaults to 0). Only read by the server, on client sent packets
     pub fn with_auth_id(mut self, auth_id: u32) -> Self {
         self.buf.write_u32_at(PacketHeaderOffset::AuthId.into(), auth_id);
         self
     }
 
-    // Sets the sequence number.
-    // Note: This field conflicts with auth_id, seq number is only for outgoing server packets
+    /// Sets the sequence number, marking the packet as reliable (defaults to 0, unreliable)
     pub fn with_sequence_number(mut self, seq_num: u16) -> Self {
         self.buf.write_u16_at(PacketHeaderOffset::SeqNum.into(), seq_num);
         self
     }
 
+    /// Acknowledges a reliable packet received from the other side (defaults to 0, nothing acked)
+    pub fn with_ack(mut self, ack: u16) -> Self {
+        self.buf.write_u16_at(PacketHeaderOffset::Ack.into(), ack);
+        self
+    }
+
+    /// Acknowledges the reliable packets received before the ack, see [Packet::ack_bits] (defaults to 0)
+    pub fn with_ack_bits(mut self, ack_bits: u32) -> Self {
+        self.buf.write_u32_at(PacketHeaderOffset::AckBits.into(), ack_bits);
+        self
+    }
+
+    /// Sets the sequence number in the event's [Channel](crate::network::Channel) (defaults to 0, unsequenced)
+    pub fn with_channel_seq(mut self, channel_seq: u16) -> Self {
+        self.buf.write_u16_at(PacketHeaderOffset::ChannelSeq.into(), channel_seq);
+        self
+    }
+
     /// Replaces default timestamp (of when new() called), with a specific timestamp
     pub fn with_timestamp(mut self, timestamp: u32) -> Self {
         self.buf.write_u32_at(PacketHeaderOffset::Timestamp.into(), timestamp);
@@ -78,6 +203,9 @@ impl PacketBuilder {
     pub fn finalize(mut self) -> Packet {
         let len: usize = self.buf.len() - PacketHeaderOffset::Payload as usize; // subtract the payload length + payload type fields
         self = self.with_length(len as u16);
+        let version = self.buf.peek_u16_at(PacketHeaderOffset::Version.into()).expect(HEADER_VALIDATED);
+        let checksum = packet_checksum(self.buf.as_bytes(), version);
+        self.buf.write_u32_at(PacketHeaderOffset::Checksum.into(), checksum);
         Packet::new(self.buf)
     }
 }
@@ -95,31 +223,70 @@ impl Packet {
         }
     }
 
-    pub fn try_from<B: Into<BitBuffer>>(buf: B) -> Result<Self, anyhow::Error> where BitBuffer: From<B> {
-        let buf: BitBuffer = buf.try_into()?;
-        if buf.len() <= PacketHeaderOffset::Payload.into() {
-            return Err(anyhow!("packet len ({}) is smaller than header size ({})", buf.len(), PacketHeaderOffset::Payload as usize));
+    pub fn try_from<B: Into<BitBuffer>>(buf: B) -> Result<Self, PacketError> where BitBuffer: From<B> {
+        let buf = BitBuffer::from(buf);
+        let header_len: usize = PacketHeaderOffset::Payload.into();
+        if buf.len() <= header_len {
+            return Err(PacketError::TooShort { len: buf.len(), expected: header_len + 1 });
         }
         let pk = Self { buf };
+        let magic = pk.magic();
+        if magic != PACKET_MAGIC {
+            return Err(PacketError::BadMagic { magic });
+        }
+        let version = pk.version();
+        if !(MIN_PROTOCOL_VERSION..=PACKET_PROTOCOL_VERSION).contains(&version) {
+            return Err(PacketError::BadVersion { version });
+        }
         let py_len = pk.payload_len();
         if py_len == 0 {
-            return Err(anyhow!("payload length is invalid (0)"))
-        } else if pk.buf.len() < py_len as usize {
-            return Err(anyhow!("buffer length too small ({}) for payload ({})", pk.buf.len(), py_len));
+            return Err(PacketError::EmptyPayload)
+        } else if pk.buf.len() < header_len + py_len as usize {
+            return Err(PacketError::TooShort { len: pk.buf.len(), expected: header_len + py_len as usize });
+        }
+        let actual = pk.checksum();
+        let expected = packet_checksum(pk.buf.get_vec_slice(0, header_len + py_len as usize), pk.version());
+        if actual != expected {
+            return Err(PacketError::BadChecksum { expected, actual });
         }
         Ok(pk)
     }
 
-    pub fn try_decompress_from_slice(slice: &[u8]) -> anyhow::Result<Self> {
-        trace!("{:x?}", slice);
-        let vec = zstd::bulk::decompress(slice, slice.len() * 2)
-            .map_err(|e| format_err!("decompress failed: {}", e))?;
-        Self::try_from(vec)
+    /// Splits a batch of packets sent back to back, each framed by the length in its own header
+    pub fn try_from_batch(vec: Vec<u8>) -> Result<Vec<Self>, PacketError> {
+        let header_len: usize = PacketHeaderOffset::Payload.into();
+        let length_offset: usize = PacketHeaderOffset::Length.into();
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while offset < vec.len() {
+            let rest = &vec[offset..];
+            if rest.len() <= header_len {
+                return Err(PacketError::TooShort { len: rest.len(), expected: header_len + 1 });
+            }
+            let py_len = u16::from_le_bytes([rest[length_offset], rest[length_offset + 1]]) as usize;
+            let end = (header_len + py_len).min(rest.len());
+            packets.push(Self::try_from(rest[..end].to_vec())?);
+            offset += end;
+        }
+        Ok(packets)
+    }
+
+    pub fn magic(&self) -> u16 {
+        self.buf.peek_u16_at(PacketHeaderOffset::Magic.into()).expect(HEADER_VALIDATED)
+    }
+
+    /// Protocol version of the sender
+    pub fn version(&self) -> u16 {
+        self.buf.peek_u16_at(PacketHeaderOffset::Version.into()).expect(HEADER_VALIDATED)
+    }
+
+    pub fn checksum(&self) -> u32 {
+        self.buf.peek_u32_at(PacketHeaderOffset::Checksum.into()).expect(HEADER_VALIDATED)
     }
 
     // The length of the packet
     pub fn payload_len(&self) -> u16 {
-        let py_len = self.buf.peek_u16_at(PacketHeaderOffset::Length.into());
+        let py_len = self.buf.peek_u16_at(PacketHeaderOffset::Length.into()).expect(HEADER_VALIDATED);
         // assert!(self.buf_len() >= py_len, "payload len exceeds buffer len (invalid value?)");
         // assert_eq!(pk_len + 4, self.buf.len() as u32, "packet len record != buffer len");
         py_len
@@ -131,22 +298,38 @@ impl Packet {
     }
 
     pub fn packet_type(&self) -> u8 {
-        self.buf.peek_u8_at(PacketHeaderOffset::PacketType.into())
+        self.buf.peek_u8_at(PacketHeaderOffset::PacketType.into()).expect(HEADER_VALIDATED)
     }
 
     pub fn timestamp(&self) -> u32 {
-        self.buf.peek_u32_at(PacketHeaderOffset::Timestamp.into())
+        self.buf.peek_u32_at(PacketHeaderOffset::Timestamp.into()).expect(HEADER_VALIDATED)
     }
 
     /// Gets the sequence number. 0 if not a reliable (requiring ACK) packet.
     pub fn sequence_number(&self) -> u16 {
-        self.buf.peek_u16_at(PacketHeaderOffset::SeqNum.into())
+        self.buf.peek_u16_at(PacketHeaderOffset::SeqNum.into()).expect(HEADER_VALIDATED)
+    }
+
+    /// Gets the latest reliable sequence number the sender received from us. 0 if none.
+    pub fn ack(&self) -> u16 {
+        self.buf.peek_u16_at(PacketHeaderOffset::Ack.into()).expect(HEADER_VALIDATED)
+    }
+
+    /// Gets which of the 32 reliable sequence numbers before [Packet::ack] the sender also received,
+    /// bit n being `ack - 1 - n`
+    pub fn ack_bits(&self) -> u32 {
+        self.buf.peek_u32_at(PacketHeaderOffset::AckBits.into()).expect(HEADER_VALIDATED)
+    }
+
+    /// Gets the sequence number in the event's [Channel](crate::network::Channel). 0 if the channel isn't sequenced
+    pub fn channel_seq(&self) -> u16 {
+        self.buf.peek_u16_at(PacketHeaderOffset::ChannelSeq.into()).expect(HEADER_VALIDATED)
     }
 
     /// Gets the auth id from client. May be 0 if Login event.
     /// Only for server reading client sent packets
     pub fn auth_id(&self) -> u32 {
-        self.buf.peek_u32_at(PacketHeaderOffset::AuthId.into())
+        self.buf.peek_u32_at(PacketHeaderOffset::AuthId.into()).expect(HEADER_VALIDATED)
     }
 
     pub fn buf_mut(&mut self) -> &mut BitBuffer {
@@ -170,37 +353,175 @@ impl Packet {
     pub fn as_hex_str(&self) -> String {
         let mut s = String::with_capacity((self.buf_len() + 4) as usize);
 
-        let header_buf = self.buf.slice(0, PacketHeaderOffset::Payload.into());
-        let payload_buf = self.payload_buf();
+        let payload_start: usize = PacketHeaderOffset::Payload.into();
         write!(s,"[{}]0x", self.buf_len()).unwrap();
-        for i in 0..PacketHeaderOffset::Payload.into() {
-            write!(s, "{:02X}", header_buf.peek_u8_at(i)).unwrap();
+        for b in self.buf.get_vec_slice(0, payload_start) {
+            write!(s, "{:02X}", b).unwrap();
         }
         write!(s, " ").unwrap();
-        for i in 0..self.payload_len() as usize {
-            write!(s, "{:02X}", payload_buf.peek_u8_at(i)).unwrap();
+        for b in self.buf.get_vec_slice(payload_start, payload_start + self.payload_len() as usize) {
+            write!(s, "{:02X}", b).unwrap();
         }
         s
     }
 
-    pub fn compress(&self) -> std::io::Result<Vec<u8>> {
-        zstd::bulk::compress(self.buf.as_bytes(), DEFAULT_COMPRESSION_LEVEL)
+    /// Writes packets back to back, to be split again by [Packet::try_from_batch]
+    pub fn concat_batch(packets: &[Packet]) -> Vec<u8> {
+        packets.iter()
+            .flat_map(|pk| pk.as_slice())
+            .copied()
+            .collect()
     }
 }
 
-impl From<&[u8]> for Packet {
-    fn from(slice: &[u8]) -> Self {
-        Packet {
-            buf: BitBuffer::from(slice.to_vec())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_fields_are_contiguous() {
+        // (offset, width) of every header field, in order
+        let fields: [(PacketHeaderOffset, usize); 11] = [
+            (PacketHeaderOffset::Magic, 2),
+            (PacketHeaderOffset::Version, 2),
+            (PacketHeaderOffset::Checksum, 4),
+            (PacketHeaderOffset::Length, 2),
+            (PacketHeaderOffset::PacketType, 1),
+            (PacketHeaderOffset::Timestamp, 4),
+            (PacketHeaderOffset::AuthId, 4),
+            (PacketHeaderOffset::SeqNum, 2),
+            (PacketHeaderOffset::Ack, 2),
+            (PacketHeaderOffset::AckBits, 4),
+            (PacketHeaderOffset::ChannelSeq, 2),
+        ];
+        let mut expected = 0;
+        for (offset, width) in fields {
+            let offset: usize = offset.into();
+            assert_eq!(offset, expected);
+            expected += width;
         }
+        assert_eq!(usize::from(PacketHeaderOffset::Payload), expected);
     }
-}
 
+    #[test]
+    fn header_fields_are_independent() {
+        let mut builder = PacketBuilder::new(0x7)
+            .with_timestamp(0xDEADBEEF)
+            .with_auth_id(0x12345678)
+            .with_sequence_number(0xABCD)
+            .with_ack(0x4321)
+            .with_ack_bits(0x8000_0001)
+            .with_channel_seq(0x9876);
+        builder.buf_mut().write_u8(0xFF);
+        let pk = Packet::try_from(builder.finalize().as_slice().to_vec()).unwrap();
 
-impl From<Vec<u8>> for Packet {
-    fn from(vec: Vec<u8>) -> Self {
-        Packet {
-            buf: BitBuffer::from(vec)
+        assert_eq!(pk.magic(), PACKET_MAGIC);
+        assert_eq!(pk.version(), PACKET_PROTOCOL_VERSION);
+        assert_eq!(pk.payload_len(), 1);
+        assert_eq!(pk.packet_type(), 0x7);
+        assert_eq!(pk.timestamp(), 0xDEADBEEF);
+        assert_eq!(pk.auth_id(), 0x12345678);
+        assert_eq!(pk.sequence_number(), 0xABCD);
+        assert_eq!(pk.ack(), 0x4321);
+        assert_eq!(pk.ack_bits(), 0x8000_0001);
+        assert_eq!(pk.channel_seq(), 0x9876);
+        assert_eq!(pk.payload_buf().read_u8().unwrap(), 0xFF);
+    }
+
+    /// A valid packet with a one byte payload
+    fn packet_bytes() -> Vec<u8> {
+        let mut builder = PacketBuilder::new(0x1);
+        builder.buf_mut().write_u8(0x2A);
+        builder.finalize().as_slice().to_vec()
+    }
+
+    #[test]
+    fn wrong_magic_is_rejected() {
+        let mut bytes = packet_bytes();
+        bytes[PacketHeaderOffset::Magic as usize] ^= 0xFF;
+        let err = Packet::try_from(bytes).err().unwrap();
+        assert!(matches!(err, PacketError::BadMagic { magic } if magic != PACKET_MAGIC), "{}", err);
+        assert!(err.is_rejected());
+    }
+
+    #[test]
+    fn corrupted_packet_fails_checksum() {
+        let mut bytes = packet_bytes();
+        *bytes.last_mut().unwrap() ^= 0x1;
+        let err = Packet::try_from(bytes).err().unwrap();
+        assert!(matches!(err, PacketError::BadChecksum { .. }), "{}", err);
+        assert!(err.is_rejected());
+
+        let mut bytes = packet_bytes();
+        bytes[PacketHeaderOffset::AuthId as usize] ^= 0x1;
+        assert!(matches!(Packet::try_from(bytes), Err(PacketError::BadChecksum { .. })));
+    }
+
+    fn packet_with_payload(packet_type: u8, payload: &[u8]) -> Packet {
+        let mut builder = PacketBuilder::new(packet_type);
+        for byte in payload {
+            builder.buf_mut().write_u8(*byte);
         }
+        builder.finalize()
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let packets = [
+            packet_with_payload(0x1, &[0x2A]),
+            packet_with_payload(0x2, &[1, 2, 3, 4, 5]),
+            packet_with_payload(0x3, &[0xFF; 300]),
+        ];
+        let batch = Packet::concat_batch(&packets);
+        assert_eq!(batch.len(), packets.iter().map(|pk| pk.as_slice().len()).sum::<usize>());
+        let received = Packet::try_from_batch(batch).unwrap();
+        assert_eq!(received.len(), packets.len());
+        for (received, sent) in received.iter().zip(&packets) {
+            assert_eq!(received.packet_type(), sent.packet_type());
+            assert_eq!(received.payload_len(), sent.payload_len());
+            assert_eq!(received.checksum(), sent.checksum());
+        }
+    }
+
+    #[test]
+    fn batch_with_partial_header_is_rejected() {
+        let mut batch = Packet::concat_batch(&[packet_with_payload(0x1, &[0x2A])]);
+        batch.extend_from_slice(&packet_bytes()[..PacketHeaderOffset::Length as usize]);
+        let err = Packet::try_from_batch(batch).err().unwrap();
+        assert!(matches!(err, PacketError::TooShort { len, .. } if len == PacketHeaderOffset::Length as usize), "{}", err);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn batch_with_length_past_the_end_is_rejected() {
+        let mut batch = Packet::concat_batch(&[packet_with_payload(0x1, &[0x2A]), packet_with_payload(0x2, &[1, 2, 3])]);
+        batch.pop();
+        let err = Packet::try_from_batch(batch.clone()).err().unwrap();
+        assert!(matches!(err, PacketError::TooShort { .. }), "{}", err);
+
+        // Same if the length itself is corrupted, claiming more than is left
+        batch.push(3);
+        let last = batch.len() - (PacketHeaderOffset::Payload as usize + 3);
+        batch[last + PacketHeaderOffset::Length as usize] = 0xFF;
+        assert!(matches!(Packet::try_from_batch(batch), Err(PacketError::TooShort { .. })));
+    }
+
+    #[test]
+    fn previous_protocol_version_is_accepted() {
+        let mut builder = PacketBuilder::new(0x1)
+            .with_version(MIN_PROTOCOL_VERSION);
+        builder.buf_mut().write_u8(0x0);
+        let pk = Packet::try_from(builder.finalize().as_slice().to_vec()).unwrap();
+        assert_eq!(pk.version(), MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn other_protocol_version_is_rejected() {
+        let mut builder = PacketBuilder::new(0x1)
+            .with_version(MIN_PROTOCOL_VERSION - 1);
+        builder.buf_mut().write_u8(0x0);
+        let bytes = builder.finalize().as_slice().to_vec();
+        let err = Packet::try_from(bytes).err().unwrap();
+        assert!(matches!(err, PacketError::BadVersion { .. }));
+        assert!(err.is_rejected());
+    }
+}
@@ -0,0 +1,359 @@
+//! Optional encrypted session between a client and the server.
+//!
+//! The client offers an ephemeral x25519 public key in its Login, and a server that requires encryption
+//! answers with its own in the Login reply. Both sides derive one ChaCha20-Poly1305 key per direction from
+//! the shared secret. From then on everything after the datagram flags byte is sealed:
+//! `[flags | FLAG_ENCRYPTED][u64 counter][ciphertext + tag]`, with the counter as the nonce.
+//!
+//! The client encrypts as soon as it has the server's key. The server keeps sending in the clear
+//! until the first encrypted datagram arrives, as the Login reply (and anything batched with it) has to be readable.
+//! Each side refuses plaintext once it has received something encrypted.
+//!
+//! The keys are ephemeral and nothing signs them, so this keeps out anyone only watching (or spoofing) traffic,
+//! but not an active man in the middle: one that rewrites both Logins can swap in its own key and relay everything.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use log::trace;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey as DalekPublicKey};
+use crate::datagram::FLAG_ENCRYPTED;
+use crate::packet::PacketError;
+
+/// x25519 public key, as sent in the Login events
+pub type PublicKey = [u8; 32];
+
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+/// Bytes an encrypted datagram has over the plaintext one
+pub const SESSION_OVERHEAD: usize = COUNTER_LEN + TAG_LEN;
+/// How far behind the newest counter a datagram can arrive (reordered) and still be accepted
+const REPLAY_WINDOW: u64 = 64;
+
+const CLIENT_TO_SERVER: &[u8] = b"mp-game-test client to server";
+const SERVER_TO_CLIENT: &[u8] = b"mp-game-test server to client";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// One side's ephemeral key pair, waiting for the other side's public key
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = DalekPublicKey::from(&secret).to_bytes();
+        Self { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Completes the key exchange with the other side's public key
+    pub fn finish(self, role: Role, their_key: PublicKey) -> Session {
+        let shared = self.secret.diffie_hellman(&DalekPublicKey::from(their_key));
+        let (client_key, server_key) = match role {
+            Role::Client => (self.public_key, their_key),
+            Role::Server => (their_key, self.public_key),
+        };
+        let mut salt = [0; 64];
+        salt[..32].copy_from_slice(&client_key);
+        salt[32..].copy_from_slice(&server_key);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let derive = |info: &[u8]| {
+            let mut key = Key::default();
+            hkdf.expand(info, &mut key).expect("32 bytes is a valid hkdf output length");
+            ChaCha20Poly1305::new(&key)
+        };
+        let (seal_info, open_info) = match role {
+            Role::Client => (CLIENT_TO_SERVER, SERVER_TO_CLIENT),
+            Role::Server => (SERVER_TO_CLIENT, CLIENT_TO_SERVER),
+        };
+        Session {
+            role,
+            seal_cipher: derive(seal_info),
+            open_cipher: derive(open_info),
+            send_counter: 0,
+            replay: ReplayWindow::default(),
+            established: false,
+        }
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tracks which counters have been received, so a captured datagram can't be played back
+#[derive(Default)]
+struct ReplayWindow {
+    /// Newest counter received, 0 if none yet (counters start at 1)
+    latest: u64,
+    /// Bit n is set if `latest - n` has been received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+        if counter > self.latest {
+            return true;
+        }
+        let age = self.latest - counter;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    /// Marks a counter as received, must have passed [ReplayWindow::check]
+    fn accept(&mut self, counter: u64) {
+        if counter > self.latest {
+            let shift = counter - self.latest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.latest = counter;
+        } else {
+            self.seen |= 1 << (self.latest - counter);
+        }
+    }
+}
+
+pub struct Session {
+    role: Role,
+    seal_cipher: ChaCha20Poly1305,
+    open_cipher: ChaCha20Poly1305,
+    send_counter: u64,
+    replay: ReplayWindow,
+    /// Set once an encrypted datagram has been received, plaintext is refused after that
+    established: bool,
+}
+
+impl Session {
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[..COUNTER_LEN].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    /// Whether datagrams to the peer are encrypted yet
+    pub fn is_sealing(&self) -> bool {
+        self.role == Role::Client || self.established
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Encrypts a datagram, keeping its flags byte in the clear (and authenticated)
+    pub fn seal(&mut self, datagram: &[u8]) -> Result<Vec<u8>, PacketError> {
+        let (&flags, body) = datagram.split_first().ok_or(PacketError::TooShort { len: 0, expected: 1 })?;
+        self.send_counter += 1;
+        let mut header = [0; 1 + COUNTER_LEN];
+        header[0] = flags | FLAG_ENCRYPTED;
+        header[1..].copy_from_slice(&self.send_counter.to_le_bytes());
+        let mut buffer = Vec::with_capacity(body.len() + TAG_LEN);
+        buffer.extend_from_slice(body);
+        self.seal_cipher.encrypt_in_place(&Self::nonce(self.send_counter), &header, &mut buffer)
+            .map_err(|_| PacketError::Encrypt)?;
+        let mut out = Vec::with_capacity(header.len() + buffer.len());
+        out.extend_from_slice(&header);
+        out.append(&mut buffer);
+        Ok(out)
+    }
+
+    /// Decrypts a datagram from [Session::seal], returning it with [FLAG_ENCRYPTED] cleared
+    pub fn open(&mut self, datagram: &[u8]) -> Result<Vec<u8>, PacketError> {
+        if datagram.len() < 1 + SESSION_OVERHEAD {
+            return Err(PacketError::TooShort { len: datagram.len(), expected: 1 + SESSION_OVERHEAD });
+        }
+        let (aad, ciphertext) = datagram.split_at(1 + COUNTER_LEN);
+        let counter = u64::from_le_bytes(aad[1..].try_into().unwrap());
+        if !self.replay.check(counter) {
+            return Err(PacketError::Replayed { counter });
+        }
+        let mut buffer = ciphertext.to_vec();
+        self.open_cipher.decrypt_in_place(&Self::nonce(counter), aad, &mut buffer)
+            .map_err(|_| PacketError::Decrypt)?;
+        self.replay.accept(counter);
+        if !self.established {
+            trace!("encrypted session established");
+            self.established = true;
+        }
+        let mut out = Vec::with_capacity(1 + buffer.len());
+        out.push(aad[0] & !FLAG_ENCRYPTED);
+        out.append(&mut buffer);
+        Ok(out)
+    }
+}
+
+/// Sessions by peer address, shared between the send and receive threads
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<SocketAddr, Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets up (or replaces) the session with a peer
+    pub fn insert(&self, addr: SocketAddr, session: Session) {
+        self.sessions.lock().unwrap().insert(addr, session);
+    }
+
+    pub fn remove(&self, addr: SocketAddr) {
+        self.sessions.lock().unwrap().remove(&addr);
+    }
+
+    /// Whether a session has been set up with the peer, even if nothing encrypted has been received yet
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.sessions.lock().unwrap().contains_key(&addr)
+    }
+
+    /// Encrypts the datagram if there is a session with the peer that is sealing, otherwise returns it as is
+    pub fn seal(&self, addr: SocketAddr, datagram: Vec<u8>) -> Result<Vec<u8>, PacketError> {
+        let mut lock = self.sessions.lock().unwrap();
+        match lock.get_mut(&addr) {
+            Some(session) if session.is_sealing() => session.seal(&datagram),
+            _ => Ok(datagram),
+        }
+    }
+
+    /// Decrypts the datagram if encrypted. Plaintext is only accepted from peers that haven't sent anything encrypted yet
+    pub fn open(&self, addr: SocketAddr, datagram: &[u8]) -> Result<Option<Vec<u8>>, PacketError> {
+        let flags = datagram.first().copied().unwrap_or(0);
+        let mut lock = self.sessions.lock().unwrap();
+        let session = lock.get_mut(&addr);
+        if flags & FLAG_ENCRYPTED == 0 {
+            return match session {
+                Some(session) if session.is_established() => Err(PacketError::Unencrypted),
+                _ => Ok(None),
+            };
+        }
+        match session {
+            Some(session) => session.open(datagram).map(Some),
+            None => Err(PacketError::NoSession),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (client, server) sessions from the same key exchange
+    fn pair() -> (Session, Session) {
+        let (client, server) = (Handshake::new(), Handshake::new());
+        let (client_key, server_key) = (client.public_key(), server.public_key());
+        (client.finish(Role::Client, server_key), server.finish(Role::Server, client_key))
+    }
+
+    #[test]
+    fn sealed_datagrams_open_on_the_other_side() {
+        let (mut client, mut server) = pair();
+        let datagram = [0x2, 1, 2, 3, 4];
+        let sealed = client.seal(&datagram).unwrap();
+        assert_eq!(sealed.len(), datagram.len() + SESSION_OVERHEAD);
+        assert_eq!(sealed[0], 0x2 | FLAG_ENCRYPTED);
+        assert_ne!(&sealed[1 + COUNTER_LEN..][..4], &datagram[1..]);
+        assert_eq!(server.open(&sealed).unwrap(), datagram);
+        assert!(server.is_established());
+
+        let reply = server.seal(&[0, 5, 6]).unwrap();
+        assert_eq!(client.open(&reply).unwrap(), [0, 5, 6]);
+    }
+
+    #[test]
+    fn tampered_datagrams_fail_to_decrypt() {
+        let (mut client, mut server) = pair();
+        let sealed = client.seal(&[0, 1, 2, 3, 4]).unwrap();
+        // Flags, ciphertext and tag are all covered
+        for i in [0, 1 + COUNTER_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x40;
+            assert!(matches!(server.open(&tampered), Err(PacketError::Decrypt)), "byte {}", i);
+        }
+        // Failed attempts don't use up the counter
+        assert!(server.open(&sealed).is_ok());
+        // Nor can a session from another key exchange open it
+        let (mut other_client, _) = pair();
+        let (_, mut other_server) = pair();
+        assert!(matches!(other_server.open(&other_client.seal(&[0, 1]).unwrap()), Err(PacketError::Decrypt)));
+    }
+
+    #[test]
+    fn replayed_datagrams_are_rejected() {
+        let (mut client, mut server) = pair();
+        let sealed = client.seal(&[0, 1]).unwrap();
+        assert!(server.open(&sealed).is_ok());
+        assert!(matches!(server.open(&sealed), Err(PacketError::Replayed { counter: 1 })));
+    }
+
+    #[test]
+    fn reordered_datagrams_are_accepted_within_the_window() {
+        let (mut client, mut server) = pair();
+        let sealed: Vec<Vec<u8>> = (0..REPLAY_WINDOW + 2).map(|_| client.seal(&[0, 1]).unwrap()).collect();
+        // Counter 66 arrives first, leaving 2 (age 64) just outside the window and 3 (age 63) inside
+        assert!(server.open(sealed.last().unwrap()).is_ok());
+        assert!(matches!(server.open(&sealed[1]), Err(PacketError::Replayed { counter: 2 })));
+        assert!(server.open(&sealed[2]).is_ok());
+        assert!(matches!(server.open(&sealed[2]), Err(PacketError::Replayed { counter: 3 })));
+        assert!(server.open(&sealed[40]).is_ok());
+    }
+
+    #[test]
+    fn window_moves_with_the_newest_counter() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.check(0));
+        for counter in [1, 2, 4] {
+            assert!(window.check(counter));
+            window.accept(counter);
+        }
+        assert!(window.check(3));
+        assert!(!window.check(2));
+
+        // A jump past the window forgets everything before it
+        window.accept(1000);
+        assert_eq!(window.seen, 1);
+        assert!(!window.check(4));
+        assert!(!window.check(1000 - REPLAY_WINDOW));
+        assert!(window.check(1000 - REPLAY_WINDOW + 1));
+        assert!(!window.check(1000));
+
+        // A smaller one shifts what was seen along with it
+        window.accept(1010);
+        assert!(!window.check(1000));
+        assert!(window.check(1005));
+    }
+
+    #[test]
+    fn plaintext_is_refused_once_established() {
+        let addr = "10.0.0.1:5000".parse().unwrap();
+        let (mut client, server) = pair();
+        let sessions = Sessions::new();
+        assert!(matches!(sessions.open(addr, &[0, 1]), Ok(None)));
+        assert!(matches!(sessions.open(addr, &client.seal(&[0, 1]).unwrap()), Err(PacketError::NoSession)));
+
+        sessions.insert(addr, server);
+        // The Login reply goes out before the client's key is confirmed, so plaintext still works both ways
+        assert!(matches!(sessions.open(addr, &[0, 1]), Ok(None)));
+        assert_eq!(sessions.seal(addr, vec![0, 1]).unwrap(), [0, 1]);
+
+        assert_eq!(sessions.open(addr, &client.seal(&[0, 2]).unwrap()).unwrap(), Some(vec![0, 2]));
+        assert!(matches!(sessions.open(addr, &[0, 1]), Err(PacketError::Unencrypted)));
+        assert_eq!(sessions.seal(addr, vec![0, 1]).unwrap()[0], FLAG_ENCRYPTED);
+    }
+}
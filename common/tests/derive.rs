@@ -0,0 +1,6 @@
+//! Checks `#[derive(PacketSerialize)]` rejects bad events at compile time
+
+#[test]
+fn bad_events_do_not_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,11 @@
+use mp_game_test_common::PacketSerialize;
+
+#[derive(Debug, PacketSerialize)]
+enum Event {
+    #[packet(id = 0x1)]
+    Jump { height: u8 },
+    #[packet(id = 0x1)]
+    Crouch { toggled: bool },
+}
+
+fn main() {}
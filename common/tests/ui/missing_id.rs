@@ -0,0 +1,9 @@
+use mp_game_test_common::PacketSerialize;
+
+#[derive(Debug, PacketSerialize)]
+enum Event {
+    #[packet(channel = ReliableOrdered)]
+    Jump { height: u8 },
+}
+
+fn main() {}
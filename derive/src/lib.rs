@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitInt, Path, Variant};
+
+/// Derives `PacketSerialize` for an event enum.
+///
+/// Every variant needs a `#[packet(id = 0x1)]` attribute with its packet type.
+/// It can also pick how it's delivered with `#[packet(id = 0x1, channel = ReliableOrdered)]`,
+/// any `network::Channel` variant, defaults to `Unreliable`.
+/// Fields are written in declaration order using their `PacketField` impl, which can be overridden per field:
+/// - `#[packet(varint)]` writes a u32 as a LEB128 varint
+/// - `#[packet(with = path::to::module)]` uses `module::write(&value, buf)` and `module::read(buf)`
+///
+/// Also generates `get_packet_type()` and a `PACKET_TYPES` table of (id, variant name).
+#[proc_macro_derive(PacketSerialize, attributes(packet))]
+pub fn derive_packet_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into()
+    }
+}
+
+struct VariantInfo<'a> {
+    variant: &'a Variant,
+    id: u8,
+    channel: Option<Ident>,
+}
+
+enum FieldCodec {
+    /// Uses the type's PacketField impl
+    Default,
+    VarInt,
+    With(Path),
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(syn::Error::new_spanned(&input.ident, "PacketSerialize can only be derived for enums"))
+    };
+
+    let mut variants = Vec::with_capacity(data.variants.len());
+    let mut seen_ids: HashMap<u8, &syn::Ident> = HashMap::new();
+    for variant in &data.variants {
+        let (id, channel) = parse_variant_attrs(variant)?;
+        if let Some(other) = seen_ids.insert(id, &variant.ident) {
+            return Err(syn::Error::new_spanned(variant, format!("packet id 0x{:X} is already used by {}", id, other)));
+        }
+        variants.push(VariantInfo { variant, id, channel });
+    }
+
+    let common = quote!(::mp_game_test_common);
+
+    let type_arms = variants.iter().map(|info| {
+        let ident = &info.variant.ident;
+        let id = info.id;
+        quote!(#name::#ident { .. } => #id)
+    });
+    let channel_arms = variants.iter().map(|info| {
+        let ident = &info.variant.ident;
+        let channel = info.channel.clone().unwrap_or_else(|| format_ident!("Unreliable"));
+        quote!(#name::#ident { .. } => #common::network::Channel::#channel)
+    });
+    let table = variants.iter().map(|info| {
+        let id = info.id;
+        let ident = info.variant.ident.to_string();
+        quote!((#id, #ident))
+    });
+
+    let mut write_arms = Vec::with_capacity(variants.len());
+    let mut read_arms = Vec::with_capacity(variants.len());
+    for info in &variants {
+        let ident = &info.variant.ident;
+        let id = info.id;
+        let trace_msg = format!("reading 0x{:X}: {}::{}", id, name, ident);
+
+        let mut bindings = Vec::new();
+        let mut writes = Vec::new();
+        let mut reads = Vec::new();
+        for (i, field) in info.variant.fields.iter().enumerate() {
+            let binding = field.ident.clone().unwrap_or_else(|| format_ident!("field_{}", i));
+            let ty = &field.ty;
+            match parse_field_codec(field)? {
+                FieldCodec::Default => {
+                    writes.push(quote!(#common::codec::PacketField::write_field(#binding, buf);));
+                    reads.push(quote!(<#ty as #common::codec::PacketField>::read_field(buf)?));
+                }
+                FieldCodec::VarInt => {
+                    writes.push(quote!(#common::codec::varint::write(#binding, buf);));
+                    reads.push(quote!(#common::codec::varint::read(buf)?));
+                }
+                FieldCodec::With(path) => {
+                    writes.push(quote!(#path::write(#binding, buf);));
+                    reads.push(quote!(#path::read(buf)?));
+                }
+            }
+            bindings.push(binding);
+        }
+
+        match &info.variant.fields {
+            Fields::Named(_) => {
+                write_arms.push(quote!(#name::#ident { #(#bindings),* } => { #(#writes)* }));
+                read_arms.push(quote!(#id => {
+                    #common::__private::log::trace!(#trace_msg);
+                    Ok(#name::#ident { #(#bindings: #reads),* })
+                }));
+            }
+            Fields::Unnamed(_) => {
+                write_arms.push(quote!(#name::#ident ( #(#bindings),* ) => { #(#writes)* }));
+                read_arms.push(quote!(#id => {
+                    #common::__private::log::trace!(#trace_msg);
+                    Ok(#name::#ident ( #(#reads),* ))
+                }));
+            }
+            Fields::Unit => {
+                write_arms.push(quote!(#name::#ident => {}));
+                read_arms.push(quote!(#id => {
+                    #common::__private::log::trace!(#trace_msg);
+                    Ok(#name::#ident)
+                }));
+            }
+        }
+    }
+
+    Ok(quote! {
+        impl #name {
+            /// Packet type ids and their variant names
+            pub const PACKET_TYPES: &'static [(u8, &'static str)] = &[#(#table),*];
+
+            pub fn get_packet_type(&self) -> u8 {
+                match self {
+                    #(#type_arms),*
+                }
+            }
+        }
+
+        impl #common::PacketSerialize for #name {
+            fn to_packet_builder(&self) -> #common::packet::PacketBuilder {
+                let mut pk = #common::packet::PacketBuilder::new(self.get_packet_type());
+                let buf = pk.buf_mut();
+                match self {
+                    #(#write_arms)*
+                }
+                pk
+            }
+
+            fn channel(&self) -> #common::network::Channel {
+                match self {
+                    #(#channel_arms),*
+                }
+            }
+
+            fn from_packet(packet: &#common::packet::Packet) -> Result<Self, #common::buffer::DecodeError> {
+                let mut payload = packet.payload_buf();
+                let buf = &mut payload;
+                match packet.packet_type() {
+                    #(#read_arms)*
+                    packet_type => Err(#common::buffer::DecodeError::UnknownPacketType {
+                        packet_type,
+                        payload_len: packet.payload_len()
+                    })
+                }
+            }
+        }
+    })
+}
+
+fn parse_variant_attrs(variant: &Variant) -> syn::Result<(u8, Option<Ident>)> {
+    let mut id = None;
+    let mut channel = None;
+    for attr in variant.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit: LitInt = meta.value()?.parse()?;
+                id = Some(lit.base10_parse::<u8>()?);
+                Ok(())
+            } else if meta.path.is_ident("channel") {
+                channel = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown packet attribute, expected `id` or `channel`"))
+            }
+        })?;
+    }
+    let id = id.ok_or_else(|| syn::Error::new_spanned(&variant.ident, "missing #[packet(id = ...)] attribute"))?;
+    Ok((id, channel))
+}
+
+fn parse_field_codec(field: &syn::Field) -> syn::Result<FieldCodec> {
+    let mut codec = FieldCodec::Default;
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("varint") {
+                codec = FieldCodec::VarInt;
+                Ok(())
+            } else if meta.path.is_ident("with") {
+                codec = FieldCodec::With(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown packet attribute, expected `varint` or `with`"))
+            }
+        })?;
+    }
+    Ok(codec)
+}
@@ -0,0 +1,100 @@
+//! Stateless login challenge. A Login is only processed once the client echoes a cookie we sent to its address,
+//! so spoofed source addresses can't take up player slots or get reliable packets sent to them.
+//! Cookies are an expiry time and a MAC over it and the address, so nothing is stored until the client proves itself.
+use std::net::{IpAddr, SocketAddr};
+use hmac::{Hmac, Mac};
+use rand::random;
+use sha2::Sha256;
+use mp_game_test_common::network::{Cookie, COOKIE_LEN};
+
+/// How long a cookie can be echoed back, in seconds
+pub const COOKIE_LIFETIME: u32 = 10;
+
+const EXPIRES_LEN: usize = 4;
+const MAC_LEN: usize = COOKIE_LEN - EXPIRES_LEN;
+
+#[derive(Clone)]
+pub struct Challenger {
+    /// Random per server run, so cookies don't outlive the server
+    secret: [u8; 32],
+}
+
+impl Challenger {
+    pub fn new() -> Self {
+        Self { secret: random() }
+    }
+
+    fn mac(&self, addr: SocketAddr, expires: u32) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_le_bytes());
+        mac.update(&expires.to_le_bytes());
+        mac
+    }
+
+    /// Creates a cookie for the address, valid for [COOKIE_LIFETIME] seconds from now
+    pub fn issue(&self, addr: SocketAddr, now: u32) -> Cookie {
+        let expires = now + COOKIE_LIFETIME;
+        let tag = self.mac(addr, expires).finalize().into_bytes();
+        let mut cookie = [0; COOKIE_LEN];
+        cookie[..EXPIRES_LEN].copy_from_slice(&expires.to_le_bytes());
+        cookie[EXPIRES_LEN..].copy_from_slice(&tag[..MAC_LEN]);
+        cookie
+    }
+
+    /// Checks the cookie was issued by us, for this address, and hasn't expired
+    pub fn verify(&self, addr: SocketAddr, cookie: &Cookie, now: u32) -> bool {
+        let expires = u32::from_le_bytes(cookie[..EXPIRES_LEN].try_into().unwrap());
+        if expires < now || expires > now + COOKIE_LIFETIME {
+            return false;
+        }
+        self.mac(addr, expires).verify_truncated_left(&cookie[EXPIRES_LEN..]).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn issued_cookie_verifies() {
+        let challenger = Challenger::new();
+        let cookie = challenger.issue(addr("10.0.0.1:5000"), 1000);
+        assert!(challenger.verify(addr("10.0.0.1:5000"), &cookie, 1000));
+        assert!(challenger.verify(addr("10.0.0.1:5000"), &cookie, 1000 + COOKIE_LIFETIME));
+    }
+
+    #[test]
+    fn cookie_is_bound_to_address() {
+        let challenger = Challenger::new();
+        let cookie = challenger.issue(addr("10.0.0.1:5000"), 1000);
+        assert!(!challenger.verify(addr("10.0.0.2:5000"), &cookie, 1000));
+        assert!(!challenger.verify(addr("10.0.0.1:5001"), &cookie, 1000));
+    }
+
+    #[test]
+    fn expired_cookie_is_rejected() {
+        let challenger = Challenger::new();
+        let cookie = challenger.issue(addr("10.0.0.1:5000"), 1000);
+        assert!(!challenger.verify(addr("10.0.0.1:5000"), &cookie, 1001 + COOKIE_LIFETIME));
+    }
+
+    #[test]
+    fn tampered_cookie_is_rejected() {
+        let challenger = Challenger::new();
+        let mut cookie = challenger.issue(addr("10.0.0.1:5000"), 1000);
+        // Pushing the expiry out invalidates the mac
+        cookie[..EXPIRES_LEN].copy_from_slice(&1005u32.to_le_bytes());
+        assert!(!challenger.verify(addr("10.0.0.1:5000"), &cookie, 1000));
+
+        let cookie = challenger.issue(addr("10.0.0.1:5000"), 1000);
+        assert!(!Challenger::new().verify(addr("10.0.0.1:5000"), &cookie, 1000));
+    }
+}
@@ -1,3 +1,4 @@
+use mp_game_test_common::network::Network;
 use crate::cmds::{CommandArgs, ServerCommand};
 use crate::game::GameInstance;
 
@@ -8,12 +9,30 @@ impl ServerCommand for Command {
         let net_stat = game.net.stat();
         let activity_time = net_stat.activity_time_as_secs_f32();
         let pk_count = net_stat.pk_count();
-        println!("pks rate in={}/s out={}/s", pk_count.rx, pk_count.rx);
+        let byte_count = net_stat.byte_count();
+        println!("pks rate in={}/s out={}/s", pk_count.rx, pk_count.tx);
+        println!("bytes in={} out={}", byte_count.rx, byte_count.tx);
+        println!("pks dropped (malformed) = {}\t\tpks rejected (magic/checksum) = {}", net_stat.dropped_count(), net_stat.rejected_count());
+        println!("pks over tick budget = {}", net_stat.over_budget_count());
         println!("net activity in[{}s ago] out[{}s ago]",
                  activity_time.rx.unwrap_or("Never".to_string()),
                  activity_time.tx.unwrap_or("Never".to_string()),
         );
         println!("in sleep = {}\t\tuptime = {:.2} min", game.in_sleep(), game.uptime().as_secs_f64() / 60.0);
+        println!(
+            "{0: <6} | {1: <12} | {2: <12} | {3: <8} | {4: <8} | {5: <8} | {6: <6} | {7: <6} | {8: <9} | {9: <8}",
+            "index", "bytes in", "bytes out", "loss in", "loss out", "jitter", "ooo", "dup", "congested", "snapshot"
+        );
+        game.for_all_clients(|index, client| {
+            let peer = game.net.stat().peer_stat(client.addr);
+            let link = &peer.link;
+            println!(
+                "{0: <6} | {1: <12} | {2: <12} | {3: <8} | {4: <8} | {5: <8} | {6: <6} | {7: <6} | {8: <9} | every {9} ticks",
+                index, peer.bytes.rx, peer.bytes.tx,
+                format!("{:.1}%", link.loss_in()), format!("{:.1}%", link.loss_out()), format!("{} ms", link.jitter().as_millis()),
+                link.out_of_order(), link.duplicates(), client.send_rate.is_congested(), client.send_rate.interval()
+            );
+        });
         true
     }
 }
\ No newline at end of file
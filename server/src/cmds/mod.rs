@@ -57,10 +57,12 @@ mod help;
 mod status;
 mod exit;
 mod debug;
+mod netsim;
 
 pub fn register_commands(game: &mut GameInstance) {
     game.reg_cmd("help", Box::new(HelpCommand::default()), CmdFlag::empty());
     game.reg_cmd("status", Box::new(StatusCommand::default()), CmdFlag::ClientCanExecute);
     game.reg_cmd("exit", Box::new(ExitCommand::default()), CmdFlag::empty());
     game.reg_cmd("debug", Box::new(debug::Command::default()), CmdFlag::ClientCanExecute);
+    game.reg_cmd("netsim", Box::new(netsim::Command::default()), CmdFlag::empty());
 }
\ No newline at end of file
@@ -0,0 +1,53 @@
+use std::time::Duration;
+use mp_game_test_common::netsim::{parse_percent, LinkConditions};
+use mp_game_test_common::network::Network;
+use crate::cmds::{CommandArgs, ServerCommand};
+use crate::game::GameInstance;
+
+/// Shows or changes the simulated conditions on everything the server sends:
+/// `netsim`, `netsim off` or `netsim <latency|jitter|loss|duplicate|reorder> <value>`
+#[derive(Default)]
+pub struct Command {}
+impl ServerCommand for Command {
+    fn run(&self, game: &mut GameInstance, _client_index: u32, command: CommandArgs) -> bool {
+        let mut conditions = game.net.link_conditions();
+        match (command.get_arg_str(0), command.get_arg_str(1)) {
+            (None, _) => {}
+            (Some("off"), None) => conditions = LinkConditions::default(),
+            (Some(name @ ("latency" | "jitter")), Some(value)) => {
+                let Ok(ms) = value.parse::<u64>() else {
+                    println!("{} is in ms, got \"{}\"", name, value);
+                    return false;
+                };
+                if name == "latency" {
+                    conditions.latency = Duration::from_millis(ms);
+                } else {
+                    conditions.jitter = Duration::from_millis(ms);
+                }
+            }
+            (Some(name @ ("loss" | "duplicate" | "reorder")), Some(value)) => {
+                let percent = match parse_percent(value) {
+                    Ok(percent) => percent,
+                    Err(e) => {
+                        println!("{}: {}", name, e);
+                        return false;
+                    }
+                };
+                match name {
+                    "loss" => conditions.loss = percent,
+                    "duplicate" => conditions.duplicate = percent,
+                    _ => conditions.reorder = percent,
+                }
+            }
+            _ => {
+                println!("usage: netsim [off | <latency|jitter> <ms> | <loss|duplicate|reorder> <percent>]");
+                return false;
+            }
+        }
+        if command.args() > 0 {
+            game.net.set_link_conditions(conditions.clone());
+        }
+        println!("netsim: {}", conditions);
+        true
+    }
+}
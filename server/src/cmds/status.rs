@@ -5,14 +5,17 @@ use crate::game::GameInstance;
 pub struct StatusCommand {}
 impl ServerCommand for StatusCommand {
     fn run(&self, game: &mut GameInstance, client_index: u32, command: CommandArgs) -> bool {
+        let login_config = game.login_config();
+        println!("players: {}/{} ({} reserved)", game.player_count(), login_config.max_players, login_config.reserved_slots);
         println!(
-            "{0: <6} | {1: <11} | {2: <32}",
-            "index", "auth_id", "name"
+            "{0: <6} | {1: <11} | {2: <8} | {3: <32}",
+            "index", "auth_id", "ping", "name"
         );
         game.for_all_players(|index, client, player| {
+            let ping = game.ping(client).map(|ping| format!("{} ms", ping.as_millis())).unwrap_or("-".to_string());
             println!(
-                "{0: <6} | {1: <11} | {2: <32}",
-               index, client.auth_id, player.name
+                "{0: <6} | {1: <11} | {2: <8} | {3: <32}",
+               index, client.auth_id, ping, player.name
             );
         });
         true
@@ -1,7 +1,7 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::f32::consts::PI;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{atomic, Arc};
 use std::sync::atomic::AtomicBool;
 use std::thread::sleep;
@@ -13,14 +13,15 @@ use tokio::net::UdpSocket;
 use tokio::time::{interval, Interval};
 use mp_game_test_common::events_client::ClientEvent;
 use mp_game_test_common::game::{Action, CommonGameInstance, PlayerData};
-use mp_game_test_common::events_server::ServerEvent;
+use mp_game_test_common::events_server::{LoginRejectReason, ServerEvent};
 use mp_game_test_common::packet::{Packet, PacketBuilder};
-use mp_game_test_common::{unix_timestamp, PacketSerialize, PACKET_PROTOCOL_VERSION};
+use mp_game_test_common::{compat, unix_timestamp, PacketSerialize, MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION};
 use mp_game_test_common::def::{Vector3, MAX_PLAYERS};
 use mp_game_test_common::events_server::ServerEvent::Disconnect;
-use mp_game_test_common::network::Network;
+use mp_game_test_common::network::{NetConfig, Network, SendRateControl};
+use mp_game_test_common::session::PublicKey;
 use crate::cmds::{CmdFlag, CommandArgs, ServerCommand};
-use crate::network::{NetServer, OutPacket};
+use crate::network::NetServer;
 use crate::TICK_RATE;
 
 /// How long of no packets from client do we consider them timed out?
@@ -29,14 +30,41 @@ static CLIENT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 /// How long to sleep when we are in sleep mode
 static SLEEP_INTERVAL: Duration = Duration::from_millis(1000);
 
+/// Who can log in, and how many players at once
+#[derive(Debug, Clone)]
+pub struct LoginConfig {
+    /// Most players at once, up to [MAX_PLAYERS]
+    pub max_players: usize,
+    /// Slots (out of max_players) only players in reserved_names can take
+    pub reserved_slots: usize,
+    pub reserved_names: Vec<String>,
+    pub banned_ips: Vec<IpAddr>,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            max_players: MAX_PLAYERS,
+            reserved_slots: 0,
+            reserved_names: Vec::new(),
+            banned_ips: Vec::new(),
+        }
+    }
+}
 
 pub(crate) struct ClientData {
     pub(crate) auth_id: u32,
-    addr: SocketAddr,
+    pub(crate) addr: SocketAddr,
+    /// How often the client is sent snapshots, lowered when its connection can't keep up
+    pub(crate) send_rate: SendRateControl,
+    /// Players that moved since the client's last snapshot, by client index
+    pending_moves: HashSet<u32>,
     last_timestamp: u32,
     seq_number: u16,
     reliable_queue: VecDeque<ReliableEntry>,
     last_packet_time: Instant,
+    /// Our key from the Login reply, if the client has an encrypted session
+    server_key: Option<PublicKey>,
 }
 #[derive(Clone)]
 struct ReliableEntry {
@@ -60,10 +88,13 @@ impl ClientData {
         ClientData {
             auth_id,
             addr,
+            send_rate: SendRateControl::default(),
+            pending_moves: HashSet::new(),
             last_timestamp: unix_timestamp(),
             seq_number: 0,
             reliable_queue: VecDeque::new(),
-            last_packet_time: Instant::now()
+            last_packet_time: Instant::now(),
+            server_key: None,
         }
     }
     pub fn mark(&mut self) {
@@ -76,7 +107,10 @@ impl ClientData {
 pub struct GameInstance {
     pub net: NetServer,
     game: CommonGameInstance,
+    login_config: LoginConfig,
     client_data: [Option<ClientData>; MAX_PLAYERS],
+    /// Addresses whose login we rejected, forgotten once they ack the rejection or are given up on
+    rejected_peers: HashSet<SocketAddr>,
 
     tick_rate: u8,
     active_tick_interval: Interval,
@@ -93,15 +127,23 @@ pub struct GameInstance {
 }
 
 impl GameInstance {
-    pub fn new(tick_rate: u8) -> Self {
+    pub fn new(tick_rate: u8, net_config: NetConfig, login_config: LoginConfig) -> Self {
+        // TODO: make socket
+        Self::with_net(tick_rate, NetServer::new("0.0.0.0:3566".parse().unwrap(), net_config), login_config)
+    }
+
+    /// Runs the game on an already set up server, such as one over a [MemoryNetwork](mp_game_test_common::network::MemoryNetwork) in tests
+    pub(crate) fn with_net(tick_rate: u8, net: NetServer, login_config: LoginConfig) -> Self {
+        assert!(login_config.max_players <= MAX_PLAYERS, "max players can be at most {}", MAX_PLAYERS);
         let ms_per_tick = 1000 / TICK_RATE as u16;
         debug!("tickrate={} ms per tick={}", TICK_RATE, ms_per_tick);
         let mut per_tick_duration = Duration::from_millis(ms_per_tick as u64);
         Self {
-            // TODO: make socket
-            net: NetServer::new("0.0.0.0:3566".parse().unwrap()),
+            net,
             game: CommonGameInstance::new(),
+            login_config,
             client_data: [const { None }; MAX_PLAYERS],
+            rejected_peers: HashSet::new(),
 
             tick_rate,
             active_tick_interval: interval(per_tick_duration),
@@ -152,12 +194,14 @@ impl GameInstance {
 
     /// Returns an instance of a command by name or alias
     pub fn get_cmd(&self, cmd_name: &str) -> Option<Arc<Box<dyn ServerCommand>>> {
+        self.get_cmd_entry(cmd_name).map(|e| e.command.clone())
+    }
+
+    fn get_cmd_entry(&self, cmd_name: &str) -> Option<&CommandContainer> {
         self.cmds.get(cmd_name)
-            .map(|e| e.command.clone())
             // If not found, check the aliases:
             .or_else(|| self.cmd_aliases.get(cmd_name)
                 .and_then(|cmd_name| self.cmds.get(cmd_name))
-                .map(|e| e.command.clone())
             )
     }
 
@@ -194,6 +238,8 @@ impl GameInstance {
             self.active_tick_interval.tick().await;
             self.process().await;
         }
+        // Send everything from this tick, batched per client
+        self.net.flush().ok();
     }
 
     /// If we are in sleep mode, sleeps for sleep interval.
@@ -218,7 +264,17 @@ impl GameInstance {
             debug!("got event, processing: {:?}", event);
             self.process_event(addr, &pk, event).await;
         }
+        for addr in self.net.take_unresponsive() {
+            if self.get_client_index(&ClientId::Addr(addr)).is_some() {
+                self.disconnect_player(&ClientId::Addr(addr), "Connection lost".to_string()).ok();
+            } else {
+                self.rejected_peers.remove(&addr);
+                self.net.forget_peer(addr).ok();
+            }
+        }
+        self.forget_rejected_peers();
         let mut client_count = 0;
+        let mut moved = Vec::new();
         for i in 0..MAX_PLAYERS {
             if let Some(client) = &mut self.client_data[i] {
                 if client.has_timed_out() {
@@ -231,23 +287,20 @@ impl GameInstance {
                 client_count += 1;
                 // If change made, update:
                 if player.process_actions() {
-                    trace!("change made, sending update");
-                    let move_event = ServerEvent::Move {
-                        client_index: player.client_index,
-                        position: player.position,
-                        angles: player.angles,
-                        velocity: Vector3::zero()
-                    };
-                    self.broadcast(move_event);
+                    trace!("change made, queuing update");
+                    moved.push(player.client_index);
                 }
 
             }
         }
+        self.send_snapshots(&moved);
         self.tick_count += 1;
         if self.tick_count == self.tick_rate {
             let pk_count = self.net.pks_per_interval();
             // debug!("tick summary. ticks={} pk_in={}/s pk_out={}/s clients={}", self.tick_count, pk_count.rx, pk_count.tx, client_count);
             self.tick_count = 0;
+            self.send_pings();
+            self.update_send_rates();
             // If we haven't seen any network activity then we can sleep
             if !self.net.stat().has_activity_within(Duration::from_millis(30_000)) && self.game.player_count() == 0 {
                 debug!("no net activity in 30s and no players, sleeping");
@@ -256,14 +309,15 @@ impl GameInstance {
         }
     }
 
-    pub fn disconnect_player(&mut self, client_id: &ClientId, reason: String) -> Result<u16, Error> {
+    pub fn disconnect_player(&mut self, client_id: &ClientId, reason: String) -> Result<(), Error> {
         if let Some(client_index) = self.get_client_index(client_id) {
             let event = ServerEvent::Disconnect {
                 client_index,
                 reason,
             };
+            let result = self.send_to_client(&event, client_id);
             self.remove_player(client_id);
-            return self.send_to_reliable(event, client_id)
+            return result
         }
         Err(anyhow!("Client does not exist"))
     }
@@ -271,6 +325,9 @@ impl GameInstance {
     pub fn remove_player(&mut self, client_id: &ClientId) {
         if let Some(index) = self.get_client_index(client_id) {
             debug!("disconnecting client index {}.", index);
+            if let Some(client) = &self.client_data[index as usize] {
+                self.net.forget_peer(client.addr).ok();
+            }
             self.client_data[index as usize] = None;
             self.game.players[index as usize] = None;
             // TODO: send disconnect packet
@@ -281,8 +338,9 @@ impl GameInstance {
             self.set_sleep(true);
         }
     }
-    pub fn setup_player(&mut self, addr: SocketAddr, name: String) -> (u32, u32) {
-        let client_index = self.game.get_empty_slot().expect("no available player slot");
+    /// Sets up a player in the first free slot, returning (client_index, auth_id). None if every slot is taken
+    pub fn setup_player(&mut self, addr: SocketAddr, name: String) -> Option<(u32, u32)> {
+        let client_index = self.game.get_empty_slot()?;
         // Generate an unique auth id that should be hard to guess
         let auth_id: u32 = random();
         trace!("auth_id={} for new client (id={}) (ip={:?}) (name={})", auth_id, client_index, addr, name);
@@ -296,7 +354,108 @@ impl GameInstance {
 
         self.set_sleep(false); // in case we are sleeping (unlikely), unsleep
 
-        (client_index, auth_id)
+        Some((client_index, auth_id))
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.game.player_count()
+    }
+
+    pub fn login_config(&self) -> &LoginConfig {
+        &self.login_config
+    }
+
+    /// Checks whether a new player can log in, before anything is set up for them
+    fn check_login(&self, addr: SocketAddr, version: u16, name: &str, client_key: Option<PublicKey>) -> Result<(), LoginRejectReason> {
+        if !compat::is_supported(version) {
+            return Err(LoginRejectReason::BadVersion);
+        }
+        if self.login_config.banned_ips.contains(&addr.ip()) {
+            return Err(LoginRejectReason::Banned);
+        }
+        if self.net.is_secure() && client_key.is_none() {
+            return Err(LoginRejectReason::EncryptionRequired);
+        }
+        let mut players = 0;
+        for player in self.game.players.iter().flatten() {
+            if player.name == name {
+                return Err(LoginRejectReason::NameTaken);
+            }
+            players += 1;
+        }
+        // Everyone can take the open slots, only reserved names can take the last reserved_slots
+        let reserved = self.login_config.reserved_names.iter().any(|reserved| reserved == name);
+        let max_players = if reserved {
+            self.login_config.max_players
+        } else {
+            self.login_config.max_players.saturating_sub(self.login_config.reserved_slots)
+        };
+        if players >= max_players {
+            return Err(LoginRejectReason::ServerFull);
+        }
+        Ok(())
+    }
+
+    /// Forgets the peers we rejected that have acked the rejection, it keeps being resent to the rest
+    fn forget_rejected_peers(&mut self) {
+        let net = &self.net;
+        self.rejected_peers.retain(|addr| {
+            if net.has_unacked(*addr) {
+                return true;
+            }
+            trace!("{} acked its login rejection, forgetting it", addr);
+            net.forget_peer(*addr).ok();
+            false
+        });
+    }
+
+    /// Smoothed round trip time to the client, None until it has acked something
+    pub fn ping(&self, client: &ClientData) -> Option<Duration> {
+        self.net.rtt(client.addr).and_then(|rtt| rtt.srtt())
+    }
+
+    /// Sends each client the players that moved since its last snapshot, as often as its connection keeps up with
+    fn send_snapshots(&mut self, moved: &[u32]) {
+        for client in self.client_data.iter_mut().flatten() {
+            client.send_rate.tick();
+            client.pending_moves.extend(moved);
+            if client.pending_moves.is_empty() || !client.send_rate.take_due() {
+                continue;
+            }
+            for index in client.pending_moves.drain() {
+                if let Some(player) = &self.game.players[index as usize] {
+                    let move_event = ServerEvent::Move {
+                        client_index: player.client_index,
+                        position: player.position,
+                        angles: player.angles,
+                        velocity: Vector3::zero()
+                    };
+                    self.net.send_to(&move_event, client.addr).ok();
+                }
+            }
+        }
+    }
+
+    /// Backs off the snapshot rate of clients that are losing packets or queueing them up
+    fn update_send_rates(&mut self) {
+        for client in self.client_data.iter_mut().flatten() {
+            let srtt = self.net.rtt(client.addr).and_then(|rtt| rtt.srtt());
+            let was_congested = client.send_rate.is_congested();
+            client.send_rate.update(srtt, self.net.resent_count(client.addr));
+            if client.send_rate.is_congested() != was_congested {
+                debug!("client {} congested={} snapshot every {} ticks", client.auth_id, client.send_rate.is_congested(), client.send_rate.interval());
+            }
+        }
+    }
+
+    /// Tells every client its ping, for them to show
+    fn send_pings(&self) {
+        for client in self.client_data.iter().flatten() {
+            if let Some(ping) = self.ping(client) {
+                let rtt_ms = ping.as_millis().min(u16::MAX as u128) as u16;
+                self.send_to(&ServerEvent::Ping { rtt_ms }, client.addr);
+            }
+        }
     }
 
     pub fn for_all_clients<F>(&self, func: F) where F: Fn(u32, &ClientData) {
@@ -381,10 +540,15 @@ impl GameInstance {
         // None
     }
 
+    /// Runs a command from the console (client_index None) or a client. Clients can only run commands flagged [CmdFlag::ClientCanExecute]
     pub fn exec_cmd(&mut self, command: &str, client_index: Option<u32>) -> Result<(), String> {
         let args = CommandArgs::from_line(command);
-        match self.get_cmd(args.name()) {
-            Some(cmd) => {
+        match self.get_cmd_entry(args.name()) {
+            Some(entry) if client_index.is_some() && !entry.flags.contains(CmdFlag::ClientCanExecute) => {
+                Err(format!("Command \"{}\" can't be run by clients", args.name()))
+            },
+            Some(entry) => {
+                let cmd = entry.command.clone();
                 cmd.run(self, client_index.unwrap_or(0), args).then(|| ()).ok_or("Command failed".to_string())
             },
             None => Err(format!("Unknown command: \"{}\"", command))
@@ -425,78 +589,95 @@ impl GameInstance {
             self.disconnect_player(&ClientId::ClientIndex(i as u32), "Server is closing".to_string()).ok();
 
         }
+        self.net.flush().ok();
         self.net.end();
     }
 
-    /// Sends an event to all clients
+    /// Sends an event to all clients, on the event's channel
     pub fn broadcast(&self, event: ServerEvent) -> usize {
-        let pk = event.to_packet();
-        let buf = pk.as_slice();;
-
         // TODO: use filter/map instead
         let addr_list = self._get_addr_list();
         let len = addr_list.len();
-        debug!("EVENT[{}B] BROADCAST[{}] {:?}", buf.len(), len, event);
+        debug!("EVENT BROADCAST[{}] {:?}", len, event);
         for addr in addr_list {
             self.send_to(&event, addr);
         }
         len
     }
 
-    // Sends a broadcast that must be ACK by all clients
-    // No other reliable events will be submitted (on a client basis) until the previous one was ACK
-    pub fn broadcast_reliable(&mut self, event: ServerEvent) -> usize {
-        // TODO: make reliable
-        let addr_list = self._get_addr_list();
-        let len = addr_list.len();
-        for addr in addr_list {
-            self.send_to_reliable(event.clone(), &ClientId::Addr(addr)).ok();
-        }
-        len
-    }
-
-    /// Send an event to a specific client
+    /// Send an event to a specific address, on the event's channel
     pub fn send_to(&self, event: &ServerEvent, addr: SocketAddr) {
         self.net.send_to(event, addr).ok();
     }
 
-    /// Sends an event to a specified client, returning Ok(sequence_number) or error if client not found
-    pub fn send_to_reliable(&mut self, event: ServerEvent, client_id: &ClientId) -> Result<u16, anyhow::Error> {
-        let addr = self.get_client_mut(client_id).map(|(_, client)| {
-            client.addr.clone()
-        }).ok_or(anyhow!("Could not find client"))?;
-        self.net.send_to_reliable(event, addr).map_err(|e| anyhow!(e))
+    /// Sends an event to a specified client, on the event's channel. Errors if client not found
+    pub fn send_to_client(&self, event: &ServerEvent, client_id: &ClientId) -> Result<(), anyhow::Error> {
+        let (_, client) = self.get_client(client_id).ok_or(anyhow!("Could not find client"))?;
+        self.net.send_to(event, client.addr).map_err(|e| anyhow!(e))
+    }
+
+    fn login_event(client_index: u32, auth_id: u32, public_key: Option<PublicKey>) -> ServerEvent {
+        ServerEvent::Login {
+            client_index,
+            auth_id,
+            public_key,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PACKET_PROTOCOL_VERSION,
+        }
     }
 
     /// Process a login packet, sending necessary events and registering client/player
-    async fn _process_login_packet(&mut self, addr: SocketAddr, packet: &Packet, version: u32, name: String) -> PacketResponse {
-        if version != PACKET_PROTOCOL_VERSION {
-            warn!("Ignoring login event - invalid protocol version (theirs: {}, ours: {})", version, PACKET_PROTOCOL_VERSION);
-            return PacketResponse::Error(anyhow!("invalid protocol version (yours: {}, ours: {})", version, PACKET_PROTOCOL_VERSION));
+    async fn _process_login_packet(&mut self, addr: SocketAddr, packet: &Packet, version: u16, name: String, client_key: Option<PublicKey>) -> PacketResponse {
+        // Anything we send them from here on is written for their version
+        self.net.set_peer_version(addr, packet.version());
+        // The client resends its Login until our reply arrives, so it may have been lost or be on its way
+        if let Some(client_index) = self.get_client_index(&ClientId::Addr(addr)) {
+            let client = self.client_data[client_index as usize].as_ref().unwrap();
+            debug!("Login from {} already logged in as client {}, resending reply", addr, client_index);
+            self.send_to(&Self::login_event(client_index, client.auth_id, client.server_key), addr);
+            return PacketResponse::Ok;
         }
+        let setup = self.check_login(addr, version, &name, client_key)
+            .and_then(|_| self.setup_player(addr, name.clone()).ok_or(LoginRejectReason::ServerFull));
+        let (client_index, auth_id) = match setup {
+            Ok(setup) => setup,
+            Err(reason) => {
+                warn!("Rejecting login from {} (name={}) (version={}): {}", addr, name, version, reason);
+                self.net.send_to(&ServerEvent::LoginRejected { reason }, addr).ok();
+                // Not forgotten until the rejection is acked, otherwise it would never be resent
+                if self.get_client_index(&ClientId::Addr(addr)).is_none() {
+                    self.rejected_peers.insert(addr);
+                }
+                return PacketResponse::Error(anyhow!("login rejected: {}", reason));
+            }
+        };
 
-        // TODO: send_reliable broadcast_reliable
+        // Only set up a session if we require one, the client always offers a key
+        let public_key = match (self.net.is_secure(), client_key) {
+            (true, Some(client_key)) => Some(self.net.start_session(addr, client_key)),
+            _ => None,
+        };
+        self.client_data[client_index as usize].as_mut().unwrap().server_key = public_key;
+        self.net.track_peer(addr);
+        self.net.set_peer_auth_id(addr, auth_id);
 
         // Tell client it's auth id and player index
-        let (client_index, auth_id) = self.setup_player(addr, name.clone());
-        let login_event = ServerEvent::Login {
-            client_index,
-            auth_id,
-        };
+        let login_event = Self::login_event(client_index, auth_id, public_key);
+        self.rejected_peers.remove(&addr);
         let client_id = ClientId::ClientIndex(client_index);
-        self.send_to_reliable(login_event, &client_id).ok();
+        self.send_to_client(&login_event, &client_id).ok();
 
         // Tell client all connected players
         for i in 0..MAX_PLAYERS {
             if let Some(player) = &self.game.players[i] {
                 let event = player.get_spawn_event();
-                self.send_to_reliable(event, &client_id).ok();
+                self.send_to_client(&event, &client_id).ok();
             }
         }
 
         // Tell all other clients that this client connected
         let spawn_event = self.game.players[client_index as usize].as_ref().unwrap().get_spawn_event();
-        self.broadcast_reliable(spawn_event);
+        self.broadcast(spawn_event);
 
         PacketResponse::Ok
     }
@@ -504,8 +685,8 @@ impl GameInstance {
     pub async fn process_event(&mut self, addr: SocketAddr, packet: &Packet, event: ClientEvent) -> PacketResponse {
         let client_id = ClientId::Addr(addr);
         // Verify login separately - as it can't verify auth
-        if let ClientEvent::Login { version, name} = event {
-            return self._process_login_packet(addr, packet, version, name).await;
+        if let ClientEvent::Login { version, name, public_key, .. } = event {
+            return self._process_login_packet(addr, packet, version, name, public_key).await;
         }
 
         if let Some((client, player)) = self.get_client_player_mut(&client_id) {
@@ -538,7 +719,7 @@ impl GameInstance {
                         client_index: player.client_index,
                         reason,
                     };
-                    self.broadcast_reliable(event);
+                    self.broadcast(event);
                 },
                 ClientEvent::Command { command, id} => {
                     let index = player.client_index;
@@ -547,7 +728,7 @@ impl GameInstance {
                         id,
                         result: result.is_ok(),
                     };
-                    self.send_to_reliable(event, &client_id).ok();
+                    self.send_to_client(&event, &client_id).ok();
                 }
             }
             return PacketResponse::Ok
@@ -564,4 +745,176 @@ pub(crate) enum PacketResponse {
     Discarded,
     // An error happened while processing
     Error(anyhow::Error),
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use mp_game_test_common::netsim::LinkConditions;
+    use mp_game_test_common::network::MemoryNetwork;
+    use crate::network::tests::{server_addr, FakeClient};
+    use super::*;
+
+    const KEY: Option<PublicKey> = Some([0x42; 32]);
+
+    fn game_on(network: &MemoryNetwork, net_config: NetConfig, login_config: LoginConfig) -> GameInstance {
+        let net = NetServer::with_transport(network.bind(server_addr()).unwrap(), net_config);
+        GameInstance::with_net(TICK_RATE, net, login_config)
+    }
+
+    fn game(login_config: LoginConfig) -> GameInstance {
+        game_on(&MemoryNetwork::new(), NetConfig::default(), login_config)
+    }
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 1, n], 5000))
+    }
+
+    /// Sets up players in the first free slots, named after their index
+    fn fill(game: &mut GameInstance, players: u8) {
+        for n in 0..players {
+            game.setup_player(addr(n), format!("player{}", n)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn login_checks_version_and_ban() {
+        let game = game(LoginConfig { banned_ips: vec![addr(1).ip()], ..LoginConfig::default() });
+        assert_eq!(game.check_login(addr(0), PACKET_PROTOCOL_VERSION, "jackz", KEY), Ok(()));
+        assert_eq!(game.check_login(addr(0), MIN_PROTOCOL_VERSION, "jackz", KEY), Ok(()));
+        assert_eq!(game.check_login(addr(0), PACKET_PROTOCOL_VERSION + 1, "jackz", KEY), Err(LoginRejectReason::BadVersion));
+        assert_eq!(game.check_login(addr(1), PACKET_PROTOCOL_VERSION, "jackz", KEY), Err(LoginRejectReason::Banned));
+        // Any port on the address
+        let other_port = SocketAddr::new(addr(1).ip(), 6000);
+        assert_eq!(game.check_login(other_port, PACKET_PROTOCOL_VERSION, "jackz", KEY), Err(LoginRejectReason::Banned));
+    }
+
+    #[tokio::test]
+    async fn login_requires_key_when_secure() {
+        let secure = game_on(&MemoryNetwork::new(), NetConfig { secure: true, ..NetConfig::default() }, LoginConfig::default());
+        assert_eq!(secure.check_login(addr(0), PACKET_PROTOCOL_VERSION, "jackz", None), Err(LoginRejectReason::EncryptionRequired));
+        assert_eq!(secure.check_login(addr(0), PACKET_PROTOCOL_VERSION, "jackz", KEY), Ok(()));
+
+        let open = game(LoginConfig::default());
+        assert_eq!(open.check_login(addr(0), PACKET_PROTOCOL_VERSION, "jackz", None), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn login_rejects_taken_names() {
+        let mut game = game(LoginConfig::default());
+        fill(&mut game, 2);
+        assert_eq!(game.check_login(addr(5), PACKET_PROTOCOL_VERSION, "player1", KEY), Err(LoginRejectReason::NameTaken));
+        assert_eq!(game.check_login(addr(5), PACKET_PROTOCOL_VERSION, "player2", KEY), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn login_rejects_past_max_players() {
+        let mut game = game(LoginConfig { max_players: 3, ..LoginConfig::default() });
+        fill(&mut game, 2);
+        assert_eq!(game.check_login(addr(5), PACKET_PROTOCOL_VERSION, "jackz", KEY), Ok(()));
+        game.setup_player(addr(2), "player2".to_string()).unwrap();
+        assert_eq!(game.check_login(addr(5), PACKET_PROTOCOL_VERSION, "jackz", KEY), Err(LoginRejectReason::ServerFull));
+    }
+
+    #[tokio::test]
+    async fn reserved_slots_are_kept_for_reserved_names() {
+        let mut game = game(LoginConfig {
+            max_players: 3,
+            reserved_slots: 1,
+            reserved_names: vec!["admin".to_string(), "mod".to_string()],
+            ..LoginConfig::default()
+        });
+        fill(&mut game, 2);
+        assert_eq!(game.check_login(addr(5), PACKET_PROTOCOL_VERSION, "jackz", KEY), Err(LoginRejectReason::ServerFull));
+        assert_eq!(game.check_login(addr(5), PACKET_PROTOCOL_VERSION, "admin", KEY), Ok(()));
+
+        game.setup_player(addr(5), "admin".to_string()).unwrap();
+        // Reserved names still count against max players
+        assert_eq!(game.check_login(addr(6), PACKET_PROTOCOL_VERSION, "mod", KEY), Err(LoginRejectReason::ServerFull));
+    }
+
+    /// Waits a bit for the recv thread to queue an event, then processes it like a tick does
+    async fn process_next_event(game: &mut GameInstance) {
+        for _ in 0..20 {
+            if let Some((pk, event, addr)) = game.net.next_event() {
+                game.process_event(addr, &pk, event).await;
+                game.net.flush().unwrap();
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no event received");
+    }
+
+    fn recv_rejection(client: &mut FakeClient) -> u16 {
+        let pk = client.recv_packet().unwrap();
+        assert!(matches!(ServerEvent::from_packet(&pk), Ok(ServerEvent::LoginRejected { reason: LoginRejectReason::ServerFull })));
+        pk.sequence_number()
+    }
+
+    #[tokio::test]
+    async fn lost_rejection_is_resent_until_acked() {
+        let network = MemoryNetwork::new();
+        let mut game = game_on(&network, NetConfig::default(), LoginConfig { max_players: 0, ..LoginConfig::default() });
+        let mut client = FakeClient::new(&network);
+        client.login(None);
+        let cookie = client.recv_challenge();
+        client.login(Some(cookie));
+        process_next_event(&mut game).await;
+
+        // The first one is lost, and not acked
+        let seq = recv_rejection(&mut client);
+        assert_eq!(recv_rejection(&mut client), seq);
+        assert!(game.rejected_peers.contains(&client.addr()));
+        game.forget_rejected_peers();
+        assert!(game.rejected_peers.contains(&client.addr()));
+
+        client.send(&ClientEvent::Ack { seq_number: seq }.to_packet_builder().with_ack(seq).finalize());
+        assert!(game.net.wait_for_acks(client.addr(), Duration::from_millis(500)));
+        game.forget_rejected_peers();
+        assert!(game.rejected_peers.is_empty());
+    }
+
+    /// The next Login the client receives, skipping anything else
+    fn recv_login(client: &mut FakeClient) -> ServerEvent {
+        loop {
+            let pk = client.recv_packet().unwrap();
+            if let Ok(event @ ServerEvent::Login { .. }) = ServerEvent::from_packet(&pk) {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_login_gets_the_same_reply() {
+        let network = MemoryNetwork::new();
+        let mut game = game_on(&network, NetConfig { secure: true, ..NetConfig::default() }, LoginConfig::default());
+        let mut client = FakeClient::new(&network);
+        client.login(None);
+        let cookie = client.recv_challenge();
+        client.login(Some(cookie));
+        process_next_event(&mut game).await;
+        let ServerEvent::Login { client_index, auth_id, public_key, .. } = recv_login(&mut client) else { unreachable!() };
+        assert!(public_key.is_some());
+
+        // The reply was lost or slow, so the client sent its Login again
+        client.login(Some(cookie));
+        process_next_event(&mut game).await;
+        let ServerEvent::Login { client_index: again, auth_id: auth_again, public_key: key_again, .. } = recv_login(&mut client) else { unreachable!() };
+        assert_eq!((again, auth_again, key_again), (client_index, auth_id, public_key));
+        assert_eq!(game.player_count(), 1);
+        assert!(game.rejected_peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clients_only_run_commands_they_can_execute() {
+        let mut game = game(LoginConfig::default());
+        crate::cmds::register_commands(&mut game);
+        fill(&mut game, 1);
+        assert_eq!(game.exec_cmd("status", Some(0)), Ok(()));
+        assert!(game.exec_cmd("netsim loss 50", Some(0)).is_err());
+        assert_eq!(game.net.link_conditions(), LinkConditions::default());
+        // The console can run anything
+        assert_eq!(game.exec_cmd("netsim loss 50", None), Ok(()));
+        assert_eq!(game.net.link_conditions().loss, 50.0);
+    }
+}
@@ -1,20 +1,28 @@
 mod game;
 mod network;
 mod cmds;
+mod challenge;
 
 use std::io::{stdin, stdout, Read};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{channel, Receiver};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 use clap::Parser;
+use clap::builder::TypedValueParser;
 use log::{debug, error, info, trace};
 use tokio::net::UdpSocket;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use mp_game_test_common::game::{CommonGameInstance, PlayerData};
 use mp_game_test_common::{setup_logger, PacketSerialize};
+use mp_game_test_common::datagram::{DEFAULT_COMPRESS_THRESHOLD, DEFAULT_MTU, MIN_MTU};
+use mp_game_test_common::network::{NetConfig, DEFAULT_MAX_SEND_ATTEMPTS, DEFAULT_TICK_BUDGET};
+use mp_game_test_common::netsim::{parse_percent, LinkConditions};
+use mp_game_test_common::def::MAX_PLAYERS;
 use rand::random;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt};
 use tokio::time::{interval, MissedTickBehavior};
@@ -22,7 +30,7 @@ use mp_game_test_common::events_client::ClientEvent;
 use mp_game_test_common::events_server::ServerEvent;
 use mp_game_test_common::packet::Packet;
 use crate::cmds::{register_commands, CommandArgs};
-use crate::game::{GameInstance, PacketResponse};
+use crate::game::{GameInstance, LoginConfig, PacketResponse};
 
 const TICK_RATE: u8 = 30;
 
@@ -36,7 +44,67 @@ struct Args {
     ip: String,
 
     #[arg(long, short = 'p', default_value_t = 3566)]
-    port: u16
+    port: u16,
+
+    /// Largest datagram to send, bigger packets are split into fragments
+    #[arg(long, default_value_t = DEFAULT_MTU, value_parser = clap::value_parser!(u16).range(MIN_MTU as i64..))]
+    mtu: u16,
+
+    /// Batches smaller than this many bytes are sent uncompressed
+    #[arg(long, default_value_t = DEFAULT_COMPRESS_THRESHOLD)]
+    compress_threshold: u16,
+
+    /// Also write every sent batch to this dir, as samples for training the compression dictionary
+    #[arg(long, value_name = "DIR")]
+    capture_packets: Option<PathBuf>,
+
+    /// Require clients to set up an encrypted session when logging in
+    #[arg(long)]
+    secure: bool,
+
+    /// Times a reliable packet is sent before giving up on the client and disconnecting it
+    #[arg(long, default_value_t = DEFAULT_MAX_SEND_ATTEMPTS, value_parser = clap::value_parser!(u8).range(1..))]
+    max_send_attempts: u8,
+
+    /// Most bytes sent to a client per tick, unreliable updates past it are dropped
+    #[arg(long, default_value_t = DEFAULT_TICK_BUDGET, value_parser = clap::value_parser!(u32).range(1..))]
+    tick_budget: u32,
+
+    /// Simulated latency added to everything sent, in ms
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    sim_latency: u64,
+
+    /// Simulated latency varies by up to this much either way, in ms
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    sim_jitter: u64,
+
+    /// Chance a sent datagram is dropped, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    sim_loss: f32,
+
+    /// Chance a sent datagram is sent twice, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    sim_duplicate: f32,
+
+    /// Chance a sent datagram is held back so later ones overtake it, in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    sim_reorder: f32,
+
+    /// Most players at once
+    #[arg(long, default_value_t = MAX_PLAYERS, value_parser = clap::value_parser!(u16).range(1..=MAX_PLAYERS as i64).map(|n| n as usize))]
+    max_players: usize,
+
+    /// Slots, out of max players, kept for the names given with --reserve
+    #[arg(long, default_value_t = 0)]
+    reserved_slots: usize,
+
+    /// Name that can take a reserved slot, can be repeated
+    #[arg(long = "reserve", value_name = "NAME")]
+    reserved_names: Vec<String>,
+
+    /// IP address that is refused from logging in, can be repeated
+    #[arg(long = "ban", value_name = "IP")]
+    banned_ips: Vec<IpAddr>,
 }
 
 #[tokio::main]
@@ -44,7 +112,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opt = Args::parse();
     setup_logger();
 
-    let mut game = GameInstance::new(opt.tick_rate);
+    let net_config = NetConfig {
+        mtu: opt.mtu,
+        compress_threshold: opt.compress_threshold,
+        capture_dir: opt.capture_packets,
+        secure: opt.secure,
+        max_send_attempts: opt.max_send_attempts,
+        tick_budget: opt.tick_budget,
+        link: LinkConditions {
+            latency: Duration::from_millis(opt.sim_latency),
+            jitter: Duration::from_millis(opt.sim_jitter),
+            loss: opt.sim_loss,
+            duplicate: opt.sim_duplicate,
+            reorder: opt.sim_reorder,
+        },
+    };
+    let login_config = LoginConfig {
+        max_players: opt.max_players,
+        reserved_slots: opt.reserved_slots,
+        reserved_names: opt.reserved_names,
+        banned_ips: opt.banned_ips,
+    };
+    let mut game = GameInstance::new(opt.tick_rate, net_config, login_config);
     register_commands(&mut game);
 
     let term = console::Term::stdout();
@@ -1,226 +1,452 @@
-use std::collections::{HashMap, VecDeque};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicU16, Ordering};
-use std::sync::mpsc::{channel, Receiver, RecvError, Sender, TryRecvError};
-use std::thread;
-use std::time::{Duration, Instant};
-use anyhow::anyhow;
-use log::{debug, error, info, trace, warn};
+use std::net::{SocketAddr, UdpSocket};
+use log::{debug, error, info, trace};
 use mp_game_test_common::events_client::ClientEvent;
-use mp_game_test_common::packet::{Packet};
-use mp_game_test_common::{NetContainer, NetDirection, NetStat, PacketSerialize, ACK_TIMEOUT_REPLY, PACKET_PROTOCOL_VERSION};
+use mp_game_test_common::packet::{Packet, PacketError};
+use mp_game_test_common::PacketSerialize;
 use mp_game_test_common::events_server::ServerEvent;
-use mp_game_test_common::network::{Network, ReliableQueue};
+use mp_game_test_common::network::{Endpoint, EndpointEvents, Link, NetConfig, Network, Received, Transport};
+use mp_game_test_common::datagram::version_info_datagram;
+use mp_game_test_common::compat;
+use mp_game_test_common::session::{Handshake, PublicKey, Role};
+use mp_game_test_common::unix_timestamp;
+use crate::challenge::Challenger;
 
 pub struct NetServer {
-    event_queue: EventQueue,
-    socket: UdpSocket,
-    // rx: Sender<ClientEvent>,
-    transmit_out_tx: Option<Sender<OutPacket>>,
-    recv_end_signal: Option<Sender<()>>,
-    send_thread: thread::JoinHandle<()>,
-    recv_thread: thread::JoinHandle<()>,
-
-    reliable_queue: Arc<Mutex<ReliableQueue>>,
-    seq_number: u16,
-    net_stat: NetStat
+    endpoint: Endpoint<ServerEvents>,
+    secure: bool,
 }
 
-#[derive(Clone)]
-pub struct ReliableEntry {
-    pub seq_id: u16,
-    pub packet: Packet,
-    pub sent_time: Instant
+impl NetServer {
+    pub(crate) fn new(addr: SocketAddr, config: NetConfig) -> Self  {
+        // socket.set_nonblocking(false);
+        Self::with_transport(UdpSocket::bind(addr).expect("Failed to bind UDP socket"), config)
+    }
+
+    /// Serves clients over any transport, such as a [MemoryTransport](mp_game_test_common::network::MemoryTransport) in tests
+    pub(crate) fn with_transport(transport: impl Transport + 'static, config: NetConfig) -> Self {
+        let endpoint = Endpoint::new(transport, &config, ServerEvents { challenger: Challenger::new() });
+        info!("server listening at {:?}", endpoint.local_addr());
+        if config.secure {
+            info!("encrypted sessions are required");
+        }
+        NetServer {
+            endpoint,
+            secure: config.secure,
+        }
+    }
+
+    /// Whether clients have to set up an encrypted session to log in
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Starts an encrypted session with the client's offered key, returning the server's key to send back in the Login reply.
+    /// Replaces any previous session with the address
+    pub fn start_session(&self, addr: SocketAddr, client_key: PublicKey) -> PublicKey {
+        let handshake = Handshake::new();
+        let server_key = handshake.public_key();
+        self.endpoint.link().encoder().sessions().insert(addr, handshake.finish(Role::Server, client_key));
+        server_key
+    }
+
+    /// Sets the protocol version a client logged in with, what we send it is rewritten for it if it's an older one
+    pub fn set_peer_version(&self, addr: SocketAddr, version: u16) {
+        self.endpoint.link().encoder().versions().set(addr, version);
+    }
 }
 
-pub enum OutPacket {
-    Single(Packet, SocketAddr),
-    Multiple(Packet, Vec<SocketAddr>),
+impl Network for NetServer {
+    type Events = ServerEvents;
+
+    fn endpoint(&self) -> &Endpoint<ServerEvents> {
+        &self.endpoint
+    }
+
+    fn endpoint_mut(&mut self) -> &mut Endpoint<ServerEvents> {
+        &mut self.endpoint
+    }
 }
 
-type EventQueue = Arc<Mutex<VecDeque<(Packet, ClientEvent, SocketAddr)>>>;
-impl NetServer {
-    pub(crate) fn new(addr: SocketAddr) -> Self  {
-        // socket.set_nonblocking(false);
-        let mut sock = UdpSocket::bind(addr).expect("Failed to bind UDP socket");
-        let (tx, rx) = channel::<OutPacket>();
-        let event_queue = Arc::new(Mutex::new(VecDeque::new()));
-        let reliable_queue = Arc::new(Mutex::new(ReliableQueue::new()));
-        let net_stat = NetStat::new();
-        let end_signal = channel::<()>();
-
-        let send_thread = {
-            let socket = sock.try_clone().unwrap();
-            let net_stat = net_stat.clone();
-            thread::spawn(move || network_send_thread(socket, rx, net_stat))
-        };
-        let recv_thread = {
-            let event_queue = event_queue.clone();
-            let socket = sock.try_clone().unwrap();
-            let reliable_queue = reliable_queue.clone();
-            let net_stat = net_stat.clone();
-            thread::spawn(move || network_recv_thread(end_signal.1, socket, event_queue.clone(), reliable_queue, net_stat))
-        };
+/// The server's side of the network: challenges logins before the game sees them
+pub struct ServerEvents {
+    challenger: Challenger,
+}
 
-        info!("server listening at UDP {:?}", sock.local_addr().unwrap());
+impl EndpointEvents for ServerEvents {
+    type Out = ServerEvent;
+    type In = ClientEvent;
 
-        NetServer {
-            transmit_out_tx: Some(tx),
-            recv_end_signal: Some(end_signal.0),
-            recv_thread,
-            send_thread,
-            event_queue,
-            socket: sock,
-            reliable_queue,
-            seq_number: 0,
-            net_stat
+    fn ack(seq_number: u16) -> ServerEvent {
+        ServerEvent::Ack { seq_number }
+    }
+
+    fn authenticates(&self, link: &Link, addr: SocketAddr, pk: &Packet, event: &ClientEvent) -> bool {
+        match link.peer_auth_id(addr) {
+            // A client resends its Login until it gets our reply, before it knows its auth id. It's never acked or sequenced
+            Some(_) if matches!(event, ClientEvent::Login { .. }) => pk.sequence_number() == 0 && pk.ack() == 0,
+            Some(auth_id) => pk.auth_id() == auth_id,
+            None => true,
+        }
+    }
+
+    fn received(&mut self, link: &mut Link, addr: SocketAddr, pk: &Packet, event: ClientEvent) -> Received<ClientEvent> {
+        match event {
+            ClientEvent::Login { cookie, .. } if !cookie.is_some_and(|cookie| self.challenger.verify(addr, &cookie, unix_timestamp())) => {
+                send_challenge(link, &self.challenger, addr, pk);
+                Received::Handled
+            }
+            // Ack events only exist to carry the header ACK, nothing else to do
+            ClientEvent::Ack { .. } => Received::Handled,
+            ClientEvent::Login { .. } => Received::Restart(event),
+            _ => Received::Queue(event),
+        }
+    }
+
+    fn rejected(&mut self, link: &mut Link, addr: SocketAddr, err: &PacketError) {
+        // Tell them what we support, so they can show why they can't connect
+        if let PacketError::BadVersion { version } = err {
+            debug!("[net] {} is on protocol {}, sending supported versions", addr, version);
+            link.send_datagram(addr, &version_info_datagram());
+        }
+    }
+}
+
+/// Replies to a Login without a valid cookie, without keeping any state for the address
+fn send_challenge(link: &Link, challenger: &Challenger, addr: SocketAddr, login: &Packet) {
+    let cookie = challenger.issue(addr, unix_timestamp());
+    let pk = match compat::server_packet_for(&ServerEvent::Challenge { cookie }.to_packet(), login.version()) {
+        Ok(Some(pk)) => pk,
+        Ok(None) => {
+            error!("[net] protocol {} has no challenge", login.version());
+            return;
+        }
+        Err(e) => {
+            error!("[net] could not write challenge for protocol {}: {}", login.version(), e);
+            return;
+        }
+    };
+    // Don't reply with more than we got, so spoofed logins can't be used to amplify traffic
+    if pk.buf_len() > login.buf_len() {
+        debug!("dropping login from {} - too small to challenge ({}B)", addr, login.buf_len());
+        return;
+    }
+    trace!("challenging login from {}", addr);
+    if let Err(e) = link.send_now(addr, std::slice::from_ref(&pk)) {
+        error!("[net] could not send challenge to {}: {}", addr, e);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use mp_game_test_common::network::{Cookie, MemoryNetwork, COOKIE_LEN};
+    use mp_game_test_common::datagram::{DatagramDecoder, DatagramEncoder, FRAGMENT_TIMEOUT, MAX_DATAGRAM_SIZE};
+    use mp_game_test_common::session::Sessions;
+    use mp_game_test_common::{ACK_TIMEOUT_REPLY, PACKET_PROTOCOL_VERSION};
+    use mp_game_test_common::def::Vector3;
+    use mp_game_test_common::game::Action;
+    use mp_game_test_common::MIN_PROTOCOL_VERSION;
+
+    pub(crate) fn server_addr() -> SocketAddr {
+        "10.0.0.1:3566".parse().unwrap()
+    }
+
+    /// A bare socket standing in for a client, so the test controls exactly what is sent
+    pub(crate) struct FakeClient {
+        socket: Box<dyn Transport>,
+        server_addr: SocketAddr,
+        encoder: DatagramEncoder,
+        decoder: DatagramDecoder,
+    }
+
+    impl FakeClient {
+        /// A client on the network, talking to the server at [server_addr]
+        pub(crate) fn new(network: &MemoryNetwork) -> Self {
+            Self::with_transport(network.bind("10.0.0.2:0".parse().unwrap()).unwrap(), server_addr())
+        }
+
+        fn with_transport(socket: impl Transport + 'static, server_addr: SocketAddr) -> Self {
+            socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let sessions = Sessions::new();
+            Self {
+                socket: Box::new(socket),
+                server_addr,
+                encoder: DatagramEncoder::new(&NetConfig::default(), sessions.clone()),
+                decoder: DatagramDecoder::new(&NetConfig::default(), FRAGMENT_TIMEOUT, sessions),
+            }
+        }
+
+        pub(crate) fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+
+        pub(crate) fn login(&self, cookie: Option<Cookie>) {
+            self.login_as(PACKET_PROTOCOL_VERSION, cookie);
+        }
+
+        /// Logs in as a client on another protocol version, assuming its Login looks like ours
+        fn login_as(&self, version: u16, cookie: Option<Cookie>) {
+            let event = ClientEvent::Login {
+                version,
+                name: "test".to_string(),
+                public_key: Some([0x42; 32]),
+                cookie,
+            };
+            let pk = event.to_packet_builder().with_version(version).finalize();
+            self.socket.send_to(&self.encoder.encode_plain(&pk), self.server_addr).unwrap();
+        }
+
+        pub(crate) fn send(&self, pk: &Packet) {
+            for datagram in self.encoder.encode(self.server_addr, std::slice::from_ref(pk)).unwrap() {
+                self.socket.send_to(&datagram, self.server_addr).unwrap();
+            }
+        }
+
+        pub(crate) fn recv_packet(&mut self) -> Result<Packet, PacketError> {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let (n, addr) = self.socket.recv_from(&mut buf).expect("nothing received");
+            assert_eq!(addr, self.server_addr);
+            let packets = self.decoder.accept(addr, &buf[..n])?;
+            Ok(packets.into_iter().next().expect("empty batch"))
+        }
+
+        pub(crate) fn recv_challenge(&mut self) -> Cookie {
+            let pk = self.recv_packet().unwrap();
+            match ServerEvent::from_packet(&pk) {
+                Ok(ServerEvent::Challenge { cookie }) => cookie,
+                other => panic!("expected challenge, got {:?}", other)
+            }
         }
     }
 
-    pub fn end(mut self) {
-        // Drop senders, which the threads will then end after noticing its dropped
-        self.transmit_out_tx = None;
-        self.recv_end_signal = None;
+    /// A server at [server_addr] on a network of its own, for [FakeClient]s to join
+    fn server_with(config: NetConfig) -> (NetServer, MemoryNetwork) {
+        let network = MemoryNetwork::new();
+        (NetServer::with_transport(network.bind(server_addr()).unwrap(), config), network)
+    }
 
-        self.send_thread.join().unwrap();
-        self.recv_thread.join().unwrap();
+    fn server() -> (NetServer, MemoryNetwork) {
+        server_with(NetConfig::default())
     }
 
-    pub(crate) fn stat(&self) -> &NetStat {
-        &self.net_stat
+    /// Waits a bit for the recv thread to queue an event
+    fn next_event(server: &mut NetServer) -> Option<ClientEvent> {
+        for _ in 0..20 {
+            if let Some((_, event, _)) = server.next_event() {
+                return Some(event);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
     }
 
-    /// Returns the number of packets sent and received since last called.
-    /// Resets the count
-    pub(crate) fn pks_per_interval(&mut self) -> NetContainer<u16> {
-        let pk_stat = self.net_stat.pk_count();
-        self.net_stat.reset_pk_count();
-        pk_stat
+    #[test]
+    fn login_without_cookie_is_challenged() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        client.login(None);
+        client.recv_challenge();
+        assert!(next_event(&mut server).is_none(), "login was queued before the challenge was answered");
+        server.end();
     }
 
-    pub fn send_to(&self, event: &ServerEvent, addr: SocketAddr) -> Result<(), String> {
-        let pk = event.to_packet();
-        let buf = pk.as_slice();;
-        debug!("EVENT[{}B] {:?} {:?}", buf.len(), addr, event);
-        let tx = self.transmit_out_tx.as_ref().ok_or("shutdown in progress".to_string())?;
-        tx.send(OutPacket::Single(pk, addr)).map_err(|e| e.to_string())
+    #[test]
+    fn echoed_cookie_is_accepted() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        client.login(None);
+        let cookie = client.recv_challenge();
+        client.login(Some(cookie));
+        assert!(matches!(next_event(&mut server), Some(ClientEvent::Login { .. })));
+        server.end();
     }
 
-    /// Sends an event to a specified addr, returning Ok(sequence_number)
-    pub fn send_to_reliable(&self, event: ServerEvent, addr: SocketAddr) -> Result<u16, String> {
-        let mut lock = self.reliable_queue.lock().unwrap();
-        let entry = lock.add_event(addr, event.clone());
-        self.send_to(&event ,addr).map(|_| entry.seq_id)
+    #[test]
+    fn cookie_from_another_address_is_challenged_again() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        let mut spoofer = FakeClient::new(&network);
+        client.login(None);
+        let cookie = client.recv_challenge();
+        spoofer.login(Some(cookie));
+        spoofer.recv_challenge();
+        assert!(next_event(&mut server).is_none());
+        server.end();
     }
 
-    fn event_queue_len(&self) -> usize {
-        self.event_queue.lock().unwrap().len()
+    #[test]
+    fn previous_version_is_challenged_in_its_version() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        client.login_as(MIN_PROTOCOL_VERSION, None);
+        let pk = client.recv_packet().unwrap();
+        assert_eq!(pk.version(), MIN_PROTOCOL_VERSION);
+        let Ok(ServerEvent::Challenge { cookie }) = ServerEvent::from_packet(&pk) else {
+            panic!("expected challenge");
+        };
+        client.login_as(MIN_PROTOCOL_VERSION, Some(cookie));
+        assert!(matches!(next_event(&mut server), Some(ClientEvent::Login { version: MIN_PROTOCOL_VERSION, .. })));
+        server.end();
     }
 
+    #[test]
+    fn unsupported_version_gets_supported_range() {
+        let (server, network) = server();
+        let mut client = FakeClient::new(&network);
+        client.login_as(PACKET_PROTOCOL_VERSION + 1, None);
+        match client.recv_packet() {
+            Err(PacketError::VersionRejected { min, max }) => {
+                assert_eq!((min, max), (MIN_PROTOCOL_VERSION, PACKET_PROTOCOL_VERSION));
+            }
+            other => panic!("expected version info, got {:?}", other.map(|pk| pk.version()))
+        }
+        server.end();
+    }
 
-    /// Pops the next incoming event off, if any
-    pub(crate) fn next_event(&mut self) -> Option<(Packet, ClientEvent, SocketAddr)> {
-        let mut lock = self.event_queue.lock().unwrap();
-        lock.pop_front()
+    #[test]
+    fn stale_sequenced_events_are_dropped() {
+        let (mut server, network) = server();
+        let client = FakeClient::new(&network);
+        server.track_peer(client.addr());
+        let action = |seq| ClientEvent::PerformAction { actions: Action::empty(), angles: Vector3::new(seq as f32, 0.0, 0.0) }
+            .to_packet_builder().with_channel_seq(seq).finalize();
+        client.send(&action(2));
+        client.send(&action(1));
+        client.send(&action(3));
+        let mut received = Vec::new();
+        while let Some(ClientEvent::PerformAction { angles, .. }) = next_event(&mut server) {
+            received.push(angles.x as u16);
+        }
+        assert_eq!(received, vec![2, 3]);
+        server.end();
     }
-}
 
-pub fn network_recv_thread(
-    end_signal: Receiver<()>,
-    socket: UdpSocket,
-    mut event_queue: EventQueue,
-    reliable_queue: Arc<Mutex<ReliableQueue>>,
-    mut net_stat: NetStat
-) {
-    let mut buf = Vec::with_capacity(2048);
-    socket.set_read_timeout(Some(Duration::from_secs(1))).expect("set_read_timeout failed");
-    while end_signal.try_recv() != Err(TryRecvError::Disconnected) { // Check if we are good
-        // Check if we received any data, and add it to packet queue
-        buf.resize(2048, 0);
-        match socket.recv_from(&mut buf) {
-            Ok((n, addr)) => {
-                trace!("recv {n} bytes, buf len = {}", buf.len());
-                buf.truncate(n);
-                net_stat.mark_activity(NetDirection::In);
-                if n > 0 {
-                    let pk = match Packet::try_decompress_from_slice(buf.as_slice()) {
-                        Ok(pk) => pk,
-                        Err(e) => {
-                            warn!("[net] dropping bad packet: {}", e);
-                            continue;
-                        }
-                    };
-                    trace!("[net] IN n={} {}", n, pk.as_hex_str());
-                    net_stat.inc_pk_count(NetDirection::Out);
-                    match ClientEvent::from_packet(&pk) {
-                        Ok(ev) => {
-                            // If it's ACK packet, handle it here
-                            if let ClientEvent::Ack {seq_number} = ev {
-                                trace!("got ACK {:?}", seq_number);
-                                let mut lock = reliable_queue.lock().unwrap();
-                                lock.try_accept_ack(addr, seq_number);
-                            } else {
-                                trace!("received event, pushing to queue");
-                                let mut lock = event_queue.lock().unwrap();
-                                lock.push_back((pk, ev, addr));
-                            }
-                        }
-                        Err(err) => {
-                            warn!("bad packet: {:?}", err);
-                        }
-                    }
-                }
-                // Check ACK state
-                {
-                    // This code assumes that the server will receive a regular amount of traffic so this is processed
-                    // If zero clients are sending data this will stall
-                    let mut lock = reliable_queue.lock().unwrap();
-                    if let Some(item) = lock.next_resend(addr) {
-                        trace!("ACK timeout (seq#{}). resending (original pk {} ms ago)", item.seq_id, item.sent_time.elapsed().as_millis());
-                        socket.send_to(item.packet.as_slice(), addr).ok();
-                        item.sent_time = Instant::now(); // update timestamp so client has another chance
-                    }
-                }
+    #[test]
+    fn unacked_packets_are_resent_until_given_up() {
+        let (server, network) = server_with(NetConfig { max_send_attempts: 3, ..Default::default() });
+        let mut client = FakeClient::new(&network);
+        let addr = client.addr();
+        server.send_to(&ServerEvent::CommandResult { id: 1, result: true }, addr).unwrap();
+        server.flush().unwrap();
+        // Sent once, then resent without the client sending anything
+        for _ in 0..3 {
+            assert_eq!(client.recv_packet().unwrap().sequence_number(), 1);
+        }
+        // Given up once the last send (backed off to 4 times the timeout) isn't acked either
+        let mut unresponsive = Vec::new();
+        for _ in 0..20 {
+            unresponsive = server.take_unresponsive();
+            if !unresponsive.is_empty() {
+                break;
             }
-            Err(e) => {
-                if e.kind() != std::io::ErrorKind::WouldBlock {
-                    error!("[net] recv error: {}", e);
-                    // let mut lock = last_error.lock().unwrap();
-                    // *lock = Some(e.to_string());
-                }
+            thread::sleep(ACK_TIMEOUT_REPLY);
+        }
+        assert_eq!(unresponsive, vec![addr]);
+        assert!(client.socket.recv_from(&mut [0; 64]).is_err(), "resent after giving up");
+        server.end();
+    }
+
+    #[test]
+    fn forged_cookie_is_challenged_again() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        client.login(Some([0; COOKIE_LEN]));
+        client.recv_challenge();
+        assert!(next_event(&mut server).is_none());
+        server.end();
+    }
+
+    #[test]
+    fn acks_measure_round_trip_time() {
+        let (server, network) = server();
+        let mut client = FakeClient::new(&network);
+        let addr = client.addr();
+        assert!(server.rtt(addr).is_none());
+        server.send_to(&ServerEvent::CommandResult { id: 1, result: true }, addr).unwrap();
+        server.flush().unwrap();
+        let pk = client.recv_packet().unwrap();
+        let seq = pk.sequence_number();
+        client.send(&ClientEvent::Ack { seq_number: seq }.to_packet_builder().with_ack(seq).finalize());
+        for _ in 0..20 {
+            if server.rtt(addr).and_then(|rtt| rtt.srtt()).is_some() {
+                break;
             }
+            thread::sleep(Duration::from_millis(10));
         }
+        let srtt = server.rtt(addr).and_then(|rtt| rtt.srtt()).expect("no round trip measured");
+        assert!(srtt < ACK_TIMEOUT_REPLY);
+        server.end();
+    }
 
+    #[test]
+    fn reliable_events_are_acked_and_duplicates_dropped() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        server.track_peer(client.addr());
+        // No channel seq, so it's only the reliable seq that tells it's a duplicate
+        let command = ClientEvent::Command { command: "status".to_string(), id: 1 }
+            .to_packet_builder().with_sequence_number(1).finalize();
+        client.send(&command);
+        client.send(&command);
+        for _ in 0..2 {
+            let pk = client.recv_packet().unwrap();
+            assert!(matches!(ServerEvent::from_packet(&pk), Ok(ServerEvent::Ack { .. })));
+            assert_eq!(pk.ack(), 1);
+        }
+        assert!(matches!(next_event(&mut server), Some(ClientEvent::Command { id: 1, .. })));
+        assert!(next_event(&mut server).is_none(), "duplicate was queued");
+        server.end();
     }
-}
-pub fn network_send_thread(
-    socket: UdpSocket,
-    mut transmit_recv: Receiver<OutPacket>,
-    mut net_stat: NetStat
-) {
-    loop {
-        // Check if there's any data we need to send out
-        if let Ok(out) = transmit_recv.recv() {
-            net_stat.mark_activity(NetDirection::Out);
-            match out {
-                OutPacket::Multiple(pk, addr_list) => {
-                    trace!("OUT addr_list={:?} pk_len={} py_len={} {}", addr_list, pk.buf_len(), pk.payload_len(), pk.as_hex_str());
-                    let buf = pk.compress().expect("compress failed");
-                    for addr in addr_list {
-                        socket.send_to(&buf, addr).unwrap();
-                    }
-                },
-                OutPacket::Single(pk, addr) => {
-                    trace!("OUT addr={} pk_len={} py_len={} {}", addr, pk.buf_len(), pk.payload_len(), pk.as_hex_str());
-                    let buf = pk.compress().expect("compress failed");
-                    socket.send_to(&buf, addr).unwrap();
-                }
+
+    #[test]
+    fn packets_without_the_auth_id_are_not_acked() {
+        let (mut server, network) = server();
+        let mut client = FakeClient::new(&network);
+        server.track_peer(client.addr());
+        server.set_peer_auth_id(client.addr(), 7);
+        let command = |auth_id| ClientEvent::Command { command: "status".to_string(), id: 1 }
+            .to_packet_builder().with_auth_id(auth_id).with_sequence_number(1).finalize();
+        // Someone else sending from the client's address, without knowing its auth id
+        client.send(&command(99));
+        assert!(next_event(&mut server).is_none(), "spoofed packet was queued");
+        assert!(client.socket.recv_from(&mut [0; 64]).is_err(), "spoofed packet was acked");
+        assert_eq!(server.stat().rejected_count(), 1);
+
+        // The real one isn't taken for a duplicate
+        client.send(&command(7));
+        assert_eq!(client.recv_packet().unwrap().ack(), 1);
+        assert!(matches!(next_event(&mut server), Some(ClientEvent::Command { id: 1, .. })));
+
+        // Nor is a resent Login, which has no auth id yet
+        client.login(None);
+        client.recv_challenge();
+        server.end();
+    }
+
+    #[test]
+    fn clients_log_in_in_order() {
+        let (mut server, network) = server();
+        let mut clients: Vec<_> = (0..3).map(|_| FakeClient::new(&network)).collect();
+        for client in &mut clients {
+            client.login(None);
+            let cookie = client.recv_challenge();
+            client.login(Some(cookie));
+        }
+        let mut logged_in = Vec::new();
+        for _ in 0..20 {
+            while let Some((_, event, addr)) = server.next_event() {
+                assert!(matches!(event, ClientEvent::Login { .. }));
+                logged_in.push(addr);
+            }
+            if logged_in.len() == clients.len() {
+                break;
             }
-            net_stat.inc_pk_count(NetDirection::Out);
-        } else {
-            debug!("send_thread: channel closed, exiting");
-            break;
+            thread::sleep(Duration::from_millis(10));
         }
+        let expected: Vec<_> = clients.iter().map(|client| client.addr()).collect();
+        assert_eq!(logged_in, expected);
+        server.end();
     }
 }